@@ -0,0 +1,77 @@
+//! Measure ARQ and HARQ goodput against SNR on the simulated link
+
+use ofdm::arq::{simulate, ArqConfig, ArqMode, SimulatedLink};
+use ofdm::harq::{self, HarqMode};
+use ofdm::*;
+
+// The rate the link runs at, for turning samples into seconds
+const SAMPLE_RATE: f64 = 1e6;
+
+/// Sweep the SNR and print the goodput of each ARQ and HARQ mode
+#[derive(argh::FromArgs)]
+struct CmdArgs {
+    /// how many bytes to send at every snr
+    #[argh(option, default = "2000")]
+    bytes: usize,
+
+    /// frames in flight for selective repeat
+    #[argh(option, default = "8")]
+    window: usize,
+
+    /// give up after this many seconds of link time
+    #[argh(option, default = "10.0")]
+    limit: f64,
+}
+
+fn main() {
+    ofdm::logging::set_up_logging("arq");
+    let cfg: CmdArgs = argh::from_env();
+
+    let data = utils::create_transmission_text(cfg.bytes, false);
+    let modes = [
+        ArqMode::StopAndWait,
+        ArqMode::SelectiveRepeat { window: cfg.window },
+    ];
+
+    println!("snr (dB) | mode | goodput (kbps) | delivered | retransmissions | timeouts");
+    for snr in (0..=30).step_by(3) {
+        for mode in modes.iter() {
+            let mut link = SimulatedLink::new(snr as f64, snr as u64);
+            let max_samples = (cfg.limit * SAMPLE_RATE) as usize;
+            let stats = simulate(&data, ArqConfig::new(*mode), &mut link, max_samples);
+
+            println!(
+                "{:>8} | {:?} | {:.1} | {}/{} | {} | {}",
+                snr,
+                mode,
+                stats.goodput(SAMPLE_RATE) / 1e3,
+                stats.delivered.len(),
+                data.len(),
+                stats.retransmissions,
+                stats.timeouts
+            );
+        }
+
+        let harq_modes = [
+            HarqMode::NoCombining,
+            HarqMode::Chase,
+            HarqMode::IncrementalRedundancy,
+        ];
+        for mode in harq_modes.iter() {
+            let mut link = SimulatedLink::new(snr as f64, snr as u64);
+            let max_samples = (cfg.limit * SAMPLE_RATE) as usize;
+            let stats = harq::simulate(&data, ArqConfig::new(*mode), &mut link, max_samples);
+
+            println!(
+                "{:>8} | {:?} | {:.1} | {}/{} | {} | {} dropped",
+                snr,
+                mode,
+                stats.goodput(SAMPLE_RATE) / 1e3,
+                stats.delivered.len(),
+                data.len(),
+                stats.retransmissions,
+                stats.dropped
+            );
+        }
+    }
+}
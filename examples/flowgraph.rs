@@ -0,0 +1,38 @@
+#![allow(non_upper_case_globals)]
+//! Lab 3b, but built as a flowgraph so each stage can be timed and probed on its own
+use colored::Colorize;
+use num::complex::Complex64;
+use ofdm::flowgraph::{blocks::*, Flowgraph};
+use ofdm::*;
+
+const num_bytes: usize = 400;
+const guard_bands: bool = true;
+const ecc_enabled: bool = false;
+const modulation: ModulationScheme = ModulationScheme::Qpsk;
+
+fn main() {
+    ofdm::logging::set_up_logging("flowgraph");
+    let source_data = utils::create_transmission_text(num_bytes, ecc_enabled);
+
+    let mut graph = Flowgraph::new(std::iter::repeat(source_data.clone()).take(10))
+        .then(Encoder::new(guard_bands, modulation))
+        .then(Channel::new(30.0).timing_error(true))
+        .then(Synchronizer)
+        .then(Equalizer::new(guard_bands))
+        .tap(|symbols: &Vec<Complex64>| plots::constellation(&symbols[..symbols.len().min(512)]))
+        .then(Demodulator::new(modulation))
+        .then(Deframer);
+
+    for received in graph.run() {
+        dbg!(utils::Analysis::new(source_data.as_ref(), &received));
+
+        println!(
+            "{}",
+            utils::decipher_transmission_text(num_bytes, received, ecc_enabled)
+                .unwrap_or_default()
+                .green()
+        );
+    }
+
+    graph.print_report();
+}
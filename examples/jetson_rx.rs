@@ -7,6 +7,9 @@ use std::{
 
 use anyhow::{Context, Result};
 use num::complex::Complex64;
+use ofdm::packets::images::ImageReceiver;
+use ofdm::pipeline::CaptureHandle;
+use ofdm::radio::{FileSource, SampleSource};
 use ofdm::*;
 use tap::Pipe;
 use uhd::{self, StreamCommand, StreamCommandType, StreamTime, TuneRequest, Usrp};
@@ -15,58 +18,107 @@ const CHANNEL_SELECT: usize = 0;
 const SAMPLE_RATE: f64 = 1e6;
 const NUM_SAMPLES: usize = 2_000_000;
 const FREQUENCY: f64 = 915e6;
+const WINDOW_SIZE: (usize, usize) = (24, 24);
 
 const guard_bands: bool = true;
 const modulation: ModulationScheme = ModulationScheme::Bpsk;
 
-type SharedBuffer = Vec<Complex64>;
+/// Receive images from jetson_tx and show them in a window
+#[derive(argh::FromArgs)]
+struct CmdArgs {
+    /// play back a capture (raw fc32 or SigMF) instead of using the USRP
+    #[argh(option)]
+    replay: Option<String>,
+
+    /// start the capture over when it runs out
+    #[argh(switch)]
+    looping: bool,
+
+    /// add noise to the capture at this power in dB
+    #[argh(option)]
+    noise_floor: Option<f64>,
+}
+
+fn capture_usrp(capture: CaptureHandle) -> Result<()> {
+    let mut usrp = Usrp::find("serial=30C628D")
+        .context("Failed to open device list")?
+        .drain(..)
+        .next()
+        .context("Failed to find a valid USRP to attach to")?
+        .pipe(|addr| Usrp::open(&addr))
+        .context("Failed to find properly open the USRP")?;
+
+    usrp.set_rx_sample_rate(SAMPLE_RATE, CHANNEL_SELECT)?;
+    usrp.set_rx_antenna("TX/RX", CHANNEL_SELECT)?;
+    usrp.set_rx_frequency(&TuneRequest::with_frequency(FREQUENCY), CHANNEL_SELECT)?;
+    usrp.set_rx_gain(150.0, CHANNEL_SELECT, "")?;
+
+    let mut receiver = usrp.get_rx_stream(&uhd::StreamArgs::<Complex64>::new("fc32"))?;
+
+    for i in 0..100 {
+        log::debug!("Starting capture {} ", i);
+        let mut chan = vec![Complex64::default(); NUM_SAMPLES];
+        receiver.receive_simple(chan.as_mut())?;
+        log::debug!("Capture {} buf1 finished", i);
+        if !capture.push(chan) {
+            log::debug!("Pipeline overflowed, capture {} dropped", i);
+        }
+    }
+
+    log::debug!("All capturing finished");
+    Ok(())
+}
+
+/// Feed the pipeline from a file, at the rate the USRP would have
+fn capture_file(capture: CaptureHandle, cfg: &CmdArgs, path: &str) -> Result<()> {
+    let mut source = FileSource::open(path)?
+        .sample_rate(SAMPLE_RATE)
+        .looping(cfg.looping);
+    if let Some(power) = cfg.noise_floor {
+        source = source.noise_floor(power, 0);
+    }
+
+    loop {
+        let mut chan = vec![Complex64::default(); NUM_SAMPLES];
+        let len = source.receive(&mut chan)?;
+        if len == 0 {
+            break;
+        }
+
+        chan.truncate(len);
+        if !capture.push(chan) {
+            log::debug!("Pipeline overflowed, {} samples dropped", len);
+        }
+    }
+
+    log::debug!("Replay finished");
+    Ok(())
+}
 
 fn main() -> Result<()> {
     ofdm::logging::set_up_logging("jetson_rx");
-    let (send_ready, recv_ready) = std::sync::mpsc::sync_channel::<SharedBuffer>(1);
-
-    let sync_channel = std::thread::spawn(move || -> Result<()> {
-        // we will use this thread for the radio business
-
-        let mut usrp = Usrp::find("serial=30C628D")
-            .context("Failed to open device list")?
-            .drain(..)
-            .next()
-            .context("Failed to find a valid USRP to attach to")?
-            .pipe(|addr| Usrp::open(&addr))
-            .context("Failed to find properly open the USRP")?;
-
-        usrp.set_rx_sample_rate(SAMPLE_RATE, CHANNEL_SELECT)?;
-        usrp.set_rx_antenna("TX/RX", CHANNEL_SELECT)?;
-        usrp.set_rx_frequency(&TuneRequest::with_frequency(FREQUENCY), CHANNEL_SELECT)?;
-        usrp.set_rx_gain(150.0, CHANNEL_SELECT, "")?;
-
-        let mut receiver = usrp.get_rx_stream(&uhd::StreamArgs::<Complex64>::new("fc32"))?;
-
-        for i in 0..100 {
-            log::debug!("Starting capture {} ", i);
-            let mut chan = vec![Complex64::default(); NUM_SAMPLES];
-            receiver.receive_simple(chan.as_mut())?;
-            log::debug!("Capture {} buf1 finished", i);
-            send_ready.send(chan).unwrap();
-            // log::debug!("Buffer sent");
-        }
+    let cfg: CmdArgs = argh::from_env();
 
-        log::debug!("All capturing finished");
-        Ok(())
+    let (capture, rx_pipeline) = pipeline::RxPipeline::spawn(pipeline::PipelineConfig {
+        guard_bands,
+        modulation,
+        ecc: true,
+        ..Default::default()
     });
 
-    let dims = (24, 24);
-    // let dims = (50, 50);
+    let sync_channel = std::thread::spawn(move || match cfg.replay.clone() {
+        Some(path) => capture_file(capture, &cfg, &path),
+        None => capture_usrp(capture),
+    });
 
-    use minifb::{Key, ScaleMode, Window, WindowOptions};
-    let width = dims.0;
-    let height = dims.1;
+    // Frames say how big they are, and get stretched to fill the window
+    let (width, height) = WINDOW_SIZE;
 
+    use minifb::{Key, ScaleMode, Window, WindowOptions};
     let mut window = Window::new(
         "Noise Test - Press ESC to exit",
-        width as usize,
-        height as usize,
+        width,
+        height,
         WindowOptions {
             resize: true,
             scale_mode: ScaleMode::AspectRatioStretch,
@@ -79,37 +131,36 @@ fn main() -> Result<()> {
 
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
 
-    let mut display_samples = vec![0; 892];
+    // Palettes come in the stream ahead of the frames that use them
+    let mut images = ImageReceiver::new();
+    let mut display = (vec![0; width * height], width, height);
     loop {
-        if let Ok(samples) = recv_ready.try_recv() {
-            log::debug!("starting decode...");
-            let res_data = ofdm::decode!(samples, guard_bands, modulation);
-            if res_data.is_err() {
-                log::debug!("failed to decode");
-                continue;
+        if let Some(data) = rx_pipeline.try_recv() {
+            log::debug!("bytes: {}", data.len());
+            if let Some((frame, rgb)) = images.push(&data) {
+                log::debug!(
+                    "Got frame {}, {}x{}",
+                    frame.header.frame,
+                    frame.width(),
+                    frame.height()
+                );
+                display = (rgb, frame.width(), frame.height());
+            } else {
+                log::debug!(
+                    "No frame, {} corrupted, {} missing their palette",
+                    images.corrupted,
+                    images.missing_palette
+                );
             }
-            let data = res_data.unwrap();
 
-            let mut bytes_iter = data.into_iter();
-
-            log::debug!("bytes: {}", bytes_iter.len());
-            let decoded = crate::utils::decipher_transmision_colorspace(&mut bytes_iter, true);
-            if let Some(img) = decoded {
-                dbg!(img.len());
-                log::debug!("We got something? {}", img.len());
-                if img.len() != 892 {
-                    continue;
-                }
-
-                display_samples = img;
-            } else {
-                log::debug!("Decoding image failed");
+            log::debug!("{:?}", rx_pipeline.stats());
+            if let Some(report) = rx_pipeline.last_report() {
+                log::debug!("{}", report);
             }
         }
         std::thread::sleep(std::time::Duration::from_millis(16));
-        window
-            .update_with_buffer(&display_samples, width as usize, height as usize)
-            .unwrap();
+        let (buffer, width, height) = &display;
+        window.update_with_buffer(buffer, *width, *height).unwrap();
     }
 
     Ok(())
@@ -21,7 +21,9 @@ fn main() {
         // 3) Receive and decode the samples
         .pipe(|samples| ofdm::decode!(samples, guard_bands, modulation).expect("Failed to decode"))
         // 5) print out the analysis
-        .pipe(|received_data| {
+        .pipe(|(received_data, report)| {
+            println!("{}", report);
+
             // Print the bit data to the terminal
             // utils::debug_data(source_data.as_ref(), &received_data);
 
@@ -12,6 +12,9 @@ const guard_bands: bool = true;
 const ecc_enabled: bool = true;
 const modulation: ModulationScheme = ModulationScheme::Bpsk;
 
+// The rate the link runs at, recordings at other rates are converted to this
+const SAMPLE_RATE: f64 = 1e6;
+
 fn transmit(path: &str) {
     let data = utils::create_transmission_text(num_bytes, ecc_enabled);
 
@@ -23,7 +26,7 @@ fn transmit(path: &str) {
     file.write_all(&utils::sig_to_bytes(samples)).unwrap();
 }
 
-fn receive(path: &str, start: Option<usize>, stop: Option<usize>) {
+fn receive(path: &str, start: Option<usize>, stop: Option<usize>, rate: Option<f64>) {
     let mut file = File::open(path).unwrap();
     let mut buf = Vec::new();
     file.read_to_end(&mut buf).unwrap();
@@ -31,9 +34,18 @@ fn receive(path: &str, start: Option<usize>, stop: Option<usize>) {
 
     let samples = samples[start.unwrap_or(0)..stop.unwrap_or_else(|| samples.len())].to_vec();
 
+    let samples = match rate {
+        Some(rate) if rate != SAMPLE_RATE => {
+            RationalResampler::from_rates(rate, SAMPLE_RATE).process(&samples)
+        }
+        _ => samples,
+    };
+
     dbg!(samples.len());
 
-    let received_data = ofdm::decode!(samples, guard_bands, modulation).expect("Failed to decode");
+    let (received_data, report) =
+        ofdm::decode!(samples, guard_bands, modulation).expect("Failed to decode");
+    println!("{}", report);
 
     // Debug the output
     let source_data = utils::create_transmission_text(num_bytes, ecc_enabled);
@@ -71,6 +83,10 @@ struct CmdArgs {
     /// stop slice of received data
     #[argh(option)]
     stop: Option<usize>,
+
+    /// sample rate the file was recorded at, if not 1 MHz
+    #[argh(option)]
+    rate: Option<f64>,
 }
 
 fn main() {
@@ -78,7 +94,7 @@ fn main() {
     let cfg: CmdArgs = argh::from_env();
     match (cfg.transmit, cfg.receive) {
         (Some(t), None) => transmit(&t),
-        (None, Some(t)) => receive(&t, cfg.start, cfg.stop),
+        (None, Some(t)) => receive(&t, cfg.start, cfg.stop, cfg.rate),
         _ => panic!("Not a valid argument combination, specify transmit or receive, but not both"),
     }
 }
@@ -39,7 +39,8 @@ fn receive(path: &str, start: Option<usize>, stop: Option<usize>) {
     // let samples = samples[815000..samples.len() - 172000].to_vec();
     dbg!(samples.len());
 
-    let received_data = ofdm::decode!(samples, guard_bands).expect("Failed to decode");
+    let (received_data, report) = ofdm::decode!(samples, guard_bands).expect("Failed to decode");
+    println!("{}", report);
 
     // Debug the output
     let i = transmission_bytes.clone().into_iter();
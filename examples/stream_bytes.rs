@@ -1,37 +1,20 @@
-use colored::Colorize;
+//! Pre-encodes the dancing gif as image packets, for jetson_rx to show. The first file is the
+//! gif's palette and the rest are its frames.
 use num::complex::Complex64;
+use ofdm::packets::images::{self, PaletteMethod};
 use ofdm::*;
+use std::fs::File;
 use std::io::prelude::*;
 
-use image::codecs::gif::*;
-use image::Rgba;
-use std::io::prelude::*;
-use std::io::Read;
-use std::{fs::File, io};
-
 const guard_bands: bool = true;
-const ecc_enabled: bool = true;
 
 fn main() {
-    let files = [
-        "support/dancing_0.bytes",
-        "support/dancing_1.bytes",
-        "support/dancing_2.bytes",
-        "support/dancing_3.bytes",
-        "support/dancing_4.bytes",
-        "support/dancing_5.bytes",
-        "support/dancing_6.bytes",
-        "support/dancing_7.bytes",
-    ];
-
-    for (idx, file) in files.iter().enumerate() {
-        let mut opened = File::open(file).unwrap();
-        let mut bytes = Vec::new();
-        opened.read_to_end(&mut bytes).unwrap();
+    let gif = include_bytes!("../support/dancing_super_small.gif");
+    let packets =
+        images::image_to_packets(gif, None, Some(PaletteMethod::KMeans), None, Some(true)).unwrap();
 
-        // let data = utils::create_transmission_text(num_bytes, ecc_enabled);
-        let i = bytes.into_iter();
-        let data = utils::create_transmission_bytes(&mut i.map(|f| f));
+    for (idx, packet) in packets.into_iter().enumerate() {
+        let data = utils::create_transmission_bytes(&mut packet.into_iter());
 
         let samples: Vec<Complex64> = ofdm::encode!(data: data.as_ref(), guard_bands);
 
@@ -0,0 +1,182 @@
+//! Carry IP traffic over the OFDM link through Linux TUN devices
+//!
+//! Every packet the kernel routes into a TUN device is fragmented, Reed-Solomon coded, encoded
+//! and sent. Whatever makes it through is decoded, reassembled and written back into the TUN
+//! device on the other side, where the kernel delivers it like any other packet.
+//!
+//! In loopback mode both ends run in this process, joined by the simulated `channel`. Creating
+//! TUN devices needs root (or CAP_NET_ADMIN), and putting each end in its own network namespace
+//! keeps the kernel from short circuiting traffic between two local addresses:
+//!
+//! ```text
+//! sudo ip netns add left
+//! sudo ip netns add right
+//! sudo cargo run --release --example tun_bridge -- --loopback
+//!
+//! # in another shell, while the bridge is running
+//! sudo ip link set ofdm0 netns left
+//! sudo ip link set ofdm1 netns right
+//! sudo ip -n left addr add 10.8.0.1/24 dev ofdm0
+//! sudo ip -n right addr add 10.8.0.2/24 dev ofdm1
+//! sudo ip -n left link set ofdm0 up
+//! sudo ip -n right link set ofdm1 up
+//! sudo ip netns exec left ping 10.8.0.2
+//! ```
+#![allow(non_upper_case_globals)]
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+
+use anyhow::{Context, Result};
+use num::complex::Complex64;
+use ofdm::packets::transport::{Fragmenter, Reassembler};
+use ofdm::*;
+
+const guard_bands: bool = true;
+const modulation: ModulationScheme = ModulationScheme::Bpsk;
+
+/// Bigger than any packet the kernel will hand over at the default MTU
+const MAX_PACKET: usize = 2048;
+
+// _IOW('T', 202, int), the same on x86_64 and aarch64
+const TUNSETIFF: u64 = 0x4004_54ca;
+
+/// Bridge IP traffic between TUN devices over OFDM
+#[derive(argh::FromArgs)]
+struct CmdArgs {
+    /// run both ends in this process over the simulated channel
+    #[argh(switch)]
+    loopback: bool,
+
+    /// name of the first tun device
+    #[argh(option, default = "String::from(\"ofdm0\")")]
+    left: String,
+
+    /// name of the second tun device, for loopback
+    #[argh(option, default = "String::from(\"ofdm1\")")]
+    right: String,
+
+    /// most bytes per frame, fragment header included
+    #[argh(option, default = "256")]
+    frame_len: usize,
+
+    /// snr of the simulated channel in dB
+    #[argh(option, default = "25.0")]
+    snr: f64,
+}
+
+/// The layout `TUNSETIFF` expects: a `struct ifreq` with only the name and flags filled in
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Open (or create) a TUN device. Reads and writes on the file are whole IP packets.
+fn open_tun(name: &str) -> Result<File> {
+    anyhow::ensure!(
+        name.len() < libc::IFNAMSIZ,
+        "interface names are at most {} characters",
+        libc::IFNAMSIZ - 1
+    );
+
+    let path = CString::new("/dev/net/tun").unwrap();
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDWR) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to open /dev/net/tun");
+    }
+
+    // Taking ownership right away closes the fd if anything below fails
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    let mut request = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        // No protocol info in front of every packet, just the packet
+        flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+        _pad: [0; 22],
+    };
+    for (slot, byte) in request.name.iter_mut().zip(name.bytes()) {
+        *slot = byte as libc::c_char;
+    }
+
+    if unsafe { libc::ioctl(fd, TUNSETIFF as _, &mut request) } < 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to set up tun device {}", name));
+    }
+
+    log::info!("Opened tun device {}", name);
+    Ok(file)
+}
+
+/// Forward every packet read from `from` over the link and write what arrives into `to`.
+/// `link` stands in for the radio: samples in, samples out.
+fn bridge(
+    mut from: File,
+    mut to: File,
+    frame_len: usize,
+    mut link: impl FnMut(Vec<Complex64>) -> Vec<Complex64>,
+) -> Result<()> {
+    let mut fragmenter = Fragmenter::new(frame_len).ecc(true);
+    let mut reassembler = Reassembler::new().ecc(true);
+
+    let mut buf = [0; MAX_PACKET];
+    loop {
+        let len = from.read(&mut buf).context("Failed to read from tun")?;
+        log::debug!("Sending a {} byte packet", len);
+
+        for frame in fragmenter.fragment(&buf[..len]) {
+            let samples = encode(&frame, Some(guard_bands), Some(modulation), None, None);
+
+            let frame = match decode(link(samples), Some(guard_bands), Some(modulation)) {
+                Ok((frame, _)) => frame,
+                Err(err) => {
+                    log::debug!("Lost a frame: {}", err);
+                    continue;
+                }
+            };
+
+            // Writing fails while the other device is down, and the packet is just lost
+            if let Some(packet) = reassembler.push(&frame) {
+                if let Err(err) = to.write_all(&packet) {
+                    log::debug!("Dropped a packet: {}", err);
+                }
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    ofdm::logging::set_up_logging("tun_bridge");
+    let cfg: CmdArgs = argh::from_env();
+
+    // The radio side needs a way to push samples out, which the USRP bindings don't have yet
+    anyhow::ensure!(cfg.loopback, "only --loopback is supported for now");
+
+    let left = open_tun(&cfg.left)?;
+    let right = open_tun(&cfg.right)?;
+    let snr = cfg.snr;
+    let frame_len = cfg.frame_len;
+
+    // Both directions share the channel model but get their own noise
+    let left_to_right = {
+        let (from, to) = (left.try_clone()?, right.try_clone()?);
+        std::thread::spawn(move || {
+            bridge(from, to, frame_len, |samples| {
+                channel(samples, Some(snr), None, None, None)
+            })
+        })
+    };
+    let right_to_left = std::thread::spawn(move || {
+        bridge(right, left, frame_len, |samples| {
+            channel(samples, Some(snr), None, None, None)
+        })
+    });
+
+    println!("Bridging {} and {}, ctrl-c to stop", cfg.left, cfg.right);
+    left_to_right.join().unwrap()?;
+    right_to_left.join().unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,138 @@
+//! Run either end of the link as its own process, with UDP standing in for the radio
+//!
+//! ```text
+//! cargo run --release --example udp_link -- --receive
+//! cargo run --release --example udp_link -- --transmit --snr 15
+//! ```
+//!
+//! Either end can be swapped for GNU Radio's UDP source or sink, with the sequence number header
+//! and the same payload size.
+#![allow(non_upper_case_globals)]
+
+use std::time::Duration;
+
+use anyhow::Result;
+use num::complex::Complex64;
+use ofdm::radio::{ImpairedSink, SampleSink, SampleSource, UdpHeader, UdpSink, UdpSource};
+use ofdm::*;
+
+const guard_bands: bool = true;
+const modulation: ModulationScheme = ModulationScheme::Bpsk;
+
+// The receiver's timing search needs some samples before the packet starts
+const LEAD_IN: usize = 500;
+
+/// Send or receive OFDM packets over UDP
+#[derive(argh::FromArgs)]
+struct CmdArgs {
+    /// send packets
+    #[argh(switch, short = 't')]
+    transmit: bool,
+
+    /// receive packets
+    #[argh(switch, short = 'r')]
+    receive: bool,
+
+    /// where the receiver listens
+    #[argh(option, default = "String::from(\"127.0.0.1:5005\")")]
+    addr: String,
+
+    /// add noise at this snr in dB before sending
+    #[argh(option)]
+    snr: Option<f64>,
+
+    /// how many packets to send
+    #[argh(option, default = "10")]
+    count: usize,
+
+    /// bytes in every packet
+    #[argh(option, default = "300")]
+    bytes: usize,
+
+    /// samples per second to send at, like the radio would
+    #[argh(option, default = "1e6")]
+    sample_rate: f64,
+
+    /// milliseconds between packets
+    #[argh(option, default = "200")]
+    interval: u64,
+}
+
+fn transmit(cfg: &CmdArgs) -> Result<()> {
+    let udp = UdpSink::connect(&cfg.addr)?
+        .header(UdpHeader::SequenceNumber)
+        .sample_rate(cfg.sample_rate);
+    let mut sink: Box<dyn SampleSink> = match cfg.snr {
+        Some(snr) => {
+            let impairments = Impairments::new().then(Awgn::new(NoiseLevel::Snr(snr), 0));
+            Box::new(ImpairedSink::new(udp, impairments))
+        }
+        None => Box::new(udp),
+    };
+
+    for idx in 0..cfg.count {
+        let mut data = format!("packet {}\n", idx).into_bytes();
+        data.extend(utils::create_transmission_text(cfg.bytes, false));
+        data.truncate(cfg.bytes);
+
+        // Silence ahead of the packet, like a radio that keeps streaming between packets
+        let mut samples = vec![Complex64::default(); LEAD_IN];
+        samples.extend(encode(
+            &data,
+            Some(guard_bands),
+            Some(modulation),
+            None,
+            None,
+        ));
+        sink.send(&samples)?;
+
+        log::info!("Sent packet {}", idx);
+        std::thread::sleep(Duration::from_millis(cfg.interval));
+    }
+
+    Ok(())
+}
+
+fn receive(cfg: &CmdArgs) -> Result<()> {
+    let mut source = UdpSource::bind(&cfg.addr)?.header(UdpHeader::SequenceNumber);
+    let (capture, rx_pipeline) = pipeline::RxPipeline::spawn(pipeline::PipelineConfig {
+        guard_bands,
+        modulation,
+        ecc: false,
+        ..Default::default()
+    });
+
+    std::thread::spawn(move || -> Result<()> {
+        let mut buf = vec![Complex64::default(); 10_000];
+        loop {
+            let len = source.receive(&mut buf)?;
+            if len > 0 && !capture.push(buf[..len].to_vec()) {
+                log::debug!("Pipeline overflowed, {} samples dropped", len);
+            }
+        }
+    });
+
+    println!("Listening on {}", cfg.addr);
+    for payload in rx_pipeline.iter() {
+        let text = String::from_utf8_lossy(&payload);
+        println!("{}", text.lines().next().unwrap_or_default());
+
+        if let Some(report) = rx_pipeline.last_report() {
+            log::info!("{}", report);
+        }
+        log::debug!("{:?}", rx_pipeline.stats());
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    ofdm::logging::set_up_logging("udp_link");
+    let cfg: CmdArgs = argh::from_env();
+
+    match (cfg.transmit, cfg.receive) {
+        (true, false) => transmit(&cfg),
+        (false, true) => receive(&cfg),
+        _ => anyhow::bail!("Pick one of --transmit or --receive"),
+    }
+}
@@ -0,0 +1,161 @@
+//! Stream raw video over the simulated channel, concealing whatever slices get lost
+//!
+//! Frames come in on stdin as raw pixels from an external encoder, and come out in a window or
+//! a raw file:
+//!
+//! ```text
+//! ffmpeg -i clip.mp4 -vf scale=96:72 -f rawvideo -pix_fmt rgb24 - \
+//!     | cargo run --release --example video -- --width 96 --height 72 --snr 16
+//!
+//! ffmpeg -i clip.mp4 -vf scale=96:72 -f rawvideo -pix_fmt rgb24 - \
+//!     | cargo run --release --example video -- --width 96 --height 72 --output out.rgb
+//! ffplay -f rawvideo -pixel_format rgb24 -video_size 96x72 out.rgb
+//! ```
+#![allow(non_upper_case_globals)]
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use anyhow::{Context, Result};
+use minifb::{Key, ScaleMode, Window, WindowOptions};
+use ofdm::packets::video::{VideoDepacketizer, VideoFrame, VideoPacketizer};
+use ofdm::*;
+
+const guard_bands: bool = true;
+const modulation: ModulationScheme = ModulationScheme::Bpsk;
+
+/// Send raw video frames from stdin over OFDM
+#[derive(argh::FromArgs)]
+struct CmdArgs {
+    /// width of every frame in pixels
+    #[argh(option)]
+    width: usize,
+
+    /// height of every frame in pixels
+    #[argh(option)]
+    height: usize,
+
+    /// frames are gray8 instead of rgb24
+    #[argh(switch)]
+    gray: bool,
+
+    /// most bytes per packet, slice header included
+    #[argh(option, default = "512")]
+    mtu: usize,
+
+    /// snr of the simulated channel in dB
+    #[argh(option, default = "20.0")]
+    snr: f64,
+
+    /// write received frames to this file instead of showing them
+    #[argh(option)]
+    output: Option<String>,
+}
+
+/// Where received frames end up
+enum Output {
+    Window(Window),
+    File(File),
+}
+
+impl Output {
+    fn show(&mut self, frame: &VideoFrame) -> Result<()> {
+        match self {
+            Output::File(file) => file.write_all(&frame.pixels)?,
+            Output::Window(window) => {
+                let buffer = frame
+                    .pixels
+                    .chunks(frame.bytes_per_pixel)
+                    .map(|pixel| match pixel {
+                        [r, g, b] => u32::from_be_bytes([0, *r, *g, *b]),
+                        [v] => u32::from_be_bytes([0, *v, *v, *v]),
+                        _ => 0,
+                    })
+                    .collect::<Vec<_>>();
+                window.update_with_buffer(&buffer, frame.width, frame.height)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Output::File(_) => true,
+            Output::Window(window) => window.is_open() && !window.is_key_down(Key::Escape),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    ofdm::logging::set_up_logging("video");
+    let cfg: CmdArgs = argh::from_env();
+
+    let bytes_per_pixel = if cfg.gray { 1 } else { 3 };
+    let mut packetizer = VideoPacketizer::new(cfg.width, cfg.height, bytes_per_pixel, cfg.mtu);
+    let mut depacketizer = VideoDepacketizer::new();
+
+    let mut output = match &cfg.output {
+        Some(path) => {
+            Output::File(File::create(path).with_context(|| format!("Failed to create {}", path))?)
+        }
+        None => Output::Window(Window::new(
+            "Video - Press ESC to exit",
+            cfg.width,
+            cfg.height,
+            WindowOptions {
+                resize: true,
+                scale_mode: ScaleMode::AspectRatioStretch,
+                scale: minifb::Scale::X4,
+                ..WindowOptions::default()
+            },
+        )?),
+    };
+
+    let mut stdin = io::stdin();
+    let mut pixels = vec![0; packetizer.frame_len()];
+    let mut concealed = 0;
+    let mut frames = 0;
+
+    while output.is_open() {
+        // The encoder closing stdin is the end of the stream
+        match stdin.read_exact(&mut pixels) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err).context("Failed to read a frame from stdin"),
+        }
+
+        let mut shown = Vec::new();
+        for packet in packetizer.packetize(&pixels) {
+            let samples = encode(&packet, Some(guard_bands), Some(modulation), None, None);
+            let received = channel(samples, Some(cfg.snr), None, None, None);
+
+            match decode(received, Some(guard_bands), Some(modulation)) {
+                Ok((packet, _)) => shown.extend(depacketizer.push(&packet)),
+                Err(err) => log::debug!("Lost a slice: {}", err),
+            }
+        }
+
+        for frame in shown {
+            log::debug!(
+                "Frame {}, {} slices concealed",
+                frame.number,
+                frame.concealed
+            );
+            concealed += frame.concealed;
+            frames += 1;
+            output.show(&frame)?;
+        }
+    }
+
+    if let Some(frame) = depacketizer.flush() {
+        concealed += frame.concealed;
+        frames += 1;
+        output.show(&frame)?;
+    }
+
+    println!(
+        "{} frames shown, {} slices concealed, {} frames skipped, {} packets corrupted",
+        frames, concealed, depacketizer.skipped, depacketizer.corrupted
+    );
+    Ok(())
+}
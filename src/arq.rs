@@ -0,0 +1,525 @@
+//! Automatic repeat request: acknowledgements and retransmissions on top of `encode`/`decode`.
+//!
+//! The link is half duplex, so the two ends take turns. The sender sends a burst of data frames
+//! and then listens. If the receiver heard anything at all it answers with an `Ack` listing the
+//! frames that arrived intact, or a `Nack` if everything it heard was corrupted. Silence means
+//! the burst or the answer was lost, and the sender only finds out when its timeout runs out.
+//! Every frame that hasn't been acknowledged goes out again in the next burst.
+//!
+//! - Stop-and-wait sends a single frame per burst, so every frame pays for a turnaround and an
+//!   acknowledgement.
+//! - Selective repeat sends up to a window of frames per burst, and the receiver holds on to
+//!   frames that arrive after a gap until the gap is filled in.
+//!
+//! `simulate` runs both ends through the `channel` simulator in one process, keeping time in
+//! samples so goodput can be compared at every SNR.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+
+use serde::{Deserialize, Serialize};
+
+use crate::packets::transport::crc32;
+use crate::ModulationScheme;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Frame {
+    Data {
+        sequence: u16,
+        payload: Vec<u8>,
+    },
+
+    /// The data frames that arrived intact during the last burst
+    Ack(Vec<u16>),
+
+    /// Something arrived during the last burst, but none of it was intact
+    Nack,
+}
+
+impl Frame {
+    /// Serialize with a CRC-32 on the end
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = bincode::serialize(self).unwrap();
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Parse a frame, or `None` if it was corrupted on the way
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 {
+            return None;
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        match crc32(body) == u32::from_le_bytes(checksum.try_into().unwrap()) {
+            true => bincode::deserialize(body).ok(),
+            false => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArqMode {
+    StopAndWait,
+
+    /// Up to `window` frames in flight at once
+    SelectiveRepeat {
+        window: usize,
+    },
+}
+
+impl ArqMode {
+    fn window(&self) -> usize {
+        match *self {
+            ArqMode::StopAndWait => 1,
+            ArqMode::SelectiveRepeat { window } => {
+                // Old and new frames have to be told apart after the sequence numbers wrap
+                assert!(
+                    window > 0 && window <= 1 << 15,
+                    "window must be between 1 and 32768"
+                );
+                window
+            }
+        }
+    }
+}
+
+/// The sending end: numbers the payloads and keeps resending them until they're acknowledged
+pub struct Sender {
+    window: usize,
+    next_sequence: u16,
+    queue: VecDeque<Vec<u8>>,
+
+    /// Sent but not yet acknowledged, oldest first
+    outstanding: Vec<(u16, Vec<u8>)>,
+
+    pub transmissions: usize,
+    pub retransmissions: usize,
+    pub timeouts: usize,
+}
+
+impl Sender {
+    pub fn new(mode: ArqMode) -> Self {
+        Self {
+            window: mode.window(),
+            next_sequence: 0,
+            queue: VecDeque::new(),
+            outstanding: Vec::new(),
+            transmissions: 0,
+            retransmissions: 0,
+            timeouts: 0,
+        }
+    }
+
+    /// Queue a payload to be sent
+    pub fn push(&mut self, payload: Vec<u8>) {
+        self.queue.push_back(payload);
+    }
+
+    /// Whether everything queued has been acknowledged
+    pub fn is_done(&self) -> bool {
+        self.queue.is_empty() && self.outstanding.is_empty()
+    }
+
+    /// The frames to send this turn: everything unacknowledged, topped up with new payloads as
+    /// far as the window allows
+    pub fn burst(&mut self) -> Vec<Vec<u8>> {
+        self.retransmissions += self.outstanding.len();
+
+        let base = self
+            .outstanding
+            .first()
+            .map_or(self.next_sequence, |(sequence, _)| *sequence);
+
+        while (self.next_sequence.wrapping_sub(base) as usize) < self.window {
+            let payload = match self.queue.pop_front() {
+                Some(payload) => payload,
+                None => break,
+            };
+            self.outstanding.push((self.next_sequence, payload));
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+        }
+
+        self.transmissions += self.outstanding.len();
+        self.outstanding
+            .iter()
+            .map(|(sequence, payload)| {
+                Frame::Data {
+                    sequence: *sequence,
+                    payload: payload.clone(),
+                }
+                .to_bytes()
+            })
+            .collect()
+    }
+
+    /// Take in whatever came back after a burst. `None` means the timeout ran out first.
+    pub fn on_feedback(&mut self, feedback: Option<Frame>) {
+        match feedback {
+            Some(Frame::Ack(acked)) => self
+                .outstanding
+                .retain(|(sequence, _)| !acked.contains(sequence)),
+            Some(_) => {}
+            None => self.timeouts += 1,
+        }
+    }
+}
+
+/// The receiving end: puts frames back in order and works out what to acknowledge
+pub struct Receiver {
+    window: usize,
+
+    /// The next sequence number to hand over
+    next_expected: u16,
+
+    /// Frames that arrived ahead of a gap
+    buffered: HashMap<u16, Vec<u8>>,
+    delivered: Vec<Vec<u8>>,
+
+    /// What happened during the current burst
+    heard: bool,
+    acked: Vec<u16>,
+}
+
+impl Receiver {
+    pub fn new(mode: ArqMode) -> Self {
+        Self {
+            window: mode.window(),
+            next_expected: 0,
+            buffered: HashMap::new(),
+            delivered: Vec::new(),
+            heard: false,
+            acked: Vec::new(),
+        }
+    }
+
+    /// Take in one transmission from the sender. `None` means nothing could be decoded at all.
+    pub fn receive(&mut self, bytes: Option<&[u8]>) {
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return,
+        };
+        self.heard = true;
+
+        let (sequence, payload) = match Frame::from_bytes(bytes) {
+            Some(Frame::Data { sequence, payload }) => (sequence, payload),
+            _ => return,
+        };
+
+        // Anything from behind the window was delivered already and only needs acking again
+        self.acked.push(sequence);
+        let ahead = sequence.wrapping_sub(self.next_expected) as usize;
+        if ahead >= self.window {
+            return;
+        }
+
+        self.buffered.insert(sequence, payload);
+        while let Some(payload) = self.buffered.remove(&self.next_expected) {
+            self.delivered.push(payload);
+            self.next_expected = self.next_expected.wrapping_add(1);
+        }
+    }
+
+    /// What to send back now the burst is over, if anything was heard
+    pub fn feedback(&mut self) -> Option<Frame> {
+        let heard = std::mem::take(&mut self.heard);
+        let acked = std::mem::take(&mut self.acked);
+
+        match (heard, acked.is_empty()) {
+            (false, _) => None,
+            (true, true) => Some(Frame::Nack),
+            (true, false) => Some(Frame::Ack(acked)),
+        }
+    }
+
+    /// Payloads that have arrived in order since the last call
+    pub fn take_delivered(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.delivered)
+    }
+}
+
+/// Both directions of a simulated half duplex link, through `channel`
+pub struct SimulatedLink {
+    pub snr: f64,
+    pub guard_bands: bool,
+    pub modulation: ModulationScheme,
+    seed: u64,
+}
+
+impl SimulatedLink {
+    pub fn new(snr: f64, seed: u64) -> Self {
+        Self {
+            snr,
+            guard_bands: true,
+            modulation: ModulationScheme::Bpsk,
+            seed,
+        }
+    }
+
+    /// Send one frame, returning how many samples it was on the air and what the other end
+    /// decoded, if anything
+    pub fn transmit(&mut self, frame: &[u8]) -> (usize, Option<Vec<u8>>) {
+        let samples = crate::encode(
+            frame,
+            Some(self.guard_bands),
+            Some(self.modulation),
+            None,
+            None,
+        );
+        let airtime = samples.len();
+
+        // Every transmission sees fresh noise
+        self.seed = self.seed.wrapping_add(1);
+        let received = crate::channel(samples, Some(self.snr), None, None, Some(self.seed));
+
+        let decoded = crate::decode(received, Some(self.guard_bands), Some(self.modulation));
+        (airtime, decoded.ok().map(|(bytes, _)| bytes))
+    }
+
+    /// Like `transmit`, but hand back log likelihood ratios for everything after the header
+    pub fn transmit_soft(&mut self, frame: &[u8]) -> (usize, Option<Vec<f64>>) {
+        let samples = crate::encode(
+            frame,
+            Some(self.guard_bands),
+            Some(self.modulation),
+            None,
+            None,
+        );
+        let airtime = samples.len();
+
+        self.seed = self.seed.wrapping_add(1);
+        let received = crate::channel(samples, Some(self.snr), None, None, Some(self.seed));
+
+        let decoded = crate::decode_soft(received, Some(self.guard_bands), Some(self.modulation));
+        (airtime, decoded.ok())
+    }
+}
+
+/// How a link is run. Hybrid ARQ uses the same settings with its own modes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArqConfig<M = ArqMode> {
+    pub mode: M,
+
+    /// Data bytes per frame
+    pub payload_len: usize,
+
+    /// Samples lost every time the radio switches between sending and receiving
+    pub turnaround: usize,
+
+    /// Samples the sender waits after its burst before giving up on feedback. Has to cover
+    /// two turnarounds and the feedback itself.
+    pub timeout: usize,
+}
+
+impl<M> ArqConfig<M> {
+    pub fn new(mode: M) -> Self {
+        Self {
+            mode,
+            payload_len: 200,
+            turnaround: 500,
+            timeout: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArqStats {
+    /// Everything the receiver handed over, in order
+    pub delivered: Vec<u8>,
+
+    /// Samples from the first burst until the last acknowledgement, or the time limit
+    pub elapsed: usize,
+
+    pub transmissions: usize,
+    pub retransmissions: usize,
+    pub timeouts: usize,
+
+    /// Frames given up on. Only hybrid ARQ ever stops resending.
+    pub dropped: usize,
+}
+
+impl ArqStats {
+    /// Delivered bits per second of link time
+    pub fn goodput(&self, sample_rate: f64) -> f64 {
+        match self.elapsed {
+            0 => 0.0,
+            elapsed => self.delivered.len() as f64 * 8.0 * sample_rate / elapsed as f64,
+        }
+    }
+}
+
+/// Send `data` across a simulated link with ARQ, giving up once `max_samples` of link time have
+/// passed
+pub fn simulate(
+    data: &[u8],
+    config: ArqConfig,
+    link: &mut SimulatedLink,
+    max_samples: usize,
+) -> ArqStats {
+    assert!(config.payload_len > 0, "frames need room for data");
+
+    let mut sender = Sender::new(config.mode);
+    let mut receiver = Receiver::new(config.mode);
+    for payload in data.chunks(config.payload_len) {
+        sender.push(payload.to_vec());
+    }
+
+    let mut delivered = Vec::new();
+    let mut clock = 0;
+    while !sender.is_done() && clock < max_samples {
+        for frame in sender.burst() {
+            let (airtime, decoded) = link.transmit(&frame);
+            clock += airtime;
+            receiver.receive(decoded.as_deref());
+        }
+
+        let feedback = receiver.feedback().and_then(|frame| {
+            let (airtime, decoded) = link.transmit(&frame.to_bytes());
+            Some((airtime, decoded.as_deref().and_then(Frame::from_bytes)?))
+        });
+
+        match feedback {
+            Some((airtime, frame)) => {
+                clock += 2 * config.turnaround + airtime;
+                sender.on_feedback(Some(frame));
+            }
+            None => {
+                clock += config.timeout;
+                sender.on_feedback(None);
+            }
+        }
+
+        delivered.extend(receiver.take_delivered().into_iter().flatten());
+    }
+
+    ArqStats {
+        delivered,
+        elapsed: clock,
+        transmissions: sender.transmissions,
+        retransmissions: sender.retransmissions,
+        timeouts: sender.timeouts,
+        dropped: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        crate::utils::create_transmission_text(1000, false)
+    }
+
+    #[test]
+    fn frames_catch_corruption() {
+        let frame = Frame::Data {
+            sequence: 9,
+            payload: b"payload".to_vec(),
+        };
+        let mut bytes = frame.to_bytes();
+        assert_eq!(Frame::from_bytes(&bytes), Some(frame));
+
+        bytes[5] ^= 1;
+        assert_eq!(Frame::from_bytes(&bytes), None);
+        assert_eq!(Frame::from_bytes(&[1, 2]), None);
+    }
+
+    #[test]
+    fn selective_repeat_fills_gaps() {
+        let mode = ArqMode::SelectiveRepeat { window: 4 };
+        let mut sender = Sender::new(mode);
+        let mut receiver = Receiver::new(mode);
+        for idx in 0..6u8 {
+            sender.push(vec![idx]);
+        }
+
+        // The second frame is lost, so nothing after the first can be delivered yet
+        let burst = sender.burst();
+        assert_eq!(burst.len(), 4);
+        for (idx, frame) in burst.iter().enumerate() {
+            receiver.receive(if idx == 1 { None } else { Some(frame) });
+        }
+        assert_eq!(receiver.take_delivered(), vec![vec![0]]);
+
+        let feedback = receiver.feedback();
+        assert_eq!(feedback, Some(Frame::Ack(vec![0, 2, 3])));
+        sender.on_feedback(feedback);
+
+        // The window only slides past the first frame: the resend plus one new frame
+        let burst = sender.burst();
+        let sequences = burst
+            .iter()
+            .map(|b| match Frame::from_bytes(b) {
+                Some(Frame::Data { sequence, .. }) => sequence,
+                other => panic!("{:?}", other),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sequences, vec![1, 4]);
+
+        for frame in burst.iter() {
+            receiver.receive(Some(frame));
+        }
+        assert_eq!(
+            receiver.take_delivered(),
+            vec![vec![1], vec![2], vec![3], vec![4]]
+        );
+        sender.on_feedback(receiver.feedback());
+        assert_eq!(sender.retransmissions, 1);
+    }
+
+    #[test]
+    fn lost_acks_are_resent_without_duplicates() {
+        let mut sender = Sender::new(ArqMode::StopAndWait);
+        let mut receiver = Receiver::new(ArqMode::StopAndWait);
+        sender.push(b"once".to_vec());
+
+        // The ack never makes it back
+        receiver.receive(Some(&sender.burst()[0]));
+        receiver.feedback();
+        sender.on_feedback(None);
+
+        receiver.receive(Some(&sender.burst()[0]));
+        sender.on_feedback(receiver.feedback());
+
+        assert!(sender.is_done());
+        assert_eq!(sender.timeouts, 1);
+        assert_eq!(receiver.take_delivered(), vec![b"once".to_vec()]);
+    }
+
+    #[test]
+    fn corrupted_bursts_are_nacked() {
+        let mut receiver = Receiver::new(ArqMode::StopAndWait);
+        receiver.receive(Some(&[0, 1, 2, 3, 4, 5]));
+        assert_eq!(receiver.feedback(), Some(Frame::Nack));
+        assert_eq!(receiver.feedback(), None);
+    }
+
+    #[test]
+    fn both_modes_deliver_everything() {
+        let data = data();
+        for mode in [ArqMode::StopAndWait, ArqMode::SelectiveRepeat { window: 8 }].iter() {
+            let mut link = SimulatedLink::new(20.0, 1);
+            let stats = simulate(&data, ArqConfig::new(*mode), &mut link, usize::MAX);
+            assert_eq!(stats.delivered, data, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn selective_repeat_has_more_goodput() {
+        let data = data();
+        let goodput = |mode| {
+            let mut link = SimulatedLink::new(20.0, 1);
+            simulate(&data, ArqConfig::new(mode), &mut link, usize::MAX).goodput(1e6)
+        };
+
+        let stop_and_wait = goodput(ArqMode::StopAndWait);
+        let selective_repeat = goodput(ArqMode::SelectiveRepeat { window: 8 });
+        assert!(
+            selective_repeat > stop_and_wait,
+            "{} vs {}",
+            selective_repeat,
+            stop_and_wait
+        );
+    }
+}
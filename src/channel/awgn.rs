@@ -0,0 +1,158 @@
+//! Complex Gaussian noise with an exact SNR.
+//!
+//! The noise power is always derived from the measured power of the signal, so the SNR that
+//! comes out of the channel is the SNR that was asked for.
+
+use std::f64::consts::PI;
+
+use num::complex::Complex64;
+use rand::Rng;
+
+use crate::signals::*;
+use crate::ModulationScheme;
+
+/// The block structure of the transmission, used to convert per-subcarrier energy into SNR
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OfdmFraming {
+    pub fft_len: usize,
+    pub prefix_len: usize,
+
+    /// Subcarriers that carry energy (data and pilots)
+    pub used_subcarriers: usize,
+}
+
+impl OfdmFraming {
+    /// The framing used by `encode`
+    pub fn new(guard_bands: bool) -> Self {
+        Self {
+            fft_len: 64,
+            prefix_len: 16,
+
+            // 11 sideband carriers and the dc carrier are left empty
+            used_subcarriers: if guard_bands { 52 } else { 64 },
+        }
+    }
+}
+
+/// How loud the noise should be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseLevel {
+    /// Average sample power over noise power, in dB
+    Snr(f64),
+
+    /// Energy per constellation symbol over the noise density, in dB
+    EsN0 { db: f64, framing: OfdmFraming },
+
+    /// Energy per information bit over the noise density, in dB
+    EbN0 {
+        db: f64,
+        modulation: ModulationScheme,
+        code_rate: f64,
+        framing: OfdmFraming,
+    },
+}
+
+impl NoiseLevel {
+    /// The linear signal to noise ratio measured over every sample of the transmission
+    ///
+    /// A symbol's energy is spread over the whole fft plus its cyclic prefix, but only lands in
+    /// the used subcarriers, so Es/N0 is scaled by used / (fft + prefix).
+    pub fn sample_snr(&self) -> f64 {
+        let es_n0_to_snr = |es_n0_db: f64, framing: &OfdmFraming| {
+            10_f64.powf(es_n0_db / 10.0) * framing.used_subcarriers as f64
+                / (framing.fft_len + framing.prefix_len) as f64
+        };
+
+        match self {
+            NoiseLevel::Snr(db) => 10_f64.powf(db / 10.0),
+            NoiseLevel::EsN0 { db, framing } => es_n0_to_snr(*db, framing),
+            NoiseLevel::EbN0 {
+                db,
+                modulation,
+                code_rate,
+                framing,
+            } => {
+                let bits = modulation.bits_per_symbol() as f64 * code_rate;
+                es_n0_to_snr(db + 10.0 * bits.log10(), framing)
+            }
+        }
+    }
+}
+
+/// Draw a circularly symmetric complex gaussian with unit variance using Box-Muller
+pub fn complex_gaussian(rng: &mut impl Rng) -> Complex64 {
+    // Keep away from ln(0)
+    let u1: f64 = 1.0 - rng.gen_range(0.0..1.0);
+    let u2: f64 = rng.gen_range(0.0..1.0);
+
+    let radius = (-u1.ln()).sqrt();
+    Complex64::from_polar(radius, 2.0 * PI * u2)
+}
+
+/// Add white gaussian noise in place, returning the noise variance that was used
+pub fn add_awgn(samples: &mut [Complex64], level: NoiseLevel, rng: &mut impl Rng) -> f64 {
+    let noise_var = samples.power() / level.sample_snr();
+    let scale = noise_var.sqrt();
+
+    for y in samples.iter_mut() {
+        *y += complex_gaussian(rng) * scale;
+    }
+
+    noise_var
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn gaussian_is_unit_variance() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let draws = (0..200_000)
+            .map(|_| complex_gaussian(&mut rng))
+            .collect::<Vec<_>>();
+
+        assert!(draws.mean().norm() < 0.01);
+        assert!((draws.power() - 1.0).abs() < 0.01);
+
+        // half the power in each quadrature
+        let re_power = draws.iter().map(|f| f.re * f.re).sum::<f64>() / draws.len() as f64;
+        assert!((re_power - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn snr_is_what_was_asked_for() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let clean = (0..100_000)
+            .map(|i| Complex64::from_polar(3.0, i as f64 * 0.1))
+            .collect::<Vec<_>>();
+
+        let mut noisy = clean.clone();
+        add_awgn(&mut noisy, NoiseLevel::Snr(10.0), &mut rng);
+
+        let noise = noisy
+            .iter()
+            .zip(clean.iter())
+            .map(|(n, c)| n - c)
+            .collect::<Vec<_>>();
+
+        let measured = 10.0 * (clean.power() / noise.power()).log10();
+        assert!((measured - 10.0).abs() < 0.1, "measured {}dB", measured);
+    }
+
+    #[test]
+    fn eb_n0_accounts_for_overhead() {
+        let framing = OfdmFraming::new(true);
+        let es = NoiseLevel::EsN0 { db: 10.0, framing };
+        let eb = NoiseLevel::EbN0 {
+            db: 10.0 - 10.0 * 2_f64.log10(),
+            modulation: ModulationScheme::Qpsk,
+            code_rate: 1.0,
+            framing,
+        };
+
+        assert!((es.sample_snr() - eb.sample_snr()).abs() < 1e-9);
+        assert!((es.sample_snr() - 10.0 * 52.0 / 80.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,380 @@
+//! Tapped-delay-line channel models with time-varying fading.
+//!
+//! The delay profiles come from 3GPP TS 36.104 (EPA/EVA/ETU) plus a simple indoor exponential
+//! profile. Each tap fades independently using a sum-of-sinusoids approximation of the Jakes
+//! Doppler spectrum, so a receiver can be tested against a channel that changes during a packet.
+//!
+//! Everything is driven off a seeded rng so that two runs with the same seed see the same channel.
+
+use std::f64::consts::PI;
+
+use num::complex::Complex64;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Number of sinusoids summed together to approximate the Jakes spectrum for every tap
+const JAKES_SINUSOIDS: usize = 16;
+
+/// A single path in a power delay profile
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tap {
+    pub delay_ns: f64,
+    pub power_db: f64,
+}
+
+impl Tap {
+    pub fn new(delay_ns: f64, power_db: f64) -> Self {
+        Self { delay_ns, power_db }
+    }
+}
+
+/// Power delay profiles to choose from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Profile {
+    /// A single tap with no delay spread
+    Flat,
+
+    /// Extended Pedestrian A
+    Epa,
+
+    /// Extended Vehicular A
+    Eva,
+
+    /// Extended Typical Urban
+    Etu,
+
+    /// Exponentially decaying taps spaced `spacing_ns` apart, truncated at -30dB
+    IndoorExponential { rms_delay_ns: f64, spacing_ns: f64 },
+
+    /// Bring your own taps
+    Custom(Vec<Tap>),
+}
+
+impl Profile {
+    pub fn taps(&self) -> Vec<Tap> {
+        let from_table = |delays: &[f64], powers: &[f64]| {
+            delays
+                .iter()
+                .zip(powers.iter())
+                .map(|(&d, &p)| Tap::new(d, p))
+                .collect()
+        };
+
+        match self {
+            Profile::Flat => vec![Tap::new(0.0, 0.0)],
+
+            Profile::Epa => from_table(
+                &[0.0, 30.0, 70.0, 90.0, 110.0, 190.0, 410.0],
+                &[0.0, -1.0, -2.0, -3.0, -8.0, -17.2, -20.8],
+            ),
+
+            Profile::Eva => from_table(
+                &[
+                    0.0, 30.0, 150.0, 310.0, 370.0, 710.0, 1090.0, 1730.0, 2510.0,
+                ],
+                &[0.0, -1.5, -1.4, -3.6, -0.6, -9.1, -7.0, -12.0, -16.9],
+            ),
+
+            Profile::Etu => from_table(
+                &[
+                    0.0, 50.0, 120.0, 200.0, 230.0, 500.0, 1600.0, 2300.0, 5000.0,
+                ],
+                &[-1.0, -1.0, -1.0, 0.0, 0.0, 0.0, -3.0, -5.0, -7.0],
+            ),
+
+            Profile::IndoorExponential {
+                rms_delay_ns,
+                spacing_ns,
+            } => {
+                // Anything else never decays below the cutoff
+                assert!(
+                    *spacing_ns > 0.0 && *rms_delay_ns > 0.0,
+                    "tap spacing and delay spread have to be positive"
+                );
+
+                let mut taps = Vec::new();
+                let mut delay = 0.0;
+                loop {
+                    // exp(-t / tau) expressed in dB
+                    let power_db = -10.0 * (delay / rms_delay_ns) * std::f64::consts::LOG10_E;
+                    if power_db < -30.0 {
+                        break;
+                    }
+                    taps.push(Tap::new(delay, power_db));
+                    delay += spacing_ns;
+                }
+                taps
+            }
+
+            Profile::Custom(taps) => taps.clone(),
+        }
+    }
+}
+
+/// How the taps change over time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fading {
+    /// Taps keep their nominal amplitude forever
+    Static,
+
+    /// Every tap is an independent Rayleigh process with the given maximum doppler shift
+    Rayleigh { doppler_hz: f64 },
+
+    /// The first tap carries a line of sight component `k_factor_db` above its scattered power
+    Rician { k_factor_db: f64, doppler_hz: f64 },
+}
+
+/// A configurable multipath channel that can be run over a stream of samples.
+///
+/// Delays are rounded onto the sample grid, and taps that land on the same sample have their
+/// powers summed. The total power of the profile is normalized to 1.
+pub struct ChannelModel {
+    taps: Vec<FadingTap>,
+    sample_rate: f64,
+    history: Vec<Complex64>,
+    sample_idx: u64,
+}
+
+impl ChannelModel {
+    pub fn new(profile: Profile, fading: Fading, sample_rate: f64, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        // Collapse the profile onto the sample grid
+        let mut grid: Vec<(usize, f64)> = Vec::new();
+        for tap in profile.taps() {
+            let delay = (tap.delay_ns * 1e-9 * sample_rate).round() as usize;
+            let power = 10_f64.powf(tap.power_db / 10.0);
+            match grid.iter_mut().find(|(d, _)| *d == delay) {
+                Some((_, p)) => *p += power,
+                None => grid.push((delay, power)),
+            }
+        }
+        grid.sort_by_key(|(d, _)| *d);
+
+        let total_power: f64 = grid.iter().map(|(_, p)| p).sum();
+
+        let taps = grid
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (delay, power))| {
+                let amplitude = (power / total_power).sqrt();
+                let process = match fading {
+                    Fading::Static => TapProcess::Static,
+                    Fading::Rayleigh { doppler_hz } => {
+                        TapProcess::Rayleigh(JakesOscillator::new(doppler_hz, &mut rng))
+                    }
+                    Fading::Rician {
+                        k_factor_db,
+                        doppler_hz,
+                    } if idx == 0 => TapProcess::Rician {
+                        k: 10_f64.powf(k_factor_db / 10.0),
+                        los_doppler: doppler_hz * rng.gen_range(-1.0..1.0),
+                        los_phase: rng.gen_range(0.0..2.0 * PI),
+                        scatter: JakesOscillator::new(doppler_hz, &mut rng),
+                    },
+                    Fading::Rician { doppler_hz, .. } => {
+                        TapProcess::Rayleigh(JakesOscillator::new(doppler_hz, &mut rng))
+                    }
+                };
+                FadingTap {
+                    delay,
+                    amplitude,
+                    process,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let max_delay = taps.iter().map(|t| t.delay).max().unwrap_or(0);
+
+        Self {
+            taps,
+            sample_rate,
+            history: vec![Complex64::default(); max_delay],
+            sample_idx: 0,
+        }
+    }
+
+    /// The largest tap delay, in samples
+    pub fn max_delay(&self) -> usize {
+        self.history.len()
+    }
+
+    /// The complex gain of every tap at the current point in time
+    pub fn tap_gains(&self) -> Vec<(usize, Complex64)> {
+        let t = self.sample_idx as f64 / self.sample_rate;
+        self.taps
+            .iter()
+            .map(|tap| (tap.delay, tap.gain(t)))
+            .collect()
+    }
+
+    /// Run a buffer of samples through the channel.
+    ///
+    /// The channel keeps its state between calls, so a long transmission can be fed through in
+    /// pieces and come out exactly the same as if it were passed in at once. The output is the
+    /// same length as the input; the tail of the impulse response carries into the next call.
+    pub fn apply(&mut self, samples: &[Complex64]) -> Vec<Complex64> {
+        let max_delay = self.history.len();
+
+        let mut input = Vec::with_capacity(max_delay + samples.len());
+        input.extend_from_slice(&self.history);
+        input.extend_from_slice(samples);
+
+        let mut output = vec![Complex64::default(); samples.len()];
+        for (idx, out) in output.iter_mut().enumerate() {
+            let t = (self.sample_idx + idx as u64) as f64 / self.sample_rate;
+            for tap in self.taps.iter() {
+                *out += tap.gain(t) * input[max_delay + idx - tap.delay];
+            }
+        }
+
+        self.sample_idx += samples.len() as u64;
+        self.history
+            .copy_from_slice(&input[input.len() - max_delay..]);
+
+        output
+    }
+}
+
+struct FadingTap {
+    delay: usize,
+    amplitude: f64,
+    process: TapProcess,
+}
+
+impl FadingTap {
+    fn gain(&self, t: f64) -> Complex64 {
+        let fade = match &self.process {
+            TapProcess::Static => Complex64::new(1.0, 0.0),
+            TapProcess::Rayleigh(osc) => osc.sample(t),
+            TapProcess::Rician {
+                k,
+                los_doppler,
+                los_phase,
+                scatter,
+            } => {
+                let los = Complex64::from_polar(1.0, 2.0 * PI * los_doppler * t + los_phase);
+                los * (k / (k + 1.0)).sqrt() + scatter.sample(t) * (1.0 / (k + 1.0)).sqrt()
+            }
+        };
+        fade * self.amplitude
+    }
+}
+
+enum TapProcess {
+    Static,
+    Rayleigh(JakesOscillator),
+    Rician {
+        k: f64,
+        los_doppler: f64,
+        los_phase: f64,
+        scatter: JakesOscillator,
+    },
+}
+
+/// Zheng & Xiao's sum-of-sinusoids generator for a unit power Rayleigh process
+///
+/// https://doi.org/10.1109/LCOMM.2002.1010855
+struct JakesOscillator {
+    doppler_hz: f64,
+    arrival: [f64; JAKES_SINUSOIDS],
+    phase_i: [f64; JAKES_SINUSOIDS],
+    phase_q: [f64; JAKES_SINUSOIDS],
+}
+
+impl JakesOscillator {
+    fn new(doppler_hz: f64, rng: &mut StdRng) -> Self {
+        let theta: f64 = rng.gen_range(-PI..PI);
+        let mut arrival = [0.0; JAKES_SINUSOIDS];
+        let mut phase_i = [0.0; JAKES_SINUSOIDS];
+        let mut phase_q = [0.0; JAKES_SINUSOIDS];
+
+        for n in 0..JAKES_SINUSOIDS {
+            arrival[n] = (2.0 * PI * (n + 1) as f64 - PI + theta) / (4.0 * JAKES_SINUSOIDS as f64);
+            phase_i[n] = rng.gen_range(-PI..PI);
+            phase_q[n] = rng.gen_range(-PI..PI);
+        }
+
+        Self {
+            doppler_hz,
+            arrival,
+            phase_i,
+            phase_q,
+        }
+    }
+
+    fn sample(&self, t: f64) -> Complex64 {
+        let w = 2.0 * PI * self.doppler_hz * t;
+        let mut re = 0.0;
+        let mut im = 0.0;
+        for n in 0..JAKES_SINUSOIDS {
+            re += (w * self.arrival[n].cos() + self.phase_i[n]).cos();
+            im += (w * self.arrival[n].sin() + self.phase_q[n]).cos();
+        }
+
+        // Each quadrature has a variance of 1/2 so the process has unit power
+        Complex64::new(re, im) / (JAKES_SINUSOIDS as f64).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signals::*;
+
+    #[test]
+    fn profiles_are_normalized() {
+        for profile in [Profile::Epa, Profile::Eva, Profile::Etu].iter() {
+            let model = ChannelModel::new(profile.clone(), Fading::Static, 30.72e6, 0);
+            let power: f64 = model.tap_gains().iter().map(|(_, g)| g.norm_sqr()).sum();
+            assert!((power - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_channel() {
+        let samples = (0..256)
+            .map(|i: i32| (i % 7, -(i % 3)))
+            .collect::<Vec<_>>()
+            .to_signal();
+        let fading = Fading::Rayleigh { doppler_hz: 50.0 };
+
+        let a = ChannelModel::new(Profile::Etu, fading, 1e6, 7).apply(&samples);
+        let b = ChannelModel::new(Profile::Etu, fading, 1e6, 7).apply(&samples);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn streaming_matches_one_shot() {
+        let samples = (0..300)
+            .map(|i: i32| (i % 5, i % 2))
+            .collect::<Vec<_>>()
+            .to_signal();
+        let fading = Fading::Rician {
+            k_factor_db: 6.0,
+            doppler_hz: 100.0,
+        };
+
+        let whole = ChannelModel::new(Profile::Etu, fading, 2e6, 3).apply(&samples);
+
+        let mut model = ChannelModel::new(Profile::Etu, fading, 2e6, 3);
+        let mut pieces = model.apply(&samples[..117]);
+        pieces.extend(model.apply(&samples[117..]));
+
+        for (l, r) in whole.iter().zip(pieces.iter()) {
+            assert!((l - r).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn rayleigh_has_unit_power() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let osc = JakesOscillator::new(100.0, &mut rng);
+
+        let power = (0..100_000)
+            .map(|i| osc.sample(i as f64 * 1e-4).norm_sqr())
+            .sum::<f64>()
+            / 100_000.0;
+
+        assert!((power - 1.0).abs() < 0.2, "power was {}", power);
+    }
+}
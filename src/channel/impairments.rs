@@ -0,0 +1,368 @@
+//! Radio frontend impairments that the B210 adds on top of the propagation channel.
+//!
+//! Each impairment is its own block implementing `Impairment`, and blocks are strung together
+//! with `Impairments` in the order the samples would hit them. For a realistic link that's
+//! usually: PA compression and IQ imbalance on the transmitter, the multipath channel, then
+//! noise, phase noise, clock offset, and dc offset on the receiver.
+
+use std::f64::consts::PI;
+
+use num::complex::Complex64;
+use rand::{rngs::StdRng, SeedableRng};
+
+use super::{add_awgn, complex_gaussian, ChannelModel, NoiseLevel};
+use crate::signals::{FarrowInterpolator, FirFilter};
+
+/// A stage in the simulated signal chain
+pub trait Impairment {
+    /// Impair the samples in place. Stages may change the number of samples.
+    fn apply(&mut self, samples: &mut Vec<Complex64>);
+}
+
+/// An ordered list of impairments that are applied one after another
+#[derive(Default)]
+pub struct Impairments {
+    stages: Vec<Box<dyn Impairment>>,
+}
+
+impl Impairments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a stage to the end of the chain
+    pub fn then(mut self, stage: impl Impairment + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn apply(&mut self, mut samples: Vec<Complex64>) -> Vec<Complex64> {
+        for stage in self.stages.iter_mut() {
+            stage.apply(&mut samples);
+        }
+        samples
+    }
+}
+
+impl Impairment for ChannelModel {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        *samples = ChannelModel::apply(self, samples);
+    }
+}
+
+/// Any fixed filter, like a transmit shaping filter or a static multipath channel
+impl Impairment for FirFilter {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        *samples = self.process(samples);
+    }
+}
+
+/// Gaussian noise as a stage in the chain
+pub struct Awgn {
+    level: NoiseLevel,
+    rng: StdRng,
+}
+
+impl Awgn {
+    pub fn new(level: NoiseLevel, seed: u64) -> Self {
+        Self {
+            level,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Impairment for Awgn {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        add_awgn(samples, self.level, &mut self.rng);
+    }
+}
+
+/// Gain and phase mismatch between the I and Q branches of the mixer
+///
+/// Modeled as y = mu * x + nu * conj(x), which puts an image of every subcarrier onto its mirror.
+pub struct IqImbalance {
+    mu: Complex64,
+    nu: Complex64,
+}
+
+impl IqImbalance {
+    pub fn new(gain_db: f64, phase_deg: f64) -> Self {
+        let gain = 10_f64.powf(gain_db / 20.0);
+        let phase = phase_deg.to_radians();
+
+        Self {
+            mu: (Complex64::new(1.0, 0.0) + Complex64::from_polar(gain, -phase)) / 2.0,
+            nu: (Complex64::new(1.0, 0.0) - Complex64::from_polar(gain, phase)) / 2.0,
+        }
+    }
+
+    /// Image rejection ratio in dB
+    pub fn image_rejection_db(&self) -> f64 {
+        10.0 * (self.mu.norm_sqr() / self.nu.norm_sqr()).log10()
+    }
+}
+
+impl Impairment for IqImbalance {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        for y in samples.iter_mut() {
+            *y = self.mu * *y + self.nu * y.conj();
+        }
+    }
+}
+
+/// LO leakage showing up as a constant offset
+pub struct DcOffset {
+    offset: Complex64,
+}
+
+impl DcOffset {
+    pub fn new(offset: Complex64) -> Self {
+        Self { offset }
+    }
+}
+
+impl Impairment for DcOffset {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        for y in samples.iter_mut() {
+            *y += self.offset;
+        }
+    }
+}
+
+/// Oscillator phase noise as a Wiener process
+///
+/// The phase takes a gaussian step every sample with a variance of 2*pi*linewidth/fs, where the
+/// linewidth is the 3dB bandwidth of the oscillator's Lorentzian spectrum.
+pub struct PhaseNoise {
+    step_std: f64,
+    phase: f64,
+    rng: StdRng,
+}
+
+impl PhaseNoise {
+    pub fn new(linewidth_hz: f64, sample_rate: f64, seed: u64) -> Self {
+        Self {
+            step_std: (2.0 * PI * linewidth_hz / sample_rate).sqrt(),
+            phase: 0.0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Impairment for PhaseNoise {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        for y in samples.iter_mut() {
+            // A unit complex gaussian has a variance of 1/2 per quadrature
+            self.phase += complex_gaussian(&mut self.rng).re * 2_f64.sqrt() * self.step_std;
+            *y *= Complex64::from_polar(1.0, self.phase);
+        }
+    }
+}
+
+/// Receiver ADC clock running off from the transmitter's DAC clock
+///
+/// The stream is resampled at intervals of (1 + ppm * 1e-6) input samples using cubic
+/// interpolation, so a positive offset means the receiver samples slower and the signal
+/// appears to shrink. State is kept between calls so a stream can be fed in pieces.
+pub struct SamplingClockOffset {
+    interpolator: FarrowInterpolator,
+}
+
+impl SamplingClockOffset {
+    pub fn new(ppm: f64) -> Self {
+        Self {
+            interpolator: FarrowInterpolator::new(1.0 + ppm * 1e-6),
+        }
+    }
+}
+
+impl Impairment for SamplingClockOffset {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        *samples = self.interpolator.process(samples);
+    }
+}
+
+/// Rapp's solid state power amplifier model
+///
+/// Smoothly compresses the amplitude towards `saturation` without touching the phase. Larger
+/// `smoothness` values make the knee sharper; ~2-3 is typical for a small SSPA.
+pub struct RappAmplifier {
+    gain: f64,
+    saturation: f64,
+    smoothness: f64,
+}
+
+impl RappAmplifier {
+    pub fn new(gain: f64, saturation: f64, smoothness: f64) -> Self {
+        Self {
+            gain,
+            saturation,
+            smoothness,
+        }
+    }
+}
+
+impl Impairment for RappAmplifier {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        let p2 = 2.0 * self.smoothness;
+        for y in samples.iter_mut() {
+            let (r, theta) = y.to_polar();
+            let r = self.gain * r;
+            let out = r / (1.0 + (r / self.saturation).powf(p2)).powf(1.0 / p2);
+            *y = Complex64::from_polar(out, theta);
+        }
+    }
+}
+
+/// Saleh's travelling wave tube amplifier model, with both AM/AM and AM/PM distortion
+pub struct SalehAmplifier {
+    alpha_a: f64,
+    beta_a: f64,
+    alpha_p: f64,
+    beta_p: f64,
+}
+
+impl SalehAmplifier {
+    pub fn new(alpha_a: f64, beta_a: f64, alpha_p: f64, beta_p: f64) -> Self {
+        Self {
+            alpha_a,
+            beta_a,
+            alpha_p,
+            beta_p,
+        }
+    }
+}
+
+impl Default for SalehAmplifier {
+    /// The parameters fitted in Saleh's original paper
+    fn default() -> Self {
+        Self::new(2.1587, 1.1517, 4.0033, 9.1040)
+    }
+}
+
+impl Impairment for SalehAmplifier {
+    fn apply(&mut self, samples: &mut Vec<Complex64>) {
+        for y in samples.iter_mut() {
+            let (r, theta) = y.to_polar();
+            let r2 = r * r;
+            let amplitude = self.alpha_a * r / (1.0 + self.beta_a * r2);
+            let phase = self.alpha_p * r2 / (1.0 + self.beta_p * r2);
+            *y = Complex64::from_polar(amplitude, theta + phase);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::{Fading, Profile};
+    use crate::signals::*;
+
+    fn tone(len: usize, freq: f64) -> Vec<Complex64> {
+        (0..len)
+            .map(|i| Complex64::from_polar(1.0, 2.0 * PI * freq * i as f64))
+            .collect()
+    }
+
+    #[test]
+    fn iq_imbalance_makes_an_image() {
+        let mut iq = IqImbalance::new(1.0, 5.0);
+        let mut samples = tone(64, 8.0 / 64.0);
+        iq.apply(&mut samples);
+
+        samples.fft();
+        let image = samples[64 - 8].norm_sqr();
+        let wanted = samples[8].norm_sqr();
+
+        let measured = 10.0 * (wanted / image).log10();
+        assert!((measured - iq.image_rejection_db()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn no_imbalance_is_transparent() {
+        let mut samples = tone(32, 0.1);
+        let original = samples.clone();
+        IqImbalance::new(0.0, 0.0).apply(&mut samples);
+
+        for (l, r) in samples.iter().zip(original.iter()) {
+            assert!((l - r).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn clock_offset_streams() {
+        let samples = tone(1000, 0.01);
+
+        let mut whole = samples.clone();
+        SamplingClockOffset::new(100.0).apply(&mut whole);
+
+        let mut sco = SamplingClockOffset::new(100.0);
+        let mut first = samples[..333].to_vec();
+        let mut second = samples[333..].to_vec();
+        sco.apply(&mut first);
+        sco.apply(&mut second);
+        first.extend(second);
+
+        assert_eq!(whole.len(), first.len());
+        for (l, r) in whole.iter().zip(first.iter()) {
+            assert!((l - r).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn clock_offset_drifts() {
+        // 1000 ppm over 10k samples is 10 samples of drift
+        let mut samples = tone(10_000, 0.001);
+        SamplingClockOffset::new(1000.0).apply(&mut samples);
+        assert!((samples.len() as i64 - 9990).abs() <= 2);
+    }
+
+    #[test]
+    fn rapp_saturates() {
+        let mut samples = vec![Complex64::new(100.0, 0.0), Complex64::new(0.01, 0.0)];
+        RappAmplifier::new(1.0, 1.0, 3.0).apply(&mut samples);
+
+        assert!(samples[0].norm() <= 1.0);
+        assert!((samples[1].norm() - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn chain_runs_in_order() {
+        let mut chain = Impairments::new()
+            .then(SalehAmplifier::default())
+            .then(IqImbalance::new(0.5, 2.0))
+            .then(ChannelModel::new(
+                Profile::Epa,
+                Fading::Rayleigh { doppler_hz: 10.0 },
+                1e6,
+                0,
+            ))
+            .then(Awgn::new(NoiseLevel::Snr(20.0), 0))
+            .then(PhaseNoise::new(100.0, 1e6, 0))
+            .then(DcOffset::new(Complex64::new(0.01, -0.01)));
+
+        let out = chain.apply(tone(512, 0.05).normalize_by(0.5).to_vec());
+        assert_eq!(out.len(), 512);
+
+        // An offset ahead of the amplifier gets compressed with everything else, one after it
+        // comes out as it went in
+        let offset = Complex64::new(2.0, 0.0);
+        let amplifier = || RappAmplifier::new(1.0, 1.0, 3.0);
+        let before = Impairments::new()
+            .then(DcOffset::new(offset))
+            .then(amplifier())
+            .apply(vec![Complex64::default(); 4]);
+        let after = Impairments::new()
+            .then(amplifier())
+            .then(DcOffset::new(offset))
+            .apply(vec![Complex64::default(); 4]);
+
+        assert!(before.iter().all(|s| s.norm() <= 1.0), "{:?}", before);
+        assert!(
+            after.iter().all(|s| (s - offset).norm() < 1e-12),
+            "{:?}",
+            after
+        );
+    }
+}
@@ -2,7 +2,19 @@ use std::f64::consts::PI;
 
 use crate::signals::*;
 use num::complex::Complex64;
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+mod awgn;
+pub use awgn::*;
+
+mod fading;
+pub use fading::*;
+
+mod impairments;
+pub use impairments::*;
+
+mod mimo;
+pub use mimo::*;
 
 // Original channel
 const _TMP: [f64; 10] = [0.0, -0.1, 1.0, -0.1, 0.05, -0.01, 0.0, 0.0, 0.0, 0.0];
@@ -34,10 +46,17 @@ pub fn channel(
     transmission: Vec<num::complex::Complex64>,
     snr: Option<f64>,
     timing_error: Option<bool>,
+    noise: Option<NoiseLevel>,
+    seed: Option<u64>,
 ) -> Vec<num::complex::Complex64> {
-    let mut rng = rand::thread_rng();
+    // Use a fixed seed when we want the same channel every run
+    let mut rng = match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
 
-    let snr = 10_f64.powf((snr.unwrap_or_else(|| 30.0) / 10.0) as f64);
+    // An explicit noise level takes precedence over the plain snr
+    let noise = noise.unwrap_or_else(|| NoiseLevel::Snr(snr.unwrap_or_else(|| 30.0)));
 
     let h = CHANNEL.clone().to_signal();
 
@@ -63,12 +82,7 @@ pub fn channel(
     }
 
     // Noise
-    let noise_var = output.variance() / snr;
-    for y in output.iter_mut() {
-        let noise = (0.5 * noise_var).sqrt()
-            * Complex64::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
-        *y += noise;
-    }
+    add_awgn(&mut output, noise, &mut rng);
 
     output
 }
@@ -76,7 +90,7 @@ pub fn channel(
 #[test]
 fn channel_works() {
     let samples = [1, 2, 3, 4, 5, 6, 7, 8].to_vec().to_signal();
-    let out = channel(samples, None, None);
+    let out = channel(samples, None, None, None, None);
 
     dbg!(out);
 }
@@ -84,7 +98,7 @@ fn channel_works() {
 #[test]
 fn channel_works_timing() {
     let samples = [1, 2, 3, 4, 5, 6, 7, 8].to_vec().to_signal();
-    let out = channel(samples, None, Some(true));
+    let out = channel(samples, None, Some(true), None, None);
 
     dbg!(out);
 }
@@ -93,7 +107,7 @@ fn channel_works_timing() {
 fn channel_makes_sense() {
     let samples = (0..128).map(|_| (1, -1)).collect::<Vec<_>>().to_signal();
 
-    let _out = channel(samples, None, Some(true));
+    let _out = channel(samples, None, Some(true), None, None);
     // dbg!(out.reals());
 
     //    0.0000 + 0.0000i
@@ -177,6 +191,15 @@ fn channel_makes_sense() {
     //    0.0000 + 0.0000i
 }
 
+#[test]
+fn channel_is_reproducible() {
+    let samples = (0..128).map(|_| (1, -1)).collect::<Vec<_>>().to_signal();
+
+    let a = channel(samples.clone(), Some(10.0), Some(true), None, Some(42));
+    let b = channel(samples, Some(10.0), Some(true), None, Some(42));
+    assert_eq!(a, b);
+}
+
 #[test]
 fn basic_math() {
     let f_delta = 0.048486623519511635;
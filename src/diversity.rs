@@ -0,0 +1,264 @@
+//! Receive diversity: decoding one transmission captured on several antennas at once.
+//!
+//! The branches share a clock, so they're synchronized together. Their locking signal
+//! correlations are summed before looking for the peak, so a branch sitting in a fade can't pull
+//! the timing off, and their frequency offset estimates are averaged. Every branch then gets its
+//! own channel and noise estimate from the training blocks, and the branches are combined on
+//! every subcarrier before demodulation.
+
+use num::complex::Complex64;
+
+use crate::packets::Header;
+use crate::receiver::{self, decode_block, unprefix_block, Equalized, Synchronized};
+use crate::report::RxReport;
+use crate::signals::*;
+use crate::transmitter::{self, ModulationScheme};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combining {
+    /// Weight every branch by its channel over its noise. Gets the most out of every branch.
+    MaximumRatio,
+
+    /// Use whichever branch has the best SNR on each subcarrier
+    Selection,
+}
+
+/// Decode a transmission captured on several receive antennas at once
+///
+/// The report's timing, frequency and training SNR come from the first branch, while the EVM and
+/// MER measure the combined constellation.
+#[optargs::optfn]
+pub fn decode_diversity(
+    branches: Vec<Vec<Complex64>>,
+    combining: Option<Combining>,
+    guard_bands: Option<bool>,
+    modulation: Option<crate::ModulationScheme>,
+) -> anyhow::Result<(Vec<u8>, RxReport)> {
+    let combining = combining.unwrap_or(Combining::MaximumRatio);
+    let guard_bands = guard_bands.unwrap_or(false);
+    let modulation = modulation.unwrap_or(ModulationScheme::Bpsk);
+
+    let synced = synchronize_jointly(branches)?;
+    let equalized = combine(&synced, combining, guard_bands);
+
+    let decoded = receiver::demodulate(equalized.symbols.clone(), modulation);
+    let payload = receiver::deframe(decoded);
+
+    let header_len = bincode::serialized_size(&Header { packet_length: 0 }).unwrap() as usize;
+    let data_symbols = (header_len + payload.len()) * 8 / modulation.bits_per_symbol();
+    let report = RxReport::new(&synced[0], &equalized, guard_bands, modulation, |idx| {
+        idx < data_symbols
+    });
+
+    Ok((payload, report))
+}
+
+/// Find the transmission in every branch with one timing and frequency offset
+pub fn synchronize_jointly(branches: Vec<Vec<Complex64>>) -> anyhow::Result<Vec<Synchronized>> {
+    anyhow::ensure!(!branches.is_empty(), "need at least one branch");
+
+    // Captures on one clock should all be the same length, but don't count on it
+    let len = branches.iter().map(|b| b.len()).min().unwrap();
+
+    let mut correlation: Vec<f64> = Vec::new();
+    for branch in branches.iter() {
+        let (_, cross) = branch[..len]
+            .to_vec()
+            .xcorr_fft(transmitter::locking_signal::<80>());
+        correlation.resize(cross.len(), 0.0);
+        for (c, x) in correlation.iter_mut().zip(cross.iter()) {
+            *c += x.norm_sqr();
+        }
+    }
+
+    let idxmax = (0..correlation.len())
+        .max_by(|&l, &r| correlation[l].partial_cmp(&correlation[r]).unwrap())
+        .unwrap_or(0);
+    let offset = idxmax as i32 - (((correlation.len() - 1) / 2) as i32 + 1);
+    anyhow::ensure!(offset >= 0, "transmission starts before the capture");
+    let offset = offset as usize;
+
+    if len < offset + 800 {
+        return Err(anyhow::anyhow!("Input not long enough, bailing early"));
+    }
+
+    let mut chunks = branches
+        .into_iter()
+        .map(|mut branch| {
+            branch.truncate(len);
+            receiver::split_into_chunks(branch.split_off(offset))
+        })
+        .collect::<Vec<_>>();
+
+    let f_delta = chunks
+        .iter()
+        .map(|c| receiver::frequency_correction(&c[3], &c[4]))
+        .sum::<f64>()
+        / chunks.len() as f64;
+
+    Ok(chunks
+        .iter_mut()
+        .map(|chunks| {
+            receiver::correct_frequency(chunks, f_delta);
+            Synchronized {
+                chunks: std::mem::take(chunks),
+                offset,
+                f_delta,
+            }
+        })
+        .collect())
+}
+
+/// Estimate every branch's channel and combine them into one stream of data symbols
+///
+/// The returned channel estimate is the first branch's, to go with its training blocks.
+pub fn combine(branches: &[Synchronized], combining: Combining, guard_bands: bool) -> Equalized {
+    let estimates = branches
+        .iter()
+        .map(|b| {
+            let h_k = receiver::estimate_channel(&b.chunks[5..10]);
+            let noise = training_noise(&b.chunks[5..10], &h_k);
+            (h_k, noise)
+        })
+        .collect::<Vec<_>>();
+
+    let blocks = branches.iter().map(|b| b.chunks.len()).min().unwrap();
+
+    let mut out_stream = Vec::new();
+    for block in 10..blocks {
+        let received = branches
+            .iter()
+            .map(|b| unprefix_block(&b.chunks[block]))
+            .collect::<Vec<_>>();
+
+        let mut combined = [Complex64::default(); 64];
+        for (k, c) in combined.iter_mut().enumerate() {
+            let branches = received.iter().zip(estimates.iter());
+
+            *c = match combining {
+                Combining::MaximumRatio => {
+                    let (sum, gain) = branches.fold(
+                        (Complex64::default(), 0.0),
+                        |(sum, gain), (y, (h, noise))| {
+                            (
+                                sum + h[k].conj() * y[k] / noise,
+                                gain + h[k].norm_sqr() / noise,
+                            )
+                        },
+                    );
+                    match gain > 0.0 {
+                        true => sum / gain,
+                        false => Complex64::default(),
+                    }
+                }
+
+                Combining::Selection => branches
+                    .max_by(|(_, (l, l_noise)), (_, (r, r_noise))| {
+                        let l_snr = l[k].norm_sqr() / l_noise;
+                        let r_snr = r[k].norm_sqr() / r_noise;
+                        l_snr.partial_cmp(&r_snr).unwrap()
+                    })
+                    .map(|(y, (h, _))| y[k] / h[k])
+                    .unwrap_or_default(),
+            };
+        }
+
+        decode_block(combined, &estimates[0].0, guard_bands, &mut out_stream);
+    }
+
+    Equalized {
+        symbols: out_stream,
+        h_k: estimates[0].0,
+    }
+}
+
+/// Average noise power on a subcarrier, from how far each training block strays from the channel
+/// estimate. Kept off zero so a noiseless branch still gets a finite weight.
+fn training_noise(training_blocks: &[[Complex64; 80]], h_k: &[Complex64; 64]) -> f64 {
+    let training = transmitter::training_signals::<64>();
+
+    let mut noise = 0.0;
+    for block in training_blocks.iter() {
+        let received = unprefix_block(block);
+        for k in 0..64 {
+            noise += (received[k] - h_k[k] * training[k]).norm_sqr();
+        }
+    }
+
+    let noise = noise / (64 * (training_blocks.len() - 1)) as f64;
+    noise.max(f64::MIN_POSITIVE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{complex_gaussian, mimo_channel, NoiseLevel};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn branches(snr: f64, seed: u64) -> (Vec<u8>, Vec<Vec<Complex64>>) {
+        let data = crate::utils::create_transmission_text(400, false);
+        let samples = crate::encode(&data, Some(true), None, None, None);
+        let received = mimo_channel(&[samples], 20e6, 2, NoiseLevel::Snr(snr), 20, seed);
+        (data, received)
+    }
+
+    #[test]
+    fn combining_beats_either_branch() {
+        let (data, received) = branches(15.0, 4);
+
+        // Each branch fades deep somewhere, which drags down its MER
+        let mut best_single = f64::MIN;
+        for branch in received.iter() {
+            let (_, report) = crate::decode(branch.clone(), Some(true), None).unwrap();
+            best_single = best_single.max(report.mer_db);
+        }
+
+        let (payload, mrc) = decode_diversity(received.clone(), None, Some(true), None).unwrap();
+        assert_eq!(payload, data);
+
+        let (payload, selection) =
+            decode_diversity(received, Some(Combining::Selection), Some(true), None).unwrap();
+        assert_eq!(payload, data);
+
+        assert!(
+            mrc.mer_db > best_single + 3.0,
+            "MRC at {}dB vs {}dB for the best branch",
+            mrc.mer_db,
+            best_single
+        );
+        assert!(
+            mrc.mer_db >= selection.mer_db,
+            "MRC at {}dB vs {}dB for selection",
+            mrc.mer_db,
+            selection.mer_db
+        );
+    }
+
+    #[test]
+    fn dead_branch_is_ignored() {
+        let (data, mut received) = branches(25.0, 5);
+
+        // A disconnected antenna only ever hears noise
+        let mut rng = StdRng::seed_from_u64(1);
+        let dead = (0..received[0].len())
+            .map(|_| complex_gaussian(&mut rng) * 0.1)
+            .collect::<Vec<_>>();
+        received.push(dead);
+
+        let (payload, report) = decode_diversity(received, None, Some(true), None).unwrap();
+        assert_eq!(payload, data);
+        assert!(report.mer_db > 15.0);
+    }
+
+    #[test]
+    fn one_branch_matches_decode() {
+        let data = crate::utils::create_transmission_text(100, false);
+        let samples = crate::encode(&data, Some(true), None, None, None);
+        let received = crate::channel(samples, Some(25.0), None, None, Some(7));
+
+        let (single, _) = crate::decode(received.clone(), Some(true), None).unwrap();
+        let (combined, _) = decode_diversity(vec![received], None, Some(true), None).unwrap();
+        assert_eq!(single, combined);
+        assert_eq!(combined, data);
+    }
+}
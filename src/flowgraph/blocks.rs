@@ -0,0 +1,240 @@
+//! The stages of the OFDM link wrapped up as blocks
+
+use num::complex::Complex64;
+
+use super::Block;
+use crate::channel::Impairments;
+use crate::packets::transport::{Fragmenter, Reassembler};
+use crate::receiver::{self, Equalized, Synchronized};
+use crate::signals::FirFilter;
+use crate::ModulationScheme;
+
+/// Bytes in, a complete baseband transmission out
+pub struct Encoder {
+    guard_bands: bool,
+    modulation: ModulationScheme,
+    window: usize,
+}
+
+impl Encoder {
+    pub fn new(guard_bands: bool, modulation: ModulationScheme) -> Self {
+        Self {
+            guard_bands,
+            modulation,
+            window: 0,
+        }
+    }
+
+    /// Overlap the blocks with a raised cosine roll-off this many samples long
+    pub fn window(mut self, overlap: usize) -> Self {
+        self.window = overlap;
+        self
+    }
+}
+
+impl Block for Encoder {
+    type Input = Vec<u8>;
+    type Output = Vec<Complex64>;
+
+    fn process(&mut self, input: Vec<u8>) -> anyhow::Result<Vec<Vec<Complex64>>> {
+        Ok(vec![crate::encode(
+            &input,
+            Some(self.guard_bands),
+            Some(self.modulation),
+            Some(self.window),
+            None,
+        )])
+    }
+}
+
+/// The original lab channel: fixed impulse response, optional frequency offset, and noise
+pub struct Channel {
+    snr: f64,
+    timing_error: bool,
+    seed: Option<u64>,
+}
+
+impl Channel {
+    pub fn new(snr: f64) -> Self {
+        Self {
+            snr,
+            timing_error: false,
+            seed: None,
+        }
+    }
+
+    pub fn timing_error(mut self, timing_error: bool) -> Self {
+        self.timing_error = timing_error;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+impl Block for Channel {
+    type Input = Vec<Complex64>;
+    type Output = Vec<Complex64>;
+
+    fn process(&mut self, input: Vec<Complex64>) -> anyhow::Result<Vec<Vec<Complex64>>> {
+        // Step the seed so every transmission doesn't see the exact same noise
+        let seed = self.seed.map(|seed| {
+            self.seed = Some(seed.wrapping_add(1));
+            seed
+        });
+
+        Ok(vec![crate::channel(
+            input,
+            Some(self.snr),
+            Some(self.timing_error),
+            None,
+            seed,
+        )])
+    }
+}
+
+impl Block for Impairments {
+    type Input = Vec<Complex64>;
+    type Output = Vec<Complex64>;
+
+    fn process(&mut self, input: Vec<Complex64>) -> anyhow::Result<Vec<Vec<Complex64>>> {
+        Ok(vec![self.apply(input)])
+    }
+}
+
+impl Block for FirFilter {
+    type Input = Vec<Complex64>;
+    type Output = Vec<Complex64>;
+
+    fn process(&mut self, input: Vec<Complex64>) -> anyhow::Result<Vec<Vec<Complex64>>> {
+        Ok(vec![FirFilter::process(self, &input)])
+    }
+}
+
+/// Locks onto the start of a transmission and removes the frequency offset
+pub struct Synchronizer;
+
+impl Block for Synchronizer {
+    type Input = Vec<Complex64>;
+    type Output = Synchronized;
+
+    fn process(&mut self, input: Vec<Complex64>) -> anyhow::Result<Vec<Synchronized>> {
+        Ok(vec![receiver::synchronize(input)?])
+    }
+}
+
+/// Channel estimation and correction, producing the data constellation
+pub struct Equalizer {
+    guard_bands: bool,
+}
+
+impl Equalizer {
+    pub fn new(guard_bands: bool) -> Self {
+        Self { guard_bands }
+    }
+}
+
+impl Block for Equalizer {
+    type Input = Synchronized;
+    type Output = Vec<Complex64>;
+
+    fn process(&mut self, input: Synchronized) -> anyhow::Result<Vec<Vec<Complex64>>> {
+        let Equalized { symbols, .. } = receiver::equalize(&input.chunks, self.guard_bands);
+        Ok(vec![symbols])
+    }
+}
+
+/// Constellation points back into bytes
+pub struct Demodulator {
+    modulation: ModulationScheme,
+}
+
+impl Demodulator {
+    pub fn new(modulation: ModulationScheme) -> Self {
+        Self { modulation }
+    }
+}
+
+impl Block for Demodulator {
+    type Input = Vec<Complex64>;
+    type Output = Vec<u8>;
+
+    fn process(&mut self, input: Vec<Complex64>) -> anyhow::Result<Vec<Vec<u8>>> {
+        Ok(vec![receiver::demodulate(input, self.modulation)])
+    }
+}
+
+/// Strips the header off and trims the payload to the length it describes
+pub struct Deframer;
+
+impl Block for Deframer {
+    type Input = Vec<u8>;
+    type Output = Vec<u8>;
+
+    fn process(&mut self, input: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+        Ok(vec![receiver::deframe(input)])
+    }
+}
+
+/// Splits every message into MTU sized frames
+impl Block for Fragmenter {
+    type Input = Vec<u8>;
+    type Output = Vec<u8>;
+
+    fn process(&mut self, input: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+        Ok(self.fragment(&input))
+    }
+}
+
+/// Holds on to frames until a whole message is in
+impl Block for Reassembler {
+    type Input = Vec<u8>;
+    type Output = Vec<u8>;
+
+    fn process(&mut self, input: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+        Ok(self.push(&input).into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flowgraph::Flowgraph;
+
+    #[test]
+    fn loopback_through_flowgraph() {
+        let data = crate::utils::create_transmission_text(100, false);
+
+        let mut graph = Flowgraph::new(std::iter::once(data.clone()))
+            .then(Encoder::new(true, ModulationScheme::Bpsk))
+            .then(Channel::new(30.0).seed(0))
+            .then(Synchronizer)
+            .then(Equalizer::new(true))
+            .then(Demodulator::new(ModulationScheme::Bpsk))
+            .then(Deframer);
+
+        let received = graph.run();
+        assert_eq!(received.len(), 1);
+        assert_eq!(graph.report().len(), 6);
+        assert_eq!(received[0], data);
+    }
+
+    #[test]
+    fn fragments_through_flowgraph() {
+        let data = crate::utils::create_transmission_text(1000, false);
+
+        let mut graph = Flowgraph::new(std::iter::once(data.clone()))
+            .then(Fragmenter::new(200))
+            .then(Encoder::new(true, ModulationScheme::Bpsk))
+            .then(Channel::new(30.0).seed(0))
+            .then(Synchronizer)
+            .then(Equalizer::new(true))
+            .then(Demodulator::new(ModulationScheme::Bpsk))
+            .then(Deframer)
+            .then(Reassembler::new());
+
+        assert_eq!(graph.run(), vec![data]);
+    }
+}
@@ -0,0 +1,333 @@
+//! Composable stream processing, in the spirit of GNU Radio flowgraphs.
+//!
+//! Every stage of the link is a `Block` with a typed input and output. Blocks are connected
+//! into a `Flowgraph` that pulls items from a source, pushes them through each block in order,
+//! and keeps count of how much time each block spent working.
+//!
+//! ```ignore
+//! let mut graph = Flowgraph::new(std::iter::once(data))
+//!     .then(blocks::Encoder::new(true, ModulationScheme::Bpsk))
+//!     .then(blocks::Channel::new(30.0))
+//!     .tap(|samples: &Vec<Complex64>| plots::constellation(samples))
+//!     .then(blocks::Synchronizer)
+//!     .then(blocks::Equalizer::new(true))
+//!     .then(blocks::Demodulator::new(ModulationScheme::Bpsk))
+//!     .then(blocks::Deframer);
+//!
+//! let received = graph.run();
+//! graph.print_report();
+//! ```
+
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+pub mod blocks;
+
+/// A single stage of stream processing
+pub trait Block {
+    type Input;
+    type Output;
+
+    /// Consume one item, producing any number of outputs.
+    ///
+    /// Blocks that need more than one input to produce something (like a packet detector) hold
+    /// on to state and return nothing until they're ready.
+    fn process(&mut self, input: Self::Input) -> anyhow::Result<Vec<Self::Output>>;
+
+    /// Name used when reporting
+    fn name(&self) -> String {
+        let full = std::any::type_name::<Self>();
+        full.rsplit("::").next().unwrap_or(full).to_string()
+    }
+
+    /// Performance counters for this block and any blocks it contains
+    fn stats(&self) -> Vec<BlockStats> {
+        Vec::new()
+    }
+}
+
+/// Counters collected for every block in a flowgraph
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockStats {
+    pub name: String,
+    pub items_in: usize,
+    pub items_out: usize,
+    pub errors: usize,
+    pub busy: Duration,
+}
+
+impl BlockStats {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            items_in: 0,
+            items_out: 0,
+            errors: 0,
+            busy: Duration::default(),
+        }
+    }
+
+    /// Average time spent on each input
+    pub fn per_item(&self) -> Duration {
+        match self.items_in {
+            0 => Duration::default(),
+            n => self.busy / n as u32,
+        }
+    }
+}
+
+/// Two blocks run back to back
+pub struct Then<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> Block for Then<A, B>
+where
+    A: Block,
+    B: Block<Input = A::Output>,
+{
+    type Input = A::Input;
+    type Output = B::Output;
+
+    fn process(&mut self, input: Self::Input) -> anyhow::Result<Vec<Self::Output>> {
+        let mut out = Vec::new();
+        for item in self.first.process(input)? {
+            out.extend(self.second.process(item)?);
+        }
+        Ok(out)
+    }
+
+    fn stats(&self) -> Vec<BlockStats> {
+        let mut stats = self.first.stats();
+        stats.extend(self.second.stats());
+        stats
+    }
+}
+
+/// Wraps a block to count its items and time how long it takes
+pub struct Metered<B> {
+    block: B,
+    stats: BlockStats,
+}
+
+impl<B: Block> Metered<B> {
+    pub fn new(block: B) -> Self {
+        let stats = BlockStats::new(block.name());
+        Self { block, stats }
+    }
+}
+
+impl<B: Block> Block for Metered<B> {
+    type Input = B::Input;
+    type Output = B::Output;
+
+    fn process(&mut self, input: Self::Input) -> anyhow::Result<Vec<Self::Output>> {
+        self.stats.items_in += 1;
+
+        let start = Instant::now();
+        let res = self.block.process(input);
+        self.stats.busy += start.elapsed();
+
+        match &res {
+            Ok(out) => self.stats.items_out += out.len(),
+            Err(_) => self.stats.errors += 1,
+        }
+        res
+    }
+
+    fn name(&self) -> String {
+        self.block.name()
+    }
+
+    fn stats(&self) -> Vec<BlockStats> {
+        vec![self.stats.clone()]
+    }
+}
+
+/// Turn a closure into a block
+pub struct FnBlock<I, O, F> {
+    name: String,
+    f: F,
+    _marker: PhantomData<fn(I) -> O>,
+}
+
+impl<I, O, F: FnMut(I) -> anyhow::Result<O>> FnBlock<I, O, F> {
+    pub fn new(name: impl Into<String>, f: F) -> Self {
+        Self {
+            name: name.into(),
+            f,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<I, O, F: FnMut(I) -> anyhow::Result<O>> Block for FnBlock<I, O, F> {
+    type Input = I;
+    type Output = O;
+
+    fn process(&mut self, input: I) -> anyhow::Result<Vec<O>> {
+        Ok(vec![(self.f)(input)?])
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Look at the items flowing between two blocks without changing them
+pub struct Probe<T, F> {
+    f: F,
+    _marker: PhantomData<fn(T)>,
+}
+
+impl<T, F: FnMut(&T)> Block for Probe<T, F> {
+    type Input = T;
+    type Output = T;
+
+    fn process(&mut self, input: T) -> anyhow::Result<Vec<T>> {
+        (self.f)(&input);
+        Ok(vec![input])
+    }
+}
+
+/// Passes items through untouched; the start of every flowgraph
+pub struct Identity<T>(PhantomData<fn(T)>);
+
+impl<T> Block for Identity<T> {
+    type Input = T;
+    type Output = T;
+
+    fn process(&mut self, input: T) -> anyhow::Result<Vec<T>> {
+        Ok(vec![input])
+    }
+}
+
+/// A source of items connected to a chain of blocks
+pub struct Flowgraph<S: Iterator, B> {
+    source: S,
+    chain: B,
+}
+
+impl<S: Iterator> Flowgraph<S, Identity<S::Item>> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            chain: Identity(PhantomData),
+        }
+    }
+}
+
+impl<S, B> Flowgraph<S, B>
+where
+    S: Iterator,
+    B: Block<Input = S::Item>,
+{
+    /// Connect another block to the end of the graph
+    pub fn then<C>(self, block: C) -> Flowgraph<S, Then<B, Metered<C>>>
+    where
+        C: Block<Input = B::Output>,
+    {
+        Flowgraph {
+            source: self.source,
+            chain: Then {
+                first: self.chain,
+                second: Metered::new(block),
+            },
+        }
+    }
+
+    /// Call a closure on every item at this point in the graph
+    pub fn tap<F>(self, f: F) -> Flowgraph<S, Then<B, Probe<B::Output, F>>>
+    where
+        F: FnMut(&B::Output),
+    {
+        Flowgraph {
+            source: self.source,
+            chain: Then {
+                first: self.chain,
+                second: Probe {
+                    f,
+                    _marker: PhantomData,
+                },
+            },
+        }
+    }
+
+    /// Pull a single item from the source and run it through the graph.
+    ///
+    /// Returns None once the source is exhausted. Items that fail in some block are logged and
+    /// dropped, the same way the receivers skip captures that fail to decode.
+    pub fn step(&mut self) -> Option<Vec<B::Output>> {
+        let item = self.source.next()?;
+        match self.chain.process(item) {
+            Ok(out) => Some(out),
+            Err(e) => {
+                log::debug!("flowgraph dropped an item: {}", e);
+                Some(Vec::new())
+            }
+        }
+    }
+
+    /// Run the source dry and collect everything that comes out the end
+    pub fn run(&mut self) -> Vec<B::Output> {
+        let mut out = Vec::new();
+        while let Some(items) = self.step() {
+            out.extend(items);
+        }
+        out
+    }
+
+    /// Counters for every block, in the order they were connected
+    pub fn report(&self) -> Vec<BlockStats> {
+        self.chain.stats()
+    }
+
+    pub fn print_report(&self) {
+        for stats in self.report() {
+            log::info!(
+                "{:>16} | in: {:>6} out: {:>6} err: {:>4} | {:?} per item",
+                stats.name,
+                stats.items_in,
+                stats.items_out,
+                stats.errors,
+                stats.per_item()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_chain_in_order() {
+        let mut graph = Flowgraph::new(1..=3)
+            .then(FnBlock::new("double", |x: i32| Ok(x * 2)))
+            .then(FnBlock::new("show", |x: i32| Ok(format!("{}", x + 1))));
+
+        assert_eq!(graph.run(), vec!["3", "5", "7"]);
+
+        let report = graph.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].name, "double");
+        assert_eq!(report[1].items_out, 3);
+    }
+
+    #[test]
+    fn errors_are_counted_and_dropped() {
+        let mut seen = Vec::new();
+        let mut graph = Flowgraph::new(0..4)
+            .then(FnBlock::new("odd only", |x: i32| match x % 2 {
+                1 => Ok(x),
+                _ => Err(anyhow::anyhow!("even")),
+            }))
+            .tap(|x: &i32| seen.push(*x));
+
+        assert_eq!(graph.run(), vec![1, 3]);
+        assert_eq!(graph.report()[0].errors, 2);
+        drop(graph);
+        assert_eq!(seen, vec![1, 3]);
+    }
+}
@@ -0,0 +1,682 @@
+//! Hybrid ARQ: keeping the soft bits of a packet that failed and combining them with its
+//! retransmissions.
+//!
+//! Every packet is a sequence number, the payload and a CRC-32, protected by a rate 1/3
+//! convolutional code (the constraint length 7 code from LTE). Each transmission carries some of
+//! the coded bits, picked by its redundancy version:
+//!
+//! - Chase combining sends the same two outputs of the code every time, and the receiver adds up
+//!   the log likelihood ratios of every copy, like one transmission at the sum of their SNRs.
+//! - Incremental redundancy sends two outputs first, for rate 1/2, and only the third in the
+//!   retransmission. That takes the receiver down to rate 1/3 for half the airtime of a repeat.
+//!   After that the redundancy versions cycle and combine like Chase.
+//! - Without combining, a packet's soft bits are thrown away when its CRC fails, which is plain
+//!   stop-and-wait ARQ on top of the code.
+//!
+//! The receiver needs the sequence number, redundancy version and length to know where the soft
+//! bits go before it can decode them, so every transmission starts with them under a CRC of their
+//! own, coded with all three outputs of the code. A transmission whose control header fails its
+//! CRC is lost rather than combined. Acknowledgements go back over the link like ARQ's, and a
+//! lost one is a timeout.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+use crate::arq::{ArqConfig, ArqStats, Frame, SimulatedLink};
+use crate::packets::transport::crc32;
+use crate::utils::{bools_to_u8, GetBitAt};
+
+/// Generators of the mother code, newest bit on the left
+const GENERATORS: [u8; 3] = [0o133, 0o171, 0o165];
+
+/// Bits of memory in the encoder
+const MEMORY: usize = 6;
+const STATES: usize = 1 << MEMORY;
+
+/// Sequence number and CRC around every payload
+const OVERHEAD: usize = 2 + 4;
+
+/// Sequence number, redundancy version, payload length and CRC in the control header
+const CONTROL_LEN: usize = 2 + 1 + 2 + 4;
+
+/// Coded bits the control header takes up at the front of every transmission
+const CONTROL_BITS: usize = (CONTROL_LEN * 8 + MEMORY) * 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HarqMode {
+    /// Throw the soft bits away whenever a packet fails
+    NoCombining,
+
+    /// Resend the same coded bits and add up the soft bits
+    Chase,
+
+    /// Send the coded bits that haven't been sent yet
+    IncrementalRedundancy,
+}
+
+/// Which outputs of the mother code a redundancy version carries
+fn streams(redundancy_version: usize) -> &'static [usize] {
+    match redundancy_version % 2 {
+        0 => &[0, 1],
+        _ => &[2],
+    }
+}
+
+/// What the receiver needs to know about a transmission to make use of it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Control {
+    pub sequence: u16,
+    pub redundancy_version: usize,
+
+    /// Payload bytes in the packet
+    pub payload_len: usize,
+}
+
+impl Control {
+    /// The coded control header that starts a transmission
+    fn to_bits(&self) -> Vec<bool> {
+        let mut header = self.sequence.to_le_bytes().to_vec();
+        header.push(self.redundancy_version as u8);
+        header.extend_from_slice(&(self.payload_len as u16).to_le_bytes());
+        let checksum = crc32(&header);
+        header.extend_from_slice(&checksum.to_le_bytes());
+
+        convolve(&to_bits(&header))
+            .iter()
+            .flat_map(|out| out.iter().copied())
+            .collect()
+    }
+
+    /// Decode the control header from the front of a transmission, if it passes its CRC
+    fn from_llrs(llrs: &[f64]) -> Option<Self> {
+        let soft = llrs
+            .get(..CONTROL_BITS)?
+            .chunks(3)
+            .map(|out| [out[0], out[1], out[2]])
+            .collect::<Vec<_>>();
+
+        let header = from_bits(&viterbi(&soft));
+        let (body, checksum) = header.split_at(CONTROL_LEN - 4);
+        if crc32(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return None;
+        }
+
+        Some(Self {
+            sequence: u16::from_le_bytes([body[0], body[1]]),
+            redundancy_version: body[2] as usize,
+            payload_len: u16::from_le_bytes([body[3], body[4]]) as usize,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transmission {
+    /// What the control header at the front of `bytes` says
+    pub control: Control,
+
+    /// The control header and coded bits to send with `encode`
+    pub bytes: Vec<u8>,
+}
+
+/// A packet that hasn't been acknowledged yet
+struct Pending {
+    sequence: u16,
+    payload_len: usize,
+    coded: Vec<[bool; 3]>,
+    attempts: usize,
+}
+
+/// The sending end: codes each packet and keeps sending more of it until it gets through
+pub struct HarqSender {
+    mode: HarqMode,
+    max_transmissions: usize,
+    next_sequence: u16,
+    queue: VecDeque<Vec<u8>>,
+    current: Option<Pending>,
+
+    pub transmissions: usize,
+    pub retransmissions: usize,
+
+    /// Packets given up on after `max_transmissions`
+    pub dropped: usize,
+}
+
+impl HarqSender {
+    pub fn new(mode: HarqMode, max_transmissions: usize) -> Self {
+        assert!(
+            max_transmissions > 0,
+            "packets have to be sent at least once"
+        );
+        Self {
+            mode,
+            max_transmissions,
+            next_sequence: 0,
+            queue: VecDeque::new(),
+            current: None,
+            transmissions: 0,
+            retransmissions: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Queue a payload to be sent
+    pub fn push(&mut self, payload: Vec<u8>) {
+        assert!(
+            payload.len() <= u16::MAX as usize,
+            "the control header only has room for a 16 bit length"
+        );
+        self.queue.push_back(payload);
+    }
+
+    /// Whether every packet has been acknowledged or given up on
+    pub fn is_done(&self) -> bool {
+        self.queue.is_empty() && self.current.is_none()
+    }
+
+    /// What to send next, or `None` once everything is done
+    pub fn next_transmission(&mut self) -> Option<Transmission> {
+        if self.current.is_none() {
+            let payload = self.queue.pop_front()?;
+            let mut packet = self.next_sequence.to_le_bytes().to_vec();
+            packet.extend_from_slice(&payload);
+            let checksum = crc32(&packet);
+            packet.extend_from_slice(&checksum.to_le_bytes());
+
+            self.current = Some(Pending {
+                sequence: self.next_sequence,
+                payload_len: payload.len(),
+                coded: convolve(&to_bits(&packet)),
+                attempts: 0,
+            });
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+        }
+
+        let current = self.current.as_ref().unwrap();
+        let redundancy_version = match self.mode {
+            HarqMode::IncrementalRedundancy => current.attempts,
+            _ => 0,
+        };
+
+        self.transmissions += 1;
+        if current.attempts > 0 {
+            self.retransmissions += 1;
+        }
+
+        let control = Control {
+            sequence: current.sequence,
+            redundancy_version,
+            payload_len: current.payload_len,
+        };
+        let mut bits = control.to_bits();
+        bits.extend(
+            current
+                .coded
+                .iter()
+                .flat_map(|out| streams(redundancy_version).iter().map(move |&s| out[s])),
+        );
+
+        Some(Transmission {
+            control,
+            bytes: from_bits(&bits),
+        })
+    }
+
+    /// Take in whatever came back. Anything but an ack for the current packet means sending
+    /// more of it.
+    pub fn on_feedback(&mut self, feedback: Option<Frame>) {
+        let current = match self.current.as_mut() {
+            Some(current) => current,
+            None => return,
+        };
+
+        match feedback {
+            Some(Frame::Ack(acked)) if acked.contains(&current.sequence) => self.current = None,
+            _ => {
+                current.attempts += 1;
+                if current.attempts >= self.max_transmissions {
+                    self.dropped += 1;
+                    self.current = None;
+                }
+            }
+        }
+    }
+}
+
+/// The receiving end: collects soft bits for a packet until it decodes
+pub struct HarqReceiver {
+    mode: HarqMode,
+
+    /// The packet being put together, and the soft bits for every step of its code so far
+    pending: Option<(u16, Vec<[f64; 3]>)>,
+    last_delivered: Option<u16>,
+    delivered: Vec<Vec<u8>>,
+
+    /// Transmissions thrown away because their control header didn't decode
+    pub lost: usize,
+}
+
+impl HarqReceiver {
+    pub fn new(mode: HarqMode) -> Self {
+        Self {
+            mode,
+            pending: None,
+            last_delivered: None,
+            delivered: Vec::new(),
+            lost: 0,
+        }
+    }
+
+    /// Take in one transmission's log likelihood ratios and return what to send back. `None`
+    /// means nothing usable was found, so there's nothing to answer.
+    pub fn receive(&mut self, llrs: Option<&[f64]>) -> Option<Frame> {
+        let llrs = llrs?;
+
+        // Without the control header there's no telling which packet the soft bits belong to
+        let control = match Control::from_llrs(llrs) {
+            Some(control) => control,
+            None => {
+                self.lost += 1;
+                return None;
+            }
+        };
+        let llrs = &llrs[CONTROL_BITS..];
+        let ack = Some(Frame::Ack(vec![control.sequence]));
+
+        // The ack got lost on the way back
+        if self.last_delivered == Some(control.sequence) {
+            return ack;
+        }
+
+        let steps = (control.payload_len + OVERHEAD) * 8 + MEMORY;
+        let fresh = match self.pending {
+            Some((sequence, _)) => {
+                sequence != control.sequence || self.mode == HarqMode::NoCombining
+            }
+            None => true,
+        };
+        if fresh {
+            self.pending = Some((control.sequence, vec![[0.0; 3]; steps]));
+        }
+
+        // Outputs that haven't been received yet stay at zero, as likely one way as the other
+        let soft = &mut self.pending.as_mut().unwrap().1;
+        let streams = streams(control.redundancy_version);
+        for (idx, llr) in llrs.iter().take(steps * streams.len()).enumerate() {
+            soft[idx / streams.len()][streams[idx % streams.len()]] += llr;
+        }
+
+        let packet = from_bits(&viterbi(soft));
+        let (body, checksum) = packet.split_at(packet.len() - 4);
+        let intact = crc32(body) == u32::from_le_bytes(checksum.try_into().unwrap())
+            && body[..2] == control.sequence.to_le_bytes();
+        if !intact {
+            return Some(Frame::Nack);
+        }
+
+        self.pending = None;
+        self.last_delivered = Some(control.sequence);
+        self.delivered.push(body[2..].to_vec());
+        ack
+    }
+
+    /// Payloads that have been decoded since the last call
+    pub fn take_delivered(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.delivered)
+    }
+}
+
+fn to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes.iter().flat_map(|byte| byte.to_bools()).collect()
+}
+
+/// Pack bits into bytes, padding the last one with zeros
+fn from_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            let mut bools = [false; 8];
+            bools[..chunk.len()].copy_from_slice(chunk);
+            bools_to_u8(bools)
+        })
+        .collect()
+}
+
+/// Every output of the mother code for each bit, plus a tail that brings the encoder back to zero
+fn convolve(bits: &[bool]) -> Vec<[bool; 3]> {
+    let mut state = 0;
+    bits.iter()
+        .copied()
+        .chain(std::iter::repeat(false).take(MEMORY))
+        .map(|bit| {
+            let register = (bit as u8) << MEMORY | state;
+            state = register >> 1;
+            outputs(register)
+        })
+        .collect()
+}
+
+fn outputs(register: u8) -> [bool; 3] {
+    let mut out = [false; 3];
+    for (o, generator) in out.iter_mut().zip(GENERATORS.iter()) {
+        *o = (register & generator).count_ones() % 2 == 1;
+    }
+    out
+}
+
+/// Soft decision Viterbi decoding of the mother code, with every output that wasn't received
+/// left at zero. Drops the tail.
+fn viterbi(llrs: &[[f64; 3]]) -> Vec<bool> {
+    let mut metrics = [f64::NEG_INFINITY; STATES];
+    metrics[0] = 0.0;
+
+    // The best state to have come from, for every state at every step
+    let mut history: Vec<[u8; STATES]> = Vec::with_capacity(llrs.len());
+
+    for step in llrs.iter() {
+        let mut next = [f64::NEG_INFINITY; STATES];
+        let mut previous = [0; STATES];
+
+        for (state, metric) in metrics.iter().enumerate() {
+            if *metric == f64::NEG_INFINITY {
+                continue;
+            }
+
+            for bit in 0..2u8 {
+                let register = bit << MEMORY | state as u8;
+                let branch = outputs(register)
+                    .iter()
+                    .zip(step.iter())
+                    .map(|(&out, llr)| if out { *llr } else { -llr })
+                    .sum::<f64>();
+
+                let to = (register >> 1) as usize;
+                if metric + branch > next[to] {
+                    next[to] = metric + branch;
+                    previous[to] = state as u8;
+                }
+            }
+        }
+
+        metrics = next;
+        history.push(previous);
+    }
+
+    // The tail always leaves the encoder in the zero state. The newest bit is the top of the
+    // state it led to.
+    let mut state = 0;
+    let mut bits = history
+        .iter()
+        .rev()
+        .map(|previous| {
+            let bit = state >> (MEMORY - 1) == 1;
+            state = previous[state] as usize;
+            bit
+        })
+        .collect::<Vec<_>>();
+
+    bits.reverse();
+    bits.truncate(llrs.len().saturating_sub(MEMORY));
+    bits
+}
+
+/// Transmissions `simulate` makes of a packet before giving up on it
+pub const MAX_TRANSMISSIONS: usize = 4;
+
+/// Send `data` across a simulated link with HARQ, one packet at a time, giving up once
+/// `max_samples` of link time have passed
+pub fn simulate(
+    data: &[u8],
+    config: ArqConfig<HarqMode>,
+    link: &mut SimulatedLink,
+    max_samples: usize,
+) -> ArqStats {
+    assert!(config.payload_len > 0, "packets need room for data");
+
+    let mut sender = HarqSender::new(config.mode, MAX_TRANSMISSIONS);
+    let mut receiver = HarqReceiver::new(config.mode);
+    for payload in data.chunks(config.payload_len) {
+        sender.push(payload.to_vec());
+    }
+
+    let mut delivered = Vec::new();
+    let mut clock = 0;
+    let mut timeouts = 0;
+    while clock < max_samples {
+        let transmission = match sender.next_transmission() {
+            Some(transmission) => transmission,
+            None => break,
+        };
+
+        let (airtime, llrs) = link.transmit_soft(&transmission.bytes);
+        clock += airtime;
+
+        // Feedback goes back over the same link, and can get lost on the way like with ARQ
+        let feedback = receiver.receive(llrs.as_deref()).and_then(|frame| {
+            let (airtime, decoded) = link.transmit(&frame.to_bytes());
+            Some((airtime, decoded.as_deref().and_then(Frame::from_bytes)?))
+        });
+
+        match feedback {
+            Some((airtime, frame)) => {
+                clock += 2 * config.turnaround + airtime;
+                sender.on_feedback(Some(frame));
+            }
+            None => {
+                clock += config.timeout;
+                timeouts += 1;
+                sender.on_feedback(None);
+            }
+        }
+
+        delivered.extend(receiver.take_delivered().into_iter().flatten());
+    }
+
+    ArqStats {
+        delivered,
+        elapsed: clock,
+        transmissions: sender.transmissions,
+        retransmissions: sender.retransmissions,
+        timeouts,
+        dropped: sender.dropped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::complex_gaussian;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// What the receiver would see for `bits` sent as BPSK at `snr` (dB per coded bit)
+    fn noisy_llrs(bits: &[bool], snr: f64, rng: &mut StdRng) -> Vec<f64> {
+        let variance = 10f64.powf(-snr / 10.0) / 2.0;
+        bits.iter()
+            .map(|&bit| {
+                let symbol = if bit { 1.0 } else { -1.0 };
+                let noise = (2.0 * variance).sqrt() * complex_gaussian(rng).re;
+                2.0 * (symbol + noise) / variance
+            })
+            .collect()
+    }
+
+    fn unpack(transmission: &Transmission) -> Vec<bool> {
+        to_bits(&transmission.bytes)
+    }
+
+    #[test]
+    fn code_round_trips() {
+        let bits = to_bits(b"convolutional");
+        let coded = convolve(&bits);
+        assert_eq!(coded.len(), bits.len() + MEMORY);
+
+        let llrs = coded
+            .iter()
+            .map(|out| {
+                let mut soft = [0.0; 3];
+                for (s, &o) in soft.iter_mut().zip(out.iter()) {
+                    *s = if o { 1.0 } else { -1.0 };
+                }
+                soft
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(viterbi(&llrs), bits);
+
+        // Any one of the outputs is enough without noise
+        for stream in 0..3 {
+            let only = llrs
+                .iter()
+                .map(|soft| {
+                    let mut only = [0.0; 3];
+                    only[stream] = soft[stream];
+                    only
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(viterbi(&only), bits, "stream {}", stream);
+        }
+    }
+
+    #[test]
+    fn clean_packets_are_acked_once() {
+        let mut sender = HarqSender::new(HarqMode::Chase, 4);
+        let mut receiver = HarqReceiver::new(HarqMode::Chase);
+        sender.push(b"first".to_vec());
+        sender.push(b"second".to_vec());
+
+        while let Some(transmission) = sender.next_transmission() {
+            let llrs = unpack(&transmission)
+                .iter()
+                .map(|&b| if b { 5.0 } else { -5.0 })
+                .collect::<Vec<_>>();
+            let feedback = receiver.receive(Some(&llrs));
+
+            // A lost ack means the same packet comes again, and is acked without a duplicate
+            if transmission.control.sequence == 0 && sender.transmissions == 1 {
+                sender.on_feedback(None);
+                continue;
+            }
+            sender.on_feedback(feedback);
+        }
+
+        assert_eq!(
+            receiver.take_delivered(),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+        assert_eq!(sender.retransmissions, 1);
+        assert_eq!(sender.dropped, 0);
+    }
+
+    #[test]
+    fn combining_recovers_what_no_transmission_could_alone() {
+        let payload = crate::utils::create_transmission_text(100, false);
+        let mut rng = StdRng::seed_from_u64(3);
+
+        for mode in [HarqMode::Chase, HarqMode::IncrementalRedundancy].iter() {
+            let mut sender = HarqSender::new(*mode, 4);
+            let mut receiver = HarqReceiver::new(*mode);
+            let mut lone = HarqReceiver::new(HarqMode::NoCombining);
+            sender.push(payload.clone());
+
+            // Far too noisy for the rate 1/2 code on its own
+            let mut feedback = Vec::new();
+            for _ in 0..2 {
+                let transmission = sender.next_transmission().unwrap();
+                let llrs = noisy_llrs(&unpack(&transmission), -1.5, &mut rng);
+
+                if transmission.control.redundancy_version == 0 {
+                    assert_eq!(lone.receive(Some(&llrs)), Some(Frame::Nack));
+                }
+
+                let answer = receiver.receive(Some(&llrs));
+                sender.on_feedback(answer.clone());
+                feedback.push(answer);
+            }
+
+            assert_eq!(
+                feedback,
+                vec![Some(Frame::Nack), Some(Frame::Ack(vec![0]))],
+                "{:?}",
+                mode
+            );
+            assert_eq!(receiver.take_delivered(), vec![payload.clone()]);
+            assert!(sender.is_done());
+        }
+    }
+
+    #[test]
+    fn incremental_redundancy_is_shorter() {
+        let mut sender = HarqSender::new(HarqMode::IncrementalRedundancy, 4);
+        sender.push(vec![0; 50]);
+
+        let first = sender.next_transmission().unwrap();
+        sender.on_feedback(Some(Frame::Nack));
+        let second = sender.next_transmission().unwrap();
+        assert_eq!(second.control.redundancy_version, 1);
+
+        let coded = |transmission: &Transmission| unpack(transmission).len() - CONTROL_BITS;
+        assert!(coded(&second) * 2 <= coded(&first) + 8);
+    }
+
+    #[test]
+    fn a_broken_control_header_loses_the_transmission() {
+        let mut sender = HarqSender::new(HarqMode::Chase, 4);
+        let mut receiver = HarqReceiver::new(HarqMode::Chase);
+        sender.push(b"control".to_vec());
+
+        // The payload is clean, but with its header flipped there is no telling what it is
+        let transmission = sender.next_transmission().unwrap();
+        let mut llrs = unpack(&transmission)
+            .iter()
+            .map(|&b| if b { 5.0 } else { -5.0 })
+            .collect::<Vec<_>>();
+        for llr in llrs[..CONTROL_BITS].iter_mut() {
+            *llr = -*llr;
+        }
+        assert_eq!(receiver.receive(Some(&llrs)), None);
+        assert_eq!(receiver.lost, 1);
+        assert!(receiver.take_delivered().is_empty());
+
+        assert_eq!(
+            Control::from_llrs(&llrs[..CONTROL_BITS - 1]),
+            None,
+            "a short header can't be read"
+        );
+    }
+
+    #[test]
+    fn packets_are_dropped_after_max_transmissions() {
+        let mut sender = HarqSender::new(HarqMode::NoCombining, 3);
+        sender.push(vec![1, 2, 3]);
+        for _ in 0..3 {
+            sender.next_transmission().unwrap();
+            sender.on_feedback(Some(Frame::Nack));
+        }
+        assert!(sender.next_transmission().is_none());
+        assert_eq!(sender.dropped, 1);
+        assert_eq!(sender.retransmissions, 2);
+    }
+
+    #[test]
+    fn combining_beats_plain_retransmission_over_the_air() {
+        let data = crate::utils::create_transmission_text(600, false);
+        let run = |mode| {
+            let mut link = SimulatedLink::new(3.0, 2);
+            simulate(&data, ArqConfig::new(mode), &mut link, usize::MAX)
+        };
+
+        let plain = run(HarqMode::NoCombining);
+        let chase = run(HarqMode::Chase);
+        let ir = run(HarqMode::IncrementalRedundancy);
+
+        // The link is too noisy for packets to get through reliably on their own
+        assert!(plain.dropped > 0, "nothing dropped without combining");
+        assert_eq!(chase.delivered, data);
+        assert_eq!(ir.delivered, data);
+
+        // Retransmissions that only carry the third output are half as long
+        assert!(
+            ir.goodput(1e6) > chase.goodput(1e6),
+            "goodput with incremental redundancy {} vs Chase {}",
+            ir.goodput(1e6),
+            chase.goodput(1e6)
+        );
+    }
+}
@@ -14,6 +14,9 @@ pub use original::*;
 mod receiver;
 pub use receiver::*;
 
+mod report;
+pub use report::*;
+
 mod signals;
 pub use signals::*;
 
@@ -28,6 +31,28 @@ pub mod plots;
 
 pub mod packets;
 
+pub mod flowgraph;
+
+pub mod pipeline;
+
+pub mod filters;
+
+pub mod spectrum;
+
+pub mod papr;
+
+pub mod ofdma;
+
+pub mod mimo;
+
+pub mod diversity;
+
+pub mod arq;
+
+pub mod harq;
+
+pub mod radio;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,6 +78,6 @@ mod tests {
     #[test]
     fn encoding_works() {
         let data = "alskdjas";
-        encode(data.as_bytes(), Some(true), None);
+        encode(data.as_bytes(), Some(true), None, None, None);
     }
 }
@@ -0,0 +1,475 @@
+//! Two transmit antennas, and as many receive antennas as the radio has.
+//!
+//! Only the first transmit antenna sends the locking block and preamble, so timing and frequency
+//! sync work exactly like they do for a single antenna. Training is orthogonal in time: the first
+//! antenna sends its five training blocks while the second is silent, then they swap. Every
+//! receive antenna can then estimate its channel from each transmit antenna on its own, giving a
+//! full channel matrix on every subcarrier.
+//!
+//! The data goes out in one of two ways:
+//! - Alamouti space-time coding sends every block twice over two block times, so a fade on one
+//!   antenna is covered by the other. Same data rate as a single antenna, and it works with a
+//!   single receive antenna.
+//! - Spatial multiplexing sends a different block from each antenna at once, doubling the data
+//!   rate. The receiver needs at least two antennas to pull the streams apart again.
+
+use num::complex::Complex64;
+
+use crate::packets::Header;
+use crate::receiver::{self, decode_block, unprefix_block, Equalized};
+use crate::report::RxReport;
+use crate::transmitter::{self, encode_block, modulate, prefix_block, ModulationScheme};
+
+pub const TX_ANTENNAS: usize = 2;
+
+/// Where the header and data start, after every antenna's training blocks
+pub const MIMO_DATA_START: usize = transmitter::TRAINING_START + TX_ANTENNAS * 5 * 80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MimoMode {
+    /// Alamouti space-time block code, for diversity
+    Alamouti,
+
+    /// An independent stream on every antenna, for twice the throughput
+    SpatialMultiplexing,
+}
+
+/// How spatially multiplexed streams are separated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detector {
+    /// Invert the channel outright. Simple, but boosts the noise on badly conditioned subcarriers.
+    ZeroForcing,
+
+    /// Trade a little leftover interference for much less noise, using the noise level measured
+    /// from the training blocks
+    Mmse,
+}
+
+/// Encode a transmission for two antennas, returning the samples for each antenna
+#[optargs::optfn]
+pub fn encode_mimo(
+    data: &[u8],
+    mode: MimoMode,
+    guard_bands: Option<bool>,
+    modulation: Option<crate::ModulationScheme>,
+) -> Vec<Vec<Complex64>> {
+    let guard_bands = guard_bands.unwrap_or(false);
+    let modulation = modulation.unwrap_or(ModulationScheme::Bpsk);
+
+    // The first antenna sends a normal frame start, then keeps quiet while the second trains
+    let mut first = transmitter::frame_start();
+    let training = first[transmitter::TRAINING_START..].to_vec();
+    first.resize(MIMO_DATA_START, Complex64::default());
+
+    let mut second = vec![Complex64::default(); transmitter::DATA_START];
+    second.extend(training);
+
+    let header = Header::new(data.len() as u128);
+    let header_bytes = bincode::serialize(&header).unwrap();
+
+    let mut complex_stream = modulate(&header_bytes, &modulation)
+        .into_iter()
+        .chain(modulate(data, &modulation).into_iter())
+        .peekable();
+
+    while complex_stream.peek().is_some() {
+        let mut x1 = encode_block(&mut complex_stream, guard_bands);
+        let mut x2 = encode_block(&mut complex_stream, guard_bands);
+
+        match mode {
+            MimoMode::Alamouti => {
+                // [x1, -x2*] on the first antenna and [x2, x1*] on the second
+                let mut x1_conj = conjugate(&x1, 1.0);
+                let mut x2_conj = conjugate(&x2, -1.0);
+
+                first.extend(prefix_block::<64, 16>(&mut x1).iter());
+                first.extend(prefix_block::<64, 16>(&mut x2_conj).iter());
+                second.extend(prefix_block::<64, 16>(&mut x2).iter());
+                second.extend(prefix_block::<64, 16>(&mut x1_conj).iter());
+            }
+
+            MimoMode::SpatialMultiplexing => {
+                first.extend(prefix_block::<64, 16>(&mut x1).iter());
+                second.extend(prefix_block::<64, 16>(&mut x2).iter());
+            }
+        }
+    }
+
+    let mut streams = vec![first, second];
+    normalize_together(&mut streams);
+    streams
+}
+
+/// Decode a transmission from `encode_mimo`, given the samples from every receive antenna.
+///
+/// The antennas have to be captured together on one clock, so they share the timing and
+/// frequency offset found on the first. The report describes the link from the first transmit
+/// antenna to the first receive antenna, along with the combined constellation.
+#[optargs::optfn]
+pub fn decode_mimo(
+    receptions: Vec<Vec<Complex64>>,
+    mode: MimoMode,
+    detector: Option<Detector>,
+    guard_bands: Option<bool>,
+    modulation: Option<crate::ModulationScheme>,
+) -> anyhow::Result<(Vec<u8>, RxReport)> {
+    let guard_bands = guard_bands.unwrap_or(false);
+    let modulation = modulation.unwrap_or(ModulationScheme::Bpsk);
+    let detector = detector.unwrap_or(Detector::Mmse);
+
+    anyhow::ensure!(!receptions.is_empty(), "need at least one receive antenna");
+    anyhow::ensure!(
+        mode == MimoMode::Alamouti || receptions.len() >= TX_ANTENNAS,
+        "spatial multiplexing needs at least {} receive antennas",
+        TX_ANTENNAS
+    );
+
+    let mut receptions = receptions.into_iter();
+    let synced = receiver::synchronize(receptions.next().unwrap())?;
+
+    let mut chunks = vec![synced.chunks.clone()];
+    for samples in receptions {
+        chunks.push(receiver::align(samples, &synced)?);
+    }
+
+    let data_block = MIMO_DATA_START / 80;
+    let blocks = chunks.iter().map(|c| c.len()).min().unwrap();
+    anyhow::ensure!(blocks > data_block, "transmission ended during training");
+
+    let channel = MimoEstimate::new(&chunks);
+
+    let received = |block: usize| {
+        chunks
+            .iter()
+            .map(|c| unprefix_block(&c[block]))
+            .collect::<Vec<_>>()
+    };
+
+    let mut out_stream = Vec::new();
+    match mode {
+        MimoMode::Alamouti => {
+            for block in (data_block..blocks - 1).step_by(2) {
+                // The pilots are space-time coded too, each antenna sends 1 on them and then
+                // -1 and 1
+                let mut first = received(block);
+                let mut second = received(block + 1);
+                channel.correct_phase(&mut first, [1.0, 1.0], guard_bands);
+                channel.correct_phase(&mut second, [-1.0, 1.0], guard_bands);
+
+                let [x1, x2] = channel.alamouti(&first, &second);
+                decode_block(x1, &channel.h[0][0], guard_bands, &mut out_stream);
+                decode_block(x2, &channel.h[0][0], guard_bands, &mut out_stream);
+            }
+        }
+
+        MimoMode::SpatialMultiplexing => {
+            let symbol_energy = symbol_energy(modulation);
+            for block in data_block..blocks {
+                let [x1, x2] = channel.separate(&received(block), detector, symbol_energy);
+                decode_block(x1, &channel.h[0][0], guard_bands, &mut out_stream);
+                decode_block(x2, &channel.h[0][0], guard_bands, &mut out_stream);
+            }
+        }
+    }
+
+    let decoded = receiver::demodulate(out_stream.clone(), modulation);
+    let payload = receiver::deframe(decoded);
+
+    let equalized = Equalized {
+        symbols: out_stream,
+        h_k: channel.h[0][0],
+    };
+
+    let header_len = bincode::serialized_size(&Header { packet_length: 0 }).unwrap() as usize;
+    let data_symbols = (header_len + payload.len()) * 8 / modulation.bits_per_symbol();
+    let report = RxReport::new(&synced, &equalized, guard_bands, modulation, |idx| {
+        idx < data_symbols
+    });
+
+    Ok((payload, report))
+}
+
+/// The channel from every transmit antenna to every receive antenna, on every subcarrier
+pub struct MimoEstimate {
+    /// Indexed by receive antenna, then transmit antenna
+    pub h: Vec<[[Complex64; 64]; TX_ANTENNAS]>,
+
+    /// Noise power on every received subcarrier, on the same scale as the constellation
+    pub noise_variance: f64,
+}
+
+impl MimoEstimate {
+    /// Estimate the channel from the training blocks of every receive antenna's aligned blocks
+    pub fn new(chunks: &[Vec<[Complex64; 80]>]) -> Self {
+        let training = transmitter::training_signals::<64>();
+
+        let mut noise = 0.0;
+        let mut noise_count = 0;
+
+        let h = chunks
+            .iter()
+            .map(|blocks| {
+                let mut h = [[Complex64::default(); 64]; TX_ANTENNAS];
+                for (tx, h) in h.iter_mut().enumerate() {
+                    let start = 5 + tx * 5;
+                    let training_blocks = &blocks[start..start + 5];
+                    *h = receiver::estimate_channel(training_blocks);
+
+                    // The five estimates see the same channel, so their spread is down to noise
+                    for block in training_blocks {
+                        let received = unprefix_block(block);
+                        for k in 0..64 {
+                            noise += (received[k] - h[k] * training[k]).norm_sqr();
+                        }
+                    }
+                    noise_count += 64 * (training_blocks.len() - 1);
+                }
+                h
+            })
+            .collect();
+
+        Self {
+            h,
+            noise_variance: noise / noise_count as f64,
+        }
+    }
+
+    /// Undo the common phase that has drifted in since training, using the pilot subcarriers.
+    /// `pilots` is what each transmit antenna sent on them.
+    ///
+    /// Alamouti combining conjugates the second block of every pair, so the two blocks' phase
+    /// errors add up instead of cancelling, and have to come off before combining.
+    fn correct_phase(
+        &self,
+        received: &mut [[Complex64; 64]],
+        pilots: [f64; TX_ANTENNAS],
+        guard_bands: bool,
+    ) {
+        let mut correlation = Complex64::default();
+        for (h, r) in self.h.iter().zip(received.iter()) {
+            for k in (0..64).filter(|&k| transmitter::is_pilot_subcarrier(k, guard_bands)) {
+                let expected = h[0][k] * pilots[0] + h[1][k] * pilots[1];
+                correlation += r[k] * expected.conj();
+            }
+        }
+
+        if correlation.norm() == 0.0 {
+            return;
+        }
+
+        let rotation = correlation.conj() / correlation.norm();
+        for s in received.iter_mut().flatten() {
+            *s *= rotation;
+        }
+    }
+
+    /// Combine the two block times of an Alamouti pair across every receive antenna
+    pub fn alamouti(
+        &self,
+        first: &[[Complex64; 64]],
+        second: &[[Complex64; 64]],
+    ) -> [[Complex64; 64]; TX_ANTENNAS] {
+        let mut out = [[Complex64::default(); 64]; TX_ANTENNAS];
+
+        for k in 0..64 {
+            let mut x1 = Complex64::default();
+            let mut x2 = Complex64::default();
+            let mut gain = 0.0;
+
+            for (h, (r1, r2)) in self.h.iter().zip(first.iter().zip(second.iter())) {
+                let (h0, h1) = (h[0][k], h[1][k]);
+                x1 += h0.conj() * r1[k] + h1 * r2[k].conj();
+                x2 += h1.conj() * r1[k] - h0 * r2[k].conj();
+                gain += h0.norm_sqr() + h1.norm_sqr();
+            }
+
+            if gain > 0.0 {
+                out[0][k] = x1 / gain;
+                out[1][k] = x2 / gain;
+            }
+        }
+
+        out
+    }
+
+    /// Separate the streams sent from each antenna during one block time
+    pub fn separate(
+        &self,
+        received: &[[Complex64; 64]],
+        detector: Detector,
+        symbol_energy: f64,
+    ) -> [[Complex64; 64]; TX_ANTENNAS] {
+        let regularization = match detector {
+            Detector::ZeroForcing => 0.0,
+            Detector::Mmse => self.noise_variance / symbol_energy,
+        };
+
+        let mut out = [[Complex64::default(); 64]; TX_ANTENNAS];
+
+        for k in 0..64 {
+            // x = (H^H H + regularization I)^-1 H^H y
+            let mut gram = [[Complex64::default(); TX_ANTENNAS]; TX_ANTENNAS];
+            let mut matched = [Complex64::default(); TX_ANTENNAS];
+
+            for (h, y) in self.h.iter().zip(received.iter()) {
+                for i in 0..TX_ANTENNAS {
+                    matched[i] += h[i][k].conj() * y[k];
+                    for j in 0..TX_ANTENNAS {
+                        gram[i][j] += h[i][k].conj() * h[j][k];
+                    }
+                }
+            }
+
+            gram[0][0] += regularization;
+            gram[1][1] += regularization;
+
+            let det = gram[0][0] * gram[1][1] - gram[0][1] * gram[1][0];
+            if det.norm() == 0.0 {
+                continue;
+            }
+
+            out[0][k] = (gram[1][1] * matched[0] - gram[0][1] * matched[1]) / det;
+            out[1][k] = (gram[0][0] * matched[1] - gram[1][0] * matched[0]) / det;
+        }
+
+        out
+    }
+}
+
+/// Conjugate every subcarrier of a block and scale it by `sign`
+fn conjugate(block: &[Complex64; 64], sign: f64) -> [Complex64; 64] {
+    let mut out = *block;
+    out.iter_mut().for_each(|s| *s = s.conj() * sign);
+    out
+}
+
+/// Average energy of a constellation point
+fn symbol_energy(modulation: ModulationScheme) -> f64 {
+    match modulation {
+        ModulationScheme::Bpsk => 1.0,
+        ModulationScheme::Qpsk => 2.0,
+        ModulationScheme::Qam => 10.0,
+    }
+}
+
+/// Like `transmitter::normalize`, but with one scale for every antenna so their relative power
+/// is kept
+fn normalize_together(streams: &mut [Vec<Complex64>]) {
+    let max = streams
+        .iter()
+        .flatten()
+        .map(|f| f64::max(f.re.abs(), f.im.abs()))
+        .fold(0.0, f64::max);
+
+    if max == 0.0 {
+        return;
+    }
+
+    for f in streams.iter_mut().flatten() {
+        *f /= max;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mimo_channel, Fading, MimoChannelModel, NoiseLevel, Profile};
+
+    fn loopback(
+        mode: MimoMode,
+        detector: Detector,
+        receivers: usize,
+        snr: f64,
+    ) -> (Vec<u8>, Vec<u8>, RxReport) {
+        let data = crate::utils::create_transmission_text(300, false);
+        let streams = encode_mimo(&data, mode, Some(true), Some(ModulationScheme::Qpsk));
+        let received = mimo_channel(&streams, 20e6, receivers, NoiseLevel::Snr(snr), 20, 4);
+
+        let (payload, report) = decode_mimo(
+            received,
+            mode,
+            Some(detector),
+            Some(true),
+            Some(ModulationScheme::Qpsk),
+        )
+        .unwrap();
+        (data, payload, report)
+    }
+
+    #[test]
+    fn training_is_orthogonal() {
+        let fading = Fading::Rayleigh { doppler_hz: 0.0 };
+        let mut model = MimoChannelModel::new(Profile::Flat, fading, 1e6, 2, 2, 6);
+
+        let streams = encode_mimo(b"orthogonal", MimoMode::Alamouti, None, None)
+            .into_iter()
+            .map(|s| {
+                let mut delayed = vec![Complex64::default(); 20];
+                delayed.extend(s);
+                delayed
+            })
+            .collect::<Vec<_>>();
+        let received = model.apply(&streams);
+
+        let synced = receiver::synchronize(received[0].clone()).unwrap();
+        let chunks = vec![
+            synced.chunks.clone(),
+            receiver::align(received[1].clone(), &synced).unwrap(),
+        ];
+        let estimate = MimoEstimate::new(&chunks);
+
+        // Every link goes through the same normalization and timing, so relative to the first
+        // link each estimate should match its link's gain on every subcarrier
+        let gain = |rx: usize, tx: usize| model.link(rx, tx).tap_gains()[0].1;
+        for rx in 0..2 {
+            for tx in 0..2 {
+                let expected = gain(rx, tx) / gain(0, 0);
+                for k in 0..64 {
+                    let relative = estimate.h[rx][tx][k] / estimate.h[0][0][k];
+                    assert!((relative - expected).norm() < 1e-6, "{} {} {}", rx, tx, k);
+                }
+            }
+        }
+        assert!(estimate.noise_variance < 1e-12);
+    }
+
+    #[test]
+    fn alamouti_with_one_or_two_receivers() {
+        let (data, single, single_report) = loopback(MimoMode::Alamouti, Detector::Mmse, 1, 30.0);
+        assert_eq!(data, single);
+
+        let (data, dual, dual_report) = loopback(MimoMode::Alamouti, Detector::Mmse, 2, 30.0);
+        assert_eq!(data, dual);
+
+        // The second receive antenna adds its own copy of the signal but independent noise
+        assert!(
+            dual_report.mer_db > single_report.mer_db + 2.0,
+            "one receiver:\n{}\ntwo receivers:\n{}",
+            single_report,
+            dual_report
+        );
+    }
+
+    #[test]
+    fn spatial_multiplexing_doubles_the_rate() {
+        let data = crate::utils::create_transmission_text(300, false);
+        let alamouti = encode_mimo(&data, MimoMode::Alamouti, Some(true), None);
+        let multiplexed = encode_mimo(&data, MimoMode::SpatialMultiplexing, Some(true), None);
+
+        let data_len = |streams: &Vec<Vec<Complex64>>| streams[0].len() - MIMO_DATA_START;
+        assert_eq!(data_len(&alamouti), 2 * data_len(&multiplexed));
+
+        for &detector in [Detector::ZeroForcing, Detector::Mmse].iter() {
+            let (data, payload, report) =
+                loopback(MimoMode::SpatialMultiplexing, detector, 2, 30.0);
+            assert_eq!(data, payload, "{:?}: {}", detector, report);
+        }
+    }
+
+    #[test]
+    fn spatial_multiplexing_needs_two_receivers() {
+        let streams = encode_mimo(b"too few", MimoMode::SpatialMultiplexing, None, None);
+        let received = mimo_channel(&streams, 20e6, 1, NoiseLevel::Snr(30.0), 20, 1);
+        assert!(decode_mimo(received, MimoMode::SpatialMultiplexing, None, None, None).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Sharing one OFDM frame between several streams.
+//!
+//! Every data subcarrier of every data block belongs to exactly one stream, as decided by an
+//! `Allocation`. The frame starts like any other, then carries an `OfdmaHeader` on every data
+//! subcarrier so the receiver can learn the allocation and each stream's length before it has to
+//! pull the streams apart. A low rate telemetry stream and a video stream can then share the link
+//! without either waiting for the other's packets.
+
+use num::complex::Complex64;
+use serde::{Deserialize, Serialize};
+
+use crate::packets::OfdmaHeader;
+use crate::receiver;
+use crate::report::RxReport;
+use crate::transmitter::{self, ModulationScheme};
+
+/// The most streams one frame can carry
+pub const MAX_STREAMS: usize = 4;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Scheme {
+    /// Subcarriers are dealt out to the streams one at a time, spreading every stream across the
+    /// whole band for frequency diversity
+    Interleaved,
+
+    /// Runs of neighbouring subcarriers are dealt out, like LTE resource blocks
+    ResourceBlocks,
+
+    /// Whole blocks are dealt out, so each stream gets every subcarrier for a while
+    TimeDivision,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Allocation {
+    pub scheme: Scheme,
+
+    /// Subcarriers per resource block, only used by `Scheme::ResourceBlocks`
+    pub block_size: u8,
+
+    /// How many slots each stream gets out of every round of dealing. Streams after the last
+    /// nonzero share are unused.
+    pub shares: [u8; MAX_STREAMS],
+}
+
+impl Allocation {
+    pub fn interleaved(shares: &[u8]) -> Self {
+        Self::new(Scheme::Interleaved, 1, shares)
+    }
+
+    pub fn resource_blocks(shares: &[u8], block_size: u8) -> Self {
+        assert!(
+            block_size > 0,
+            "resource blocks need at least one subcarrier"
+        );
+        Self::new(Scheme::ResourceBlocks, block_size, shares)
+    }
+
+    pub fn time_division(shares: &[u8]) -> Self {
+        Self::new(Scheme::TimeDivision, 1, shares)
+    }
+
+    fn new(scheme: Scheme, block_size: u8, shares: &[u8]) -> Self {
+        assert!(
+            shares.len() <= MAX_STREAMS,
+            "at most {} streams fit in a frame",
+            MAX_STREAMS
+        );
+
+        let mut padded = [0; MAX_STREAMS];
+        padded[..shares.len()].copy_from_slice(shares);
+
+        let allocation = Self {
+            scheme,
+            block_size,
+            shares: padded,
+        };
+        assert!(allocation.is_valid(), "some stream needs a share");
+        allocation
+    }
+
+    /// How many streams the allocation has room for
+    pub fn streams(&self) -> usize {
+        self.shares
+            .iter()
+            .rposition(|&share| share > 0)
+            .map_or(0, |last| last + 1)
+    }
+
+    /// Whether the allocation can actually deal out subcarriers. A garbled header can easily
+    /// describe one that can't.
+    pub fn is_valid(&self) -> bool {
+        self.streams() > 0 && self.block_size > 0
+    }
+
+    /// Which stream owns the `subcarrier`th data subcarrier of the `block`th data block, given
+    /// how many data subcarriers each block has
+    pub fn owner(&self, block: usize, subcarrier: usize, per_block: usize) -> usize {
+        let block_size = self.block_size as usize;
+        let slot = match self.scheme {
+            Scheme::Interleaved => block * per_block + subcarrier,
+            Scheme::ResourceBlocks => {
+                let per_block = (per_block + block_size - 1) / block_size;
+                block * per_block + subcarrier / block_size
+            }
+            Scheme::TimeDivision => block,
+        };
+
+        let round = self.shares.iter().map(|&s| s as usize).sum::<usize>();
+        let mut slot = slot % round;
+        for (stream, &share) in self.shares.iter().enumerate() {
+            match slot < share as usize {
+                true => return stream,
+                false => slot -= share as usize,
+            }
+        }
+
+        unreachable!("the slot always lands inside the round")
+    }
+}
+
+/// Encode several streams into one frame, each on the subcarriers the allocation gives it
+pub fn encode_streams(
+    streams: &[&[u8]],
+    allocation: Allocation,
+    guard_bands: bool,
+    modulation: ModulationScheme,
+) -> Vec<Complex64> {
+    assert!(
+        streams.len() <= MAX_STREAMS,
+        "at most {} streams fit in a frame",
+        MAX_STREAMS
+    );
+
+    let mut stream_lengths = [0; MAX_STREAMS];
+    for (idx, stream) in streams.iter().enumerate() {
+        assert!(
+            stream.is_empty() || allocation.shares.get(idx).map_or(false, |&s| s > 0),
+            "stream {} has data but no share of the subcarriers",
+            idx
+        );
+        stream_lengths[idx] = stream.len() as u32;
+    }
+
+    let header = OfdmaHeader {
+        allocation,
+        stream_lengths,
+    };
+    let header_bytes = bincode::serialize(&header).unwrap();
+
+    let mut out_stream = transmitter::frame_start();
+
+    // The receiver can't know the allocation before reading the header, so it goes out on every
+    // data subcarrier like a normal frame
+    let mut header_symbols = transmitter::modulate(&header_bytes, &modulation)
+        .into_iter()
+        .peekable();
+    while header_symbols.peek().is_some() {
+        let mut block = transmitter::encode_block(&mut header_symbols, guard_bands);
+        out_stream.extend(transmitter::prefix_block::<64, 16>(&mut block).iter());
+    }
+
+    let mut queues = streams
+        .iter()
+        .map(|stream| transmitter::modulate(stream, &modulation).into_iter())
+        .collect::<Vec<_>>();
+    let mut remaining = queues.iter().map(|q| q.len()).sum::<usize>();
+
+    let per_block = transmitter::data_subcarriers(guard_bands).len();
+    let mut block_idx = 0;
+    while remaining > 0 {
+        // Streams that have run out leave their subcarriers empty
+        let mut symbols = (0..per_block)
+            .map(|subcarrier| {
+                let owner = allocation.owner(block_idx, subcarrier, per_block);
+                let symbol = queues.get_mut(owner).and_then(|q| q.next());
+                if symbol.is_some() {
+                    remaining -= 1;
+                }
+                symbol.unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .into_iter();
+
+        let mut block = transmitter::encode_block(&mut symbols, guard_bands);
+        out_stream.extend(transmitter::prefix_block::<64, 16>(&mut block).iter());
+        block_idx += 1;
+    }
+
+    transmitter::normalize(&mut out_stream);
+    out_stream
+}
+
+/// Decode a frame from `encode_streams`, returning the payload of every stream the allocation
+/// has room for
+pub fn decode_streams(
+    samples: Vec<Complex64>,
+    guard_bands: bool,
+    modulation: ModulationScheme,
+) -> anyhow::Result<(Vec<Vec<u8>>, RxReport)> {
+    let synced = receiver::synchronize(samples)?;
+    let equalized = receiver::equalize(&synced.chunks, guard_bands);
+    let symbols = &equalized.symbols;
+
+    let per_block = transmitter::data_subcarriers(guard_bands).len();
+    let bits_per_symbol = modulation.bits_per_symbol();
+
+    // The header fills whole blocks, and the streams start on the block after it
+    let header_symbols = OfdmaHeader::serialized_len() * 8 / bits_per_symbol;
+    let data_start = (header_symbols + per_block - 1) / per_block * per_block;
+    anyhow::ensure!(
+        symbols.len() >= data_start,
+        "frame is too short to hold the header"
+    );
+
+    let header: OfdmaHeader =
+        bincode::deserialize(&demodulate_exact(&symbols[..header_symbols], modulation))?;
+    let allocation = header.allocation;
+    anyhow::ensure!(allocation.is_valid(), "header has an unusable allocation");
+
+    let wanted = header
+        .stream_lengths
+        .iter()
+        .map(|&len| len as usize * 8 / bits_per_symbol)
+        .collect::<Vec<_>>();
+
+    let mut carried = vec![false; symbols.len()];
+    carried[..header_symbols].iter_mut().for_each(|c| *c = true);
+
+    let mut stream_symbols = vec![Vec::new(); MAX_STREAMS];
+    for (idx, symbol) in symbols[data_start..].iter().enumerate() {
+        let owner = allocation.owner(idx / per_block, idx % per_block, per_block);
+        if stream_symbols[owner].len() < wanted[owner] {
+            stream_symbols[owner].push(*symbol);
+            carried[data_start + idx] = true;
+        }
+    }
+
+    anyhow::ensure!(
+        stream_symbols
+            .iter()
+            .zip(wanted.iter())
+            .all(|(got, &wanted)| got.len() == wanted),
+        "frame ended before every stream was complete"
+    );
+
+    let payloads = stream_symbols
+        .iter()
+        .take(allocation.streams())
+        .map(|symbols| demodulate_exact(symbols, modulation))
+        .collect();
+
+    let report = RxReport::new(&synced, &equalized, guard_bands, modulation, |idx| {
+        carried[idx]
+    });
+
+    Ok((payloads, report))
+}
+
+/// Demodulate symbols that don't necessarily fill whole bytes' worth of chunks
+fn demodulate_exact(symbols: &[Complex64], modulation: ModulationScheme) -> Vec<u8> {
+    let bytes = symbols.len() * modulation.bits_per_symbol() / 8;
+
+    let mut padded = symbols.to_vec();
+    padded.resize((symbols.len() + 7) / 8 * 8, Complex64::default());
+
+    let mut decoded = receiver::demodulate(padded, modulation);
+    decoded.truncate(bytes);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(allocation: Allocation, blocks: usize, per_block: usize) -> Vec<usize> {
+        let mut counts = vec![0; MAX_STREAMS];
+        for block in 0..blocks {
+            for subcarrier in 0..per_block {
+                counts[allocation.owner(block, subcarrier, per_block)] += 1;
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn shares_are_respected() {
+        assert_eq!(
+            counts(Allocation::interleaved(&[1, 3]), 10, 48),
+            vec![120, 360, 0, 0]
+        );
+        assert_eq!(
+            counts(Allocation::resource_blocks(&[1, 1, 1], 12), 12, 48),
+            vec![192, 192, 192, 0]
+        );
+        assert_eq!(
+            counts(Allocation::time_division(&[2, 1]), 9, 48),
+            vec![288, 144, 0, 0]
+        );
+    }
+
+    #[test]
+    fn resource_blocks_are_contiguous() {
+        let allocation = Allocation::resource_blocks(&[1, 1], 6);
+        let owners = (0..48)
+            .map(|subcarrier| allocation.owner(0, subcarrier, 48))
+            .collect::<Vec<_>>();
+        assert_eq!(&owners[..12], &[0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn header_is_fixed_size() {
+        let header = OfdmaHeader {
+            allocation: Allocation::resource_blocks(&[3, 1, 4, 1], 12),
+            stream_lengths: [5, 9, 2, 6],
+        };
+        let bytes = bincode::serialize(&header).unwrap();
+        assert_eq!(bytes.len(), OfdmaHeader::serialized_len());
+    }
+
+    #[test]
+    fn streams_share_a_frame() {
+        let telemetry = b"lat 42.36 lon -71.09 alt 12".to_vec();
+        let video = crate::utils::create_transmission_text(400, false);
+
+        for allocation in [
+            Allocation::interleaved(&[1, 4]),
+            Allocation::resource_blocks(&[1, 3], 6),
+            Allocation::time_division(&[1, 2]),
+        ]
+        .iter()
+        {
+            let samples = encode_streams(
+                &[&telemetry, &video],
+                *allocation,
+                true,
+                ModulationScheme::Bpsk,
+            );
+            let received = crate::channel(samples, Some(30.0), None, None, Some(3));
+
+            let (payloads, report) =
+                decode_streams(received, true, ModulationScheme::Bpsk).unwrap();
+            assert_eq!(payloads.len(), 2);
+            assert_eq!(payloads[0], telemetry, "{:?}", allocation);
+            assert_eq!(payloads[1], video, "{:?}", allocation);
+            assert!(report.mer_db > 10.0);
+        }
+    }
+}
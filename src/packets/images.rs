@@ -0,0 +1,392 @@
+//! Images and animations as packets that describe themselves.
+//!
+//! Every frame is sent as one byte per pixel, each byte picking a color out of a palette, behind
+//! an `ImageHeader` that says how big the frame is, which palette it uses and where it falls in
+//! the animation. Palettes other than the fixed `COLORMAP` are sent in the stream as packets of
+//! their own ahead of the frames that use them, so the receiver needs nothing agreed on ahead of
+//! time. Anything the `image` crate can read works as a source, and every frame of a GIF comes
+//! out as its own packet.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use anyhow::Context;
+use image::codecs::gif::GifDecoder;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, ImageFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::packets::colors::COLORMAP;
+use crate::packets::palette::Palette;
+
+/// How many bytes a serialized `ImageHeader` takes up
+pub const IMAGE_HEADER_LEN: usize = 7;
+
+/// Palette ID of the 256 color `COLORMAP`, which every receiver already has
+pub const COLORMAP_PALETTE: u8 = 0;
+
+/// Palette ID `image_to_packets` gives the palettes it builds
+pub const GENERATED_PALETTE: u8 = 1;
+
+/// The first byte of every packet, saying what follows
+pub const FRAME_PACKET: u8 = 0;
+pub const PALETTE_PACKET: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ImageHeader {
+    pub width: u16,
+    pub height: u16,
+
+    /// Which palette the pixel bytes index into
+    pub palette: u8,
+
+    /// Where the frame falls in its animation, zero for still images. Wraps around.
+    pub frame: u16,
+}
+
+/// One frame, as palette indices row after row
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageFrame {
+    pub header: ImageHeader,
+    pub pixels: Vec<u8>,
+}
+
+impl ImageFrame {
+    /// Quantize a frame to the `COLORMAP`, dropping transparency
+    pub fn from_rgba(image: &RgbaImage, frame: u16) -> Self {
+        let pixels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b, _] = pixel.0;
+                COLORMAP.get_closest(r, g, b).color_id
+            })
+            .collect();
+
+        Self::with_pixels(image, frame, COLORMAP_PALETTE, pixels)
+    }
+
+    /// Quantize a frame to any palette, optionally dithering
+    pub fn quantize(image: &RgbaImage, frame: u16, palette: &Palette, dither: bool) -> Self {
+        let pixels = palette.quantize(image, dither);
+        Self::with_pixels(image, frame, palette.id, pixels)
+    }
+
+    fn with_pixels(image: &RgbaImage, frame: u16, palette: u8, pixels: Vec<u8>) -> Self {
+        assert!(
+            image.width() <= u16::MAX as u32 && image.height() <= u16::MAX as u32,
+            "images can be at most {} pixels on a side",
+            u16::MAX
+        );
+
+        Self {
+            header: ImageHeader {
+                width: image.width() as u16,
+                height: image.height() as u16,
+                palette,
+                frame,
+            },
+            pixels,
+        }
+    }
+
+    /// The header followed by the pixels, ready for `encode`
+    pub fn to_packet(&self) -> Vec<u8> {
+        let mut packet = vec![FRAME_PACKET];
+        packet.extend(bincode::serialize(&self.header).unwrap());
+        packet.extend_from_slice(&self.pixels);
+        packet
+    }
+
+    /// Read a frame back out of a packet, if it is one and all the pixels are there. Extra bytes
+    /// after the pixels, like padding from Reed-Solomon decoding, are ignored.
+    pub fn from_packet(packet: &[u8]) -> Option<Self> {
+        match ImagePacket::parse(packet)? {
+            ImagePacket::Frame(frame) => Some(frame),
+            ImagePacket::Palette(_) => None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.header.width as usize
+    }
+
+    pub fn height(&self) -> usize {
+        self.header.height as usize
+    }
+
+    /// The frame as 0RGB words, the way minifb wants them
+    pub fn to_rgb(&self, palette: &Palette) -> Vec<u32> {
+        self.pixels.iter().map(|&idx| palette.rgb(idx)).collect()
+    }
+}
+
+/// Anything that can come out of an image stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImagePacket {
+    Frame(ImageFrame),
+    Palette(Palette),
+}
+
+impl ImagePacket {
+    /// Work out what a packet holds, if it's complete
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let (&kind, body) = packet.split_first()?;
+        match kind {
+            FRAME_PACKET => {
+                let header: ImageHeader =
+                    bincode::deserialize(body.get(..IMAGE_HEADER_LEN)?).ok()?;
+                let len = header.width as usize * header.height as usize;
+                let pixels = body.get(IMAGE_HEADER_LEN..IMAGE_HEADER_LEN + len)?.to_vec();
+                Some(ImagePacket::Frame(ImageFrame { header, pixels }))
+            }
+            PALETTE_PACKET => Palette::from_packet(packet).map(ImagePacket::Palette),
+            _ => None,
+        }
+    }
+}
+
+/// Turns a stream of image packets back into pictures, keeping track of the palettes sent along
+/// the way
+pub struct ImageReceiver {
+    palettes: HashMap<u8, Palette>,
+
+    /// Packets that didn't parse
+    pub corrupted: usize,
+
+    /// Frames thrown away because their palette never arrived
+    pub missing_palette: usize,
+}
+
+impl ImageReceiver {
+    /// A receiver that only knows the `COLORMAP` so far
+    pub fn new() -> Self {
+        let mut palettes = HashMap::new();
+        palettes.insert(COLORMAP_PALETTE, Palette::colormap());
+        Self {
+            palettes,
+            corrupted: 0,
+            missing_palette: 0,
+        }
+    }
+
+    /// Take in one decoded packet, returning a frame and its 0RGB pixels if it was one. A palette
+    /// replaces any earlier palette with the same ID.
+    pub fn push(&mut self, packet: &[u8]) -> Option<(ImageFrame, Vec<u32>)> {
+        let frame = match ImagePacket::parse(packet) {
+            Some(ImagePacket::Frame(frame)) => frame,
+            Some(ImagePacket::Palette(palette)) => {
+                self.palettes.insert(palette.id, palette);
+                return None;
+            }
+            None => {
+                self.corrupted += 1;
+                return None;
+            }
+        };
+
+        match self.palettes.get(&frame.header.palette) {
+            Some(palette) => {
+                let rgb = frame.to_rgb(palette);
+                Some((frame, rgb))
+            }
+            None => {
+                self.missing_palette += 1;
+                None
+            }
+        }
+    }
+
+    pub fn palette(&self, id: u8) -> Option<&Palette> {
+        self.palettes.get(&id)
+    }
+}
+
+impl Default for ImageReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How `image_to_packets` picks the colors frames are sent in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaletteMethod {
+    /// The fixed `COLORMAP`, which doesn't need sending
+    Colormap,
+
+    /// Median cut over every frame
+    MedianCut,
+
+    /// Median cut refined with k-means
+    KMeans,
+}
+
+/// Decode an image file of any format the `image` crate knows, quantizing every frame to the
+/// `COLORMAP`. GIFs give one frame per frame of the animation, everything else gives one. Frames
+/// are scaled to `size` first if it's given.
+#[optargs::optfn]
+pub fn load_frames(bytes: &[u8], size: Option<(u32, u32)>) -> anyhow::Result<Vec<ImageFrame>> {
+    Ok(load_images(bytes, size)?
+        .iter()
+        .enumerate()
+        .map(|(idx, image)| ImageFrame::from_rgba(image, idx as u16))
+        .collect())
+}
+
+/// Encode an image file as packets ready to send: the palette, unless it's the `COLORMAP`, then
+/// every frame. One palette covers the whole animation. Defaults to a median cut palette of 256
+/// colors without dithering.
+#[optargs::optfn]
+pub fn image_to_packets(
+    bytes: &[u8],
+    size: Option<(u32, u32)>,
+    method: Option<PaletteMethod>,
+    colors: Option<usize>,
+    dither: Option<bool>,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let images = load_images(bytes, size)?;
+    let colors = colors.unwrap_or(crate::packets::palette::MAX_COLORS);
+
+    let palette = match method.unwrap_or(PaletteMethod::MedianCut) {
+        PaletteMethod::Colormap => Palette::colormap(),
+        PaletteMethod::MedianCut => Palette::median_cut(GENERATED_PALETTE, &images, colors),
+        PaletteMethod::KMeans => Palette::kmeans(GENERATED_PALETTE, &images, colors),
+    };
+
+    let mut packets = Vec::with_capacity(images.len() + 1);
+    if palette.id != COLORMAP_PALETTE {
+        packets.push(palette.to_packet());
+    }
+    for (idx, image) in images.iter().enumerate() {
+        let frame = ImageFrame::quantize(image, idx as u16, &palette, dither.unwrap_or(false));
+        packets.push(frame.to_packet());
+    }
+
+    Ok(packets)
+}
+
+/// Decode every frame of an image file, scaled to `size` if it's given
+fn load_images(bytes: &[u8], size: Option<(u32, u32)>) -> anyhow::Result<Vec<RgbaImage>> {
+    let format = image::guess_format(bytes).context("Couldn't tell what kind of image this is")?;
+
+    let images = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?
+            .into_frames()
+            .collect_frames()
+            .context("Failed to decode the gif")?
+            .into_iter()
+            .map(|frame| frame.into_buffer())
+            .collect(),
+        _ => vec![image::load_from_memory_with_format(bytes, format)
+            .context("Failed to decode the image")?
+            .to_rgba8()],
+    };
+
+    Ok(images
+        .into_iter()
+        .map(|image| match size {
+            Some((width, height)) if image.dimensions() != (width, height) => {
+                image::imageops::resize(&image, width, height, FilterType::Triangle)
+            }
+            _ => image,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 40) as u8, (y * 40) as u8, 128, 255])
+        });
+
+        let mut bytes = Vec::new();
+        image::DynamicImage::ImageRgba8(image)
+            .write_to(&mut bytes, ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn frames_round_trip_through_packets() {
+        let frames = load_frames(&png(6, 4), None).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].width(), frames[0].height()), (6, 4));
+
+        let mut packet = frames[0].to_packet();
+        assert_eq!(packet.len(), 1 + IMAGE_HEADER_LEN + 24);
+        packet.extend_from_slice(&[0; 10]);
+        assert_eq!(ImageFrame::from_packet(&packet).as_ref(), Some(&frames[0]));
+
+        // Anything cut short is rejected instead of shown garbled
+        assert_eq!(
+            ImageFrame::from_packet(&packet[..IMAGE_HEADER_LEN + 24]),
+            None
+        );
+    }
+
+    #[test]
+    fn images_are_scaled_and_quantized() {
+        let frames = load_frames(&png(10, 10), Some((5, 3))).unwrap();
+        assert_eq!((frames[0].width(), frames[0].height()), (5, 3));
+
+        // Pure white is in the colormap, so it comes back exactly
+        let white = RgbaImage::from_pixel(2, 2, image::Rgba([255, 255, 255, 255]));
+        let frame = ImageFrame::from_rgba(&white, 0);
+        assert!(frame
+            .to_rgb(&Palette::colormap())
+            .iter()
+            .all(|&rgb| rgb == 0xFF_FF_FF));
+    }
+
+    #[test]
+    fn every_gif_frame_is_its_own_packet() {
+        let frames = load_frames(
+            include_bytes!("../../support/dancing_super_small.gif"),
+            None,
+        )
+        .unwrap();
+        assert!(frames.len() > 1);
+
+        for (idx, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.header.frame, idx as u16);
+            assert_eq!(frame.pixels.len(), frame.width() * frame.height());
+        }
+    }
+
+    #[test]
+    fn palettes_travel_with_the_frames() {
+        let gif = include_bytes!("../../support/dancing_super_small.gif");
+        let packets = image_to_packets(
+            gif,
+            None,
+            Some(PaletteMethod::MedianCut),
+            Some(16),
+            Some(true),
+        )
+        .unwrap();
+
+        let palette = match ImagePacket::parse(&packets[0]) {
+            Some(ImagePacket::Palette(palette)) => palette,
+            other => panic!("expected a palette first, got {:?}", other),
+        };
+        assert_eq!(palette.id, GENERATED_PALETTE);
+        assert!(palette.colors().len() <= 16);
+
+        // Without the palette, frames can't be shown
+        let mut receiver = ImageReceiver::new();
+        assert!(receiver.push(&packets[1]).is_none());
+        assert_eq!(receiver.missing_palette, 1);
+
+        assert!(receiver.push(&packets[0]).is_none());
+        for packet in &packets[1..] {
+            let (frame, rgb) = receiver.push(packet).unwrap();
+            assert_eq!(frame.header.palette, GENERATED_PALETTE);
+            assert_eq!(rgb.len(), frame.width() * frame.height());
+            assert!(rgb.iter().all(|color| palette.colors().iter().any(|c| {
+                *color == ((c[0] as u32) << 16) | ((c[1] as u32) << 8) | c[2] as u32
+            })));
+        }
+    }
+}
@@ -1,21 +1,13 @@
 //! Useful tools for encoding packets
 
-use std::fs::{File, OpenOptions};
-
-use image::codecs::gif::*;
-use image::Rgba;
-use std::io;
-use std::io::prelude::*;
-use std::io::Read;
-
-use image::gif::{GifDecoder, GifEncoder};
-use image::{AnimationDecoder, ImageDecoder};
 use serde::{Deserialize, Serialize};
 
-use crate::packets::colors::COLORMAP;
-
 pub mod colors;
 pub mod compression;
+pub mod images;
+pub mod palette;
+pub mod transport;
+pub mod video;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Header {
@@ -31,6 +23,29 @@ impl Header {
     }
 }
 
+/// Header for a frame shared between several streams.
+///
+/// Always serializes to the same size, so the receiver knows how many blocks to read it from
+/// before it knows anything else about the frame.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OfdmaHeader {
+    pub allocation: crate::ofdma::Allocation,
+
+    // How many bytes each stream sent, zero for unused streams
+    pub stream_lengths: [u32; crate::ofdma::MAX_STREAMS],
+}
+
+impl OfdmaHeader {
+    /// How many bytes the header takes up on the air
+    pub fn serialized_len() -> usize {
+        let header = OfdmaHeader {
+            allocation: crate::ofdma::Allocation::interleaved(&[1]),
+            stream_lengths: [0; crate::ofdma::MAX_STREAMS],
+        };
+        bincode::serialized_size(&header).unwrap() as usize
+    }
+}
+
 #[test]
 fn header_size() {
     let sample_header = Header::new(100);
@@ -39,59 +54,29 @@ fn header_size() {
     dbg!(decoded);
 }
 
-/// A small script/utility that writes the dancing gif to 1-byte color bytes
+/// The dancing gif comes out as one byte per pixel, frame by frame
 #[test]
 fn image_to_custom_colorspace() {
-    //
+    let bits = include_bytes!("../../support/dancing_super_small.gif");
 
-    // let mut bits = include_bytes!("../../support/dancing_smaller.gif");
-    let mut bits = include_bytes!("../../support/dancing_super_small.gif");
+    let ((width, height), out_bytes) = gif_to_bytestream(bits);
 
-    let (dims, out_bytes) = gif_to_bytestream(bits);
-
-    for (id, frame) in out_bytes.into_iter().enumerate() {
-        let mut out_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(format!("support/dancing_{}.bytes", id))
-            .unwrap();
-
-        // let first_frame = out_bytes.first().unwrap();
-        out_file.write_all(&frame).unwrap();
+    assert!(!out_bytes.is_empty());
+    for frame in out_bytes {
+        assert_eq!(frame.len(), (width * height) as usize);
     }
-
-    // dbg!(out_bytes.len());
 }
 
+/// Quantize every frame of a gif to the `COLORMAP`. `images::load_frames` does the same for any
+/// image format and keeps the dimensions with every frame.
 pub fn gif_to_bytestream(bytes: &[u8]) -> ((u32, u32), Vec<Vec<u8>>) {
-    let mut decoder = GifDecoder::new(bytes).unwrap();
-
-    let dims = decoder.dimensions();
-
-    let frames = decoder.into_frames();
-    let g = frames.collect_frames().expect("error_decoding");
+    let frames = images::load_frames(bytes, None).expect("error_decoding");
 
-    let mut out_bytes: Vec<Vec<u8>> = Vec::new();
-    for frame in g {
-        let mut raw_bytes = frame.into_buffer().into_raw().into_iter();
-
-        let mut frame_bytes = Vec::with_capacity(raw_bytes.len());
-
-        while let (Some(r), Some(g), Some(b), Some(a)) = (
-            raw_bytes.next(),
-            raw_bytes.next(),
-            raw_bytes.next(),
-            raw_bytes.next(),
-        ) {
-            let approx = colors::COLORMAP.get_closest(r, g, b);
-            frame_bytes.push(approx.color_id);
-        }
-
-        out_bytes.push(frame_bytes);
-    }
+    let dims = frames.first().map_or((0, 0), |frame| {
+        (frame.header.width as u32, frame.header.height as u32)
+    });
 
-    (dims, out_bytes)
+    (dims, frames.into_iter().map(|frame| frame.pixels).collect())
 }
 
 #[test]
@@ -0,0 +1,375 @@
+//! Palettes that pixels are sent as indices into.
+//!
+//! The fixed `COLORMAP` works for anything but fits nothing well, so smooth gradients come out
+//! in bands. A palette built from the image itself with median cut, optionally refined with
+//! k-means, spends its colors where the image actually has them. Floyd-Steinberg dithering
+//! spreads the leftover error onto the neighboring pixels, trading banding for fine noise that
+//! the eye averages out.
+
+use image::RgbaImage;
+
+use crate::packets::colors::{CustomRgb, COLORMAP};
+use crate::packets::images::{COLORMAP_PALETTE, PALETTE_PACKET};
+
+/// Most colors a palette can hold, so every index fits in a byte
+pub const MAX_COLORS: usize = 256;
+
+/// Pixels k-means looks at, at most. Bigger images are sampled evenly.
+const KMEANS_SAMPLES: usize = 20_000;
+
+const KMEANS_ITERATIONS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    /// What image headers call this palette
+    pub id: u8,
+    colors: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    pub fn new(id: u8, colors: Vec<[u8; 3]>) -> Self {
+        assert!(
+            (1..=MAX_COLORS).contains(&colors.len()),
+            "palettes hold between 1 and {} colors",
+            MAX_COLORS
+        );
+        Self { id, colors }
+    }
+
+    /// The fixed 256 color `COLORMAP`
+    pub fn colormap() -> Self {
+        let colors = (0..MAX_COLORS)
+            .map(|idx| {
+                let CustomRgb { r, g, b } = COLORMAP.get(idx as u8).rgb;
+                [r, g, b]
+            })
+            .collect();
+        Self::new(COLORMAP_PALETTE, colors)
+    }
+
+    /// Build a palette of at most `size` colors by splitting the color space where the pixels
+    /// are, like a k-d tree, until there are `size` boxes. Each color is the average of a box.
+    pub fn median_cut(id: u8, images: &[RgbaImage], size: usize) -> Self {
+        assert!(
+            (1..=MAX_COLORS).contains(&size),
+            "palettes hold between 1 and {} colors",
+            MAX_COLORS
+        );
+
+        let pixels = images
+            .iter()
+            .flat_map(|image| image.pixels().map(|pixel| rgb(pixel.0)))
+            .collect::<Vec<_>>();
+        if pixels.is_empty() {
+            return Self::new(id, vec![[0; 3]]);
+        }
+
+        let mut boxes = vec![pixels];
+        while boxes.len() < size {
+            // Split whichever box spans the most along any one channel
+            let (widest, channel, span) = boxes
+                .iter()
+                .enumerate()
+                .map(|(idx, pixels)| {
+                    let (channel, span) = widest_channel(pixels);
+                    (idx, channel, span)
+                })
+                .max_by_key(|&(_, _, span)| span)
+                .unwrap();
+
+            // Every box is a single color, so there's nothing left to split
+            if span == 0 {
+                break;
+            }
+
+            let mut pixels = boxes.swap_remove(widest);
+            pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+            let upper = pixels.split_off(pixels.len() / 2);
+            boxes.push(pixels);
+            boxes.push(upper);
+        }
+
+        Self::new(id, boxes.iter().map(|pixels| average(pixels)).collect())
+    }
+
+    /// Median cut, then a few rounds of k-means to pull every color to the middle of the pixels
+    /// closest to it. Slower but closer to the image. Always gives the same palette for the same
+    /// images.
+    pub fn kmeans(id: u8, images: &[RgbaImage], size: usize) -> Self {
+        let mut palette = Self::median_cut(id, images, size);
+
+        let total = images
+            .iter()
+            .map(|image| image.pixels().len())
+            .sum::<usize>();
+        let stride = (total / KMEANS_SAMPLES).max(1);
+        let samples = images
+            .iter()
+            .flat_map(|image| image.pixels().map(|pixel| rgb(pixel.0)))
+            .step_by(stride)
+            .collect::<Vec<_>>();
+
+        for _ in 0..KMEANS_ITERATIONS {
+            let mut sums = vec![[0u64; 4]; palette.colors.len()];
+            for pixel in &samples {
+                let sum = &mut sums[palette.nearest(*pixel) as usize];
+                for channel in 0..3 {
+                    sum[channel] += pixel[channel] as u64;
+                }
+                sum[3] += 1;
+            }
+
+            // Colors nobody picked stay where they are
+            let mut moved = false;
+            for (color, sum) in palette.colors.iter_mut().zip(sums.iter()) {
+                if sum[3] == 0 {
+                    continue;
+                }
+                let mean = [
+                    (sum[0] / sum[3]) as u8,
+                    (sum[1] / sum[3]) as u8,
+                    (sum[2] / sum[3]) as u8,
+                ];
+                moved |= mean != *color;
+                *color = mean;
+            }
+
+            if !moved {
+                break;
+            }
+        }
+
+        palette
+    }
+
+    /// The palette's ID and colors, ready for `encode`. Send it before any frame that uses it.
+    pub fn to_packet(&self) -> Vec<u8> {
+        let mut packet = vec![PALETTE_PACKET, self.id, (self.colors.len() - 1) as u8];
+        for color in &self.colors {
+            packet.extend_from_slice(color);
+        }
+        packet
+    }
+
+    /// Read a palette back out of a packet, if it is one and all the colors are there
+    pub fn from_packet(packet: &[u8]) -> Option<Self> {
+        let (&kind, body) = packet.split_first()?;
+        if kind != PALETTE_PACKET {
+            return None;
+        }
+
+        let (&id, body) = body.split_first()?;
+        let (&last, body) = body.split_first()?;
+        let colors = body
+            .get(..3 * (last as usize + 1))?
+            .chunks_exact(3)
+            .map(|color| [color[0], color[1], color[2]])
+            .collect();
+        Some(Self::new(id, colors))
+    }
+
+    pub fn colors(&self) -> &[[u8; 3]] {
+        &self.colors
+    }
+
+    /// Index of the closest color
+    pub fn nearest(&self, color: [u8; 3]) -> u8 {
+        self.colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, candidate)| distance(**candidate, color))
+            .map(|(idx, _)| idx as u8)
+            .unwrap()
+    }
+
+    /// The color at `index` as a 0RGB word, the way minifb wants it. Indices past the end of the
+    /// palette are black.
+    pub fn rgb(&self, index: u8) -> u32 {
+        let [r, g, b] = self.colors.get(index as usize).copied().unwrap_or_default();
+        ((r as u32) << 16) | ((g as u32) << 8) | b as u32
+    }
+
+    /// Turn an image into palette indices, row after row, dropping transparency
+    pub fn quantize(&self, image: &RgbaImage, dither: bool) -> Vec<u8> {
+        if !dither {
+            return image
+                .pixels()
+                .map(|pixel| self.nearest(rgb(pixel.0)))
+                .collect();
+        }
+
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut error = vec![[0f32; 3]; width * height];
+        let mut indices = Vec::with_capacity(width * height);
+
+        for (idx, pixel) in image.pixels().enumerate() {
+            let (x, y) = (idx % width, idx / width);
+
+            let mut wanted = [0f32; 3];
+            let mut rounded = [0u8; 3];
+            for channel in 0..3 {
+                wanted[channel] = (pixel.0[channel] as f32 + error[idx][channel]).clamp(0.0, 255.0);
+                rounded[channel] = wanted[channel].round() as u8;
+            }
+            let index = self.nearest(rounded);
+            indices.push(index);
+
+            // Push what's left over onto the pixels that haven't been picked yet
+            let picked = self.colors[index as usize];
+            let mut left_over = [0f32; 3];
+            for channel in 0..3 {
+                left_over[channel] = wanted[channel] - picked[channel] as f32;
+            }
+            let mut spread = |dx: isize, dy: usize, weight: f32| {
+                let nx = x as isize + dx;
+                if nx < 0 || nx >= width as isize || y + dy >= height {
+                    return;
+                }
+                let neighbor = &mut error[(y + dy) * width + nx as usize];
+                for channel in 0..3 {
+                    neighbor[channel] += left_over[channel] * weight;
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+
+        indices
+    }
+}
+
+fn rgb(rgba: [u8; 4]) -> [u8; 3] {
+    [rgba[0], rgba[1], rgba[2]]
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// The channel the pixels spread out over the most, and by how much
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = pixels.iter().map(|pixel| pixel[channel]);
+            let span = values.clone().max().unwrap() - values.min().unwrap();
+            (channel, span)
+        })
+        .max_by_key(|&(_, span)| span)
+        .unwrap()
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for channel in 0..3 {
+            sum[channel] += pixel[channel] as u64;
+        }
+    }
+    let len = pixels.len() as u64;
+    [
+        (sum[0] / len) as u8,
+        (sum[1] / len) as u8,
+        (sum[2] / len) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /// A horizontal gray ramp, the worst case for banding
+    fn gradient(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, _| {
+            let value = (x * 255 / (width - 1)) as u8;
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    fn mean_error(palette: &Palette, image: &RgbaImage, indices: &[u8]) -> f64 {
+        let total = image
+            .pixels()
+            .zip(indices)
+            .map(|(pixel, &idx)| distance(rgb(pixel.0), palette.colors()[idx as usize]) as f64)
+            .sum::<f64>();
+        total / indices.len() as f64
+    }
+
+    #[test]
+    fn median_cut_finds_the_colors_that_are_there() {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [250, 250, 250]];
+        let image = RgbaImage::from_fn(8, 8, |x, y| {
+            let [r, g, b] = colors[((x + y) % 4) as usize];
+            Rgba([r, g, b, 255])
+        });
+
+        // Asking for more colors than there are doesn't invent any
+        let palette = Palette::median_cut(1, &[image.clone()], 16);
+        assert_eq!(palette.colors().len(), 4);
+        for color in colors.iter() {
+            assert!(palette.colors().contains(color), "{:?}", color);
+        }
+
+        let indices = palette.quantize(&image, false);
+        assert_eq!(mean_error(&palette, &image, &indices), 0.0);
+    }
+
+    #[test]
+    fn fitted_palettes_beat_the_colormap() {
+        let image = RgbaImage::from_fn(32, 32, |x, y| {
+            Rgba([(x * 8) as u8, (y * 8) as u8, ((x + y) * 3) as u8, 255])
+        });
+
+        let colormap = Palette::colormap();
+        let median_cut = Palette::median_cut(1, &[image.clone()], 16);
+        let kmeans = Palette::kmeans(1, &[image.clone()], 16);
+
+        let error =
+            |palette: &Palette| mean_error(palette, &image, &palette.quantize(&image, false));
+        assert!(error(&kmeans) <= error(&median_cut));
+        assert!(error(&median_cut) < error(&colormap));
+        assert_eq!(kmeans, Palette::kmeans(1, &[image], 16));
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_brightness() {
+        let image = gradient(64, 8);
+        let palette = Palette::new(1, vec![[0; 3], [255; 3]]);
+
+        let brightness = |indices: &[u8]| {
+            indices
+                .iter()
+                .map(|&idx| palette.colors()[idx as usize][0] as f64)
+                .sum::<f64>()
+                / indices.len() as f64
+        };
+        let original = image.pixels().map(|p| p.0[0] as f64).sum::<f64>() / 512.0;
+
+        // Both come out about half bright, but only dithering keeps the ramp within each row
+        let dithered = palette.quantize(&image, true);
+        assert!((brightness(&dithered) - original).abs() < 4.0);
+
+        // Columns 8 to 24 are dark gray, which is all black without dithering
+        let dark_band = |indices: &[u8]| {
+            let band = indices
+                .chunks(64)
+                .flat_map(|row| row[8..24].iter().copied())
+                .collect::<Vec<_>>();
+            brightness(&band)
+        };
+        assert_eq!(dark_band(&palette.quantize(&image, false)), 0.0);
+        assert!((dark_band(&dithered) - 64.0).abs() < 32.0);
+    }
+
+    #[test]
+    fn the_colormap_matches_colors_json() {
+        let palette = Palette::colormap();
+        assert_eq!(palette.colors().len(), MAX_COLORS);
+        assert_eq!(palette.rgb(15), 0xFF_FF_FF);
+    }
+}
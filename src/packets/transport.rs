@@ -0,0 +1,448 @@
+//! Splitting byte streams into frames small enough to survive the air, and putting them back
+//! together on the other side.
+//!
+//! Every frame is sent as its own transmission with its own `Header`, so a bit error only costs
+//! the fragment it landed in instead of the whole message. Each frame starts with a
+//! `FragmentHeader` saying which message it belongs to and where it goes, and carries a CRC so
+//! corrupted frames are thrown away instead of being stitched into the message. Frames can
+//! optionally be Reed-Solomon coded with `utils::create_transmission_bytes` on top.
+//!
+//! The other packet formats frame their payloads the same way, through `FrameHeader`.
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::utils;
+
+/// How many bytes a serialized `FragmentHeader` takes up
+pub const FRAGMENT_HEADER_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FragmentHeader {
+    /// Which message the fragment belongs to. Wraps around.
+    pub sequence: u16,
+
+    /// Where the fragment's bytes start in the message
+    pub offset: u32,
+
+    /// Length of the whole message, so the receiver knows when it has all of it
+    pub message_length: u32,
+
+    /// Length of this fragment's payload. Reed-Solomon decoding pads frames out with zeros.
+    pub fragment_length: u16,
+
+    /// CRC-32 over the header, with this field zeroed, and the payload
+    pub checksum: u32,
+}
+
+/// A fixed size header at the front of a frame, carrying a CRC-32 over itself and the payload
+/// behind it
+pub trait FrameHeader: Serialize + DeserializeOwned + Copy {
+    /// How many bytes the header serializes to
+    const LEN: usize;
+
+    /// How many payload bytes follow the header
+    fn payload_len(&self) -> usize;
+
+    fn checksum(&self) -> u32;
+    fn set_checksum(&mut self, checksum: u32);
+}
+
+/// CRC-32 over the header, with its checksum zeroed, and the payload
+fn checksum_of<H: FrameHeader>(header: &H, payload: &[u8]) -> u32 {
+    let mut unsigned = *header;
+    unsigned.set_checksum(0);
+    let mut bytes = bincode::serialize(&unsigned).unwrap();
+    bytes.extend_from_slice(payload);
+    crc32(&bytes)
+}
+
+/// Put the header in front of the payload with its checksum filled in, Reed-Solomon coding the
+/// frame if `ecc` is set
+pub fn seal_frame<H: FrameHeader>(mut header: H, payload: &[u8], ecc: bool) -> Vec<u8> {
+    header.set_checksum(checksum_of(&header, payload));
+
+    let mut frame = bincode::serialize(&header).unwrap();
+    debug_assert_eq!(frame.len(), H::LEN, "frame headers have a fixed length");
+    frame.extend_from_slice(payload);
+
+    match ecc {
+        true => utils::create_transmission_bytes(&mut frame.into_iter()),
+        false => frame,
+    }
+}
+
+/// Split a frame made by `seal_frame` back into its header and payload, if it's intact
+pub fn open_frame<H: FrameHeader>(frame: &[u8], ecc: bool) -> Option<(H, Vec<u8>)> {
+    let frame = match ecc {
+        true => utils::decipher_transmission_bytes(&mut frame.iter().copied())?,
+        false => frame.to_vec(),
+    };
+
+    let header: H = bincode::deserialize(frame.get(..H::LEN)?).ok()?;
+    let payload = frame.get(H::LEN..H::LEN + header.payload_len())?.to_vec();
+
+    match checksum_of(&header, &payload) == header.checksum() {
+        true => Some((header, payload)),
+        false => None,
+    }
+}
+
+impl FrameHeader for FragmentHeader {
+    const LEN: usize = FRAGMENT_HEADER_LEN;
+
+    fn payload_len(&self) -> usize {
+        self.fragment_length as usize
+    }
+
+    fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn set_checksum(&mut self, checksum: u32) {
+        self.checksum = checksum;
+    }
+}
+
+/// Splits messages into frames no bigger than the MTU
+pub struct Fragmenter {
+    mtu: usize,
+    ecc: bool,
+    sequence: u16,
+}
+
+impl Fragmenter {
+    /// `mtu` is the most bytes a frame can hold, fragment header included
+    pub fn new(mtu: usize) -> Self {
+        assert!(
+            mtu > FRAGMENT_HEADER_LEN,
+            "the mtu has to leave room for data after the {} byte header",
+            FRAGMENT_HEADER_LEN
+        );
+        assert!(
+            mtu - FRAGMENT_HEADER_LEN <= u16::MAX as usize,
+            "fragments can be at most {} bytes",
+            u16::MAX
+        );
+
+        Self {
+            mtu,
+            ecc: false,
+            sequence: 0,
+        }
+    }
+
+    /// Reed-Solomon code every frame after it's built
+    pub fn ecc(mut self, ecc: bool) -> Self {
+        self.ecc = ecc;
+        self
+    }
+
+    /// The most message bytes that fit in one frame
+    pub fn payload_len(&self) -> usize {
+        self.mtu - FRAGMENT_HEADER_LEN
+    }
+
+    /// Split a message into frames ready for `encode`, giving it the next sequence number
+    pub fn fragment(&mut self, message: &[u8]) -> Vec<Vec<u8>> {
+        assert!(
+            message.len() <= u32::MAX as usize,
+            "messages can be at most 4GB"
+        );
+
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        // An empty message still needs a frame to say so
+        let mut chunks = message.chunks(self.payload_len()).collect::<Vec<_>>();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        let mut offset = 0;
+        chunks
+            .into_iter()
+            .map(|payload| {
+                let header = FragmentHeader {
+                    sequence,
+                    offset: offset as u32,
+                    message_length: message.len() as u32,
+                    fragment_length: payload.len() as u16,
+                    checksum: 0,
+                };
+                offset += payload.len();
+
+                seal_frame(header, payload, self.ecc)
+            })
+            .collect()
+    }
+}
+
+/// Collects fragments, in any order, back into whole messages
+pub struct Reassembler {
+    ecc: bool,
+    max_pending: usize,
+    pending: HashMap<u16, Partial>,
+
+    /// Sequence numbers of the pending messages, oldest first
+    arrival: VecDeque<u16>,
+
+    /// Frames thrown away because they failed the checksum or didn't parse
+    pub corrupted: usize,
+
+    /// Messages given up on to make room for newer ones
+    pub abandoned: usize,
+}
+
+struct Partial {
+    data: Vec<u8>,
+
+    /// Which bytes have arrived so far
+    received: Vec<bool>,
+    remaining: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            ecc: false,
+            max_pending: 16,
+            pending: HashMap::new(),
+            arrival: VecDeque::new(),
+            corrupted: 0,
+            abandoned: 0,
+        }
+    }
+
+    /// Expect Reed-Solomon coded frames, like a `Fragmenter` with `ecc(true)` makes
+    pub fn ecc(mut self, ecc: bool) -> Self {
+        self.ecc = ecc;
+        self
+    }
+
+    /// How many incomplete messages to hold on to before giving up on the oldest
+    pub fn max_pending(mut self, max_pending: usize) -> Self {
+        assert!(max_pending > 0, "need room for at least one message");
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Take in one decoded frame, returning the message if this was its last missing fragment.
+    /// Duplicate fragments are harmless.
+    pub fn push(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let (header, payload) = match open_frame::<FragmentHeader>(frame, self.ecc) {
+            Some(parsed) => parsed,
+            None => {
+                self.corrupted += 1;
+                return None;
+            }
+        };
+
+        let start = header.offset as usize;
+        let end = start + payload.len();
+        if end > header.message_length as usize {
+            self.corrupted += 1;
+            return None;
+        }
+
+        if !self.pending.contains_key(&header.sequence) {
+            if self.arrival.len() == self.max_pending {
+                let oldest = self.arrival.pop_front().unwrap();
+                self.pending.remove(&oldest);
+                self.abandoned += 1;
+            }
+
+            let len = header.message_length as usize;
+            self.pending.insert(
+                header.sequence,
+                Partial {
+                    data: vec![0; len],
+                    received: vec![false; len],
+                    remaining: len,
+                },
+            );
+            self.arrival.push_back(header.sequence);
+        }
+
+        let partial = self.pending.get_mut(&header.sequence).unwrap();
+
+        // A sequence number can come back around while an old message is still pending
+        if partial.data.len() != header.message_length as usize {
+            self.corrupted += 1;
+            return None;
+        }
+
+        for (idx, byte) in (start..end).zip(payload.iter()) {
+            if !partial.received[idx] {
+                partial.received[idx] = true;
+                partial.data[idx] = *byte;
+                partial.remaining -= 1;
+            }
+        }
+
+        if partial.remaining > 0 {
+            return None;
+        }
+
+        self.arrival.retain(|&s| s != header.sequence);
+        self.pending.remove(&header.sequence).map(|p| p.data)
+    }
+
+    /// Every pending message with the byte ranges it's still waiting on
+    pub fn missing(&self) -> Vec<(u16, Vec<Range<usize>>)> {
+        self.arrival
+            .iter()
+            .map(|sequence| {
+                let received = &self.pending[sequence].received;
+
+                let mut gaps = Vec::new();
+                let mut gap_start = None;
+                for (idx, &got) in received.iter().enumerate() {
+                    match (got, gap_start) {
+                        (false, None) => gap_start = Some(idx),
+                        (true, Some(start)) => {
+                            gaps.push(start..idx);
+                            gap_start = None;
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(start) = gap_start {
+                    gaps.push(start..received.len());
+                }
+
+                (*sequence, gaps)
+            })
+            .collect()
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The CRC-32 used by ethernet and zip files
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn crc_matches_the_standard() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn out_of_order_and_missing_fragments() {
+        let data = message(1000);
+        let mut fragmenter = Fragmenter::new(116);
+        let mut frames = fragmenter.fragment(&data);
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|f| f.len() <= 116));
+
+        // Everything but the fourth fragment, backwards
+        let lost = frames.remove(3);
+        let mut reassembler = Reassembler::new();
+        for frame in frames.iter().rev() {
+            assert_eq!(reassembler.push(frame), None);
+        }
+        assert_eq!(reassembler.missing(), vec![(0, vec![300..400])]);
+
+        // A late duplicate changes nothing, the missing piece completes the message
+        assert_eq!(reassembler.push(&frames[0]), None);
+        assert_eq!(reassembler.push(&lost), Some(data));
+        assert!(reassembler.missing().is_empty());
+    }
+
+    #[test]
+    fn messages_interleave() {
+        let first = message(500);
+        let second = b"short".to_vec();
+        let empty = Vec::new();
+
+        let mut fragmenter = Fragmenter::new(200);
+        let first_frames = fragmenter.fragment(&first);
+        let second_frames = fragmenter.fragment(&second);
+        let empty_frames = fragmenter.fragment(&empty);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&first_frames[0]), None);
+        assert_eq!(reassembler.push(&second_frames[0]), Some(second));
+        assert_eq!(reassembler.push(&empty_frames[0]), Some(empty));
+        assert_eq!(reassembler.push(&first_frames[2]), None);
+        assert_eq!(reassembler.push(&first_frames[1]), Some(first));
+    }
+
+    #[test]
+    fn corrupted_frames_are_dropped() {
+        let data = message(300);
+        let mut frames = Fragmenter::new(116).fragment(&data);
+        frames[1][40] ^= 0x10;
+
+        let mut reassembler = Reassembler::new();
+        for frame in frames.iter() {
+            assert_eq!(reassembler.push(frame), None);
+        }
+        assert_eq!(reassembler.corrupted, 1);
+        assert_eq!(reassembler.missing(), vec![(0, vec![100..200])]);
+    }
+
+    #[test]
+    fn ecc_fixes_what_it_can() {
+        let data = message(600);
+        let mut frames = Fragmenter::new(216).ecc(true).fragment(&data);
+
+        // Well within the 16 byte errors per block Reed-Solomon can correct
+        for frame in frames.iter_mut() {
+            for idx in (0..frame.len()).step_by(40) {
+                frame[idx] ^= 0xFF;
+            }
+        }
+
+        let mut reassembler = Reassembler::new().ecc(true);
+        let done = frames
+            .iter()
+            .filter_map(|frame| reassembler.push(frame))
+            .collect::<Vec<_>>();
+        assert_eq!(done, vec![data]);
+    }
+
+    #[test]
+    fn oldest_messages_are_abandoned() {
+        let mut fragmenter = Fragmenter::new(50);
+        let mut reassembler = Reassembler::new().max_pending(2);
+
+        // Only the first fragment of three messages
+        for _ in 0..3 {
+            reassembler.push(&fragmenter.fragment(&message(100))[0]);
+        }
+
+        assert_eq!(reassembler.abandoned, 1);
+        let pending = reassembler
+            .missing()
+            .into_iter()
+            .map(|(sequence, _)| sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(pending, vec![1, 2]);
+    }
+}
@@ -0,0 +1,473 @@
+//! Streaming video as packets that can each be decoded on their own.
+//!
+//! Every frame is cut into slices of whole rows, and every slice goes out as its own transmission
+//! behind a `SliceHeader` saying which frame it belongs to, where its rows go and how big the
+//! frame is. Losing a slice only costs those rows: the receiver fills them in from the last frame
+//! it showed, which for video is usually close enough to go unnoticed. Frames are raw pixels,
+//! `bytes_per_pixel` bytes each, row after row.
+
+use serde::{Deserialize, Serialize};
+
+use crate::packets::transport::{open_frame, seal_frame, FrameHeader};
+
+/// How many bytes a serialized `SliceHeader` takes up
+pub const SLICE_HEADER_LEN: usize = 21;
+
+/// How many frames behind the newest one a slice can be and still count as late. Any further back
+/// and the sender must have restarted.
+const MAX_REORDER: i32 = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SliceHeader {
+    /// Counts up from zero with every frame sent
+    pub frame: u32,
+
+    /// Which slice of the frame this is, and how many there are
+    pub slice: u16,
+    pub slices: u16,
+
+    /// Size of the whole frame, so the receiver doesn't need to be told ahead of time
+    pub width: u16,
+    pub height: u16,
+    pub bytes_per_pixel: u8,
+
+    /// The rows this slice covers
+    pub first_row: u16,
+    pub rows: u16,
+
+    /// CRC-32 over the header, with this field zeroed, and the pixels
+    pub checksum: u32,
+}
+
+impl SliceHeader {
+    fn row_len(&self) -> usize {
+        self.width as usize * self.bytes_per_pixel as usize
+    }
+}
+
+impl FrameHeader for SliceHeader {
+    const LEN: usize = SLICE_HEADER_LEN;
+
+    fn payload_len(&self) -> usize {
+        self.rows as usize * self.row_len()
+    }
+
+    fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn set_checksum(&mut self, checksum: u32) {
+        self.checksum = checksum;
+    }
+}
+
+/// A frame as it comes out of the receiver
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoFrame {
+    pub number: u32,
+    pub width: usize,
+    pub height: usize,
+    pub bytes_per_pixel: usize,
+    pub pixels: Vec<u8>,
+
+    /// Slices that never arrived and were filled in from the frame before
+    pub concealed: usize,
+}
+
+/// Cuts frames into slices small enough for one transmission each
+pub struct VideoPacketizer {
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    mtu: usize,
+    ecc: bool,
+    frame: u32,
+}
+
+impl VideoPacketizer {
+    /// `mtu` is the most bytes a packet can hold, slice header included, and has to fit at least
+    /// one row
+    pub fn new(width: usize, height: usize, bytes_per_pixel: usize, mtu: usize) -> Self {
+        assert!(
+            width <= u16::MAX as usize && height <= u16::MAX as usize,
+            "frames can be at most {} pixels on a side",
+            u16::MAX
+        );
+        assert!(
+            (1..=u8::MAX as usize).contains(&bytes_per_pixel),
+            "pixels need between 1 and {} bytes",
+            u8::MAX
+        );
+        assert!(
+            mtu >= SLICE_HEADER_LEN + width * bytes_per_pixel,
+            "the mtu has to fit a {} byte row after the {} byte header",
+            width * bytes_per_pixel,
+            SLICE_HEADER_LEN
+        );
+
+        Self {
+            width,
+            height,
+            bytes_per_pixel,
+            mtu,
+            ecc: false,
+            frame: 0,
+        }
+    }
+
+    /// Reed-Solomon code every packet after it's built
+    pub fn ecc(mut self, ecc: bool) -> Self {
+        self.ecc = ecc;
+        self
+    }
+
+    /// Bytes in one whole frame
+    pub fn frame_len(&self) -> usize {
+        self.width * self.height * self.bytes_per_pixel
+    }
+
+    /// How many rows go in each slice
+    pub fn rows_per_slice(&self) -> usize {
+        let row_len = self.width * self.bytes_per_pixel;
+        ((self.mtu - SLICE_HEADER_LEN) / row_len.max(1)).min(self.height.max(1))
+    }
+
+    /// Split a frame into packets ready for `encode`, giving it the next frame number
+    pub fn packetize(&mut self, pixels: &[u8]) -> Vec<Vec<u8>> {
+        assert_eq!(
+            pixels.len(),
+            self.frame_len(),
+            "frames have to be {}x{} with {} bytes per pixel",
+            self.width,
+            self.height,
+            self.bytes_per_pixel
+        );
+
+        let frame = self.frame;
+        self.frame = self.frame.wrapping_add(1);
+
+        let rows_per_slice = self.rows_per_slice();
+        let row_len = self.width * self.bytes_per_pixel;
+        let slices = (self.height + rows_per_slice - 1) / rows_per_slice;
+
+        (0..slices)
+            .map(|slice| {
+                let first_row = slice * rows_per_slice;
+                let rows = rows_per_slice.min(self.height - first_row);
+                let payload = &pixels[first_row * row_len..(first_row + rows) * row_len];
+
+                let header = SliceHeader {
+                    frame,
+                    slice: slice as u16,
+                    slices: slices as u16,
+                    width: self.width as u16,
+                    height: self.height as u16,
+                    bytes_per_pixel: self.bytes_per_pixel as u8,
+                    first_row: first_row as u16,
+                    rows: rows as u16,
+                    checksum: 0,
+                };
+                seal_frame(header, payload, self.ecc)
+            })
+            .collect()
+    }
+}
+
+/// Puts frames back together from their slices, covering for the ones that got lost
+pub struct VideoDepacketizer {
+    ecc: bool,
+
+    /// The frame slices are arriving for
+    current: Option<Assembly>,
+
+    /// The last frame handed out, which lost slices are filled in from
+    previous: Option<VideoFrame>,
+
+    /// Packets thrown away because they failed the checksum or didn't parse
+    pub corrupted: usize,
+
+    /// Packets for a frame that had already been handed out
+    pub late: usize,
+
+    /// Frames that never had a single slice arrive
+    pub skipped: usize,
+}
+
+/// A frame with only some of its slices in so far
+struct Assembly {
+    frame: VideoFrame,
+    slices: Vec<bool>,
+
+    /// Which rows arrived, and which need to be filled in
+    rows: Vec<bool>,
+}
+
+impl VideoDepacketizer {
+    pub fn new() -> Self {
+        Self {
+            ecc: false,
+            current: None,
+            previous: None,
+            corrupted: 0,
+            late: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Expect Reed-Solomon coded packets, like a `VideoPacketizer` with `ecc(true)` makes
+    pub fn ecc(mut self, ecc: bool) -> Self {
+        self.ecc = ecc;
+        self
+    }
+
+    /// Take in one decoded packet, returning any frames it finished. A frame is done once all of
+    /// its slices are in, or once a slice of a newer frame shows up and it's time to move on.
+    pub fn push(&mut self, packet: &[u8]) -> Vec<VideoFrame> {
+        let (header, pixels) = match self.parse(packet) {
+            Some(parsed) => parsed,
+            None => {
+                self.corrupted += 1;
+                return Vec::new();
+            }
+        };
+
+        let newest = match (&self.current, &self.previous) {
+            (Some(current), _) => Some(current.frame.number),
+            (None, Some(previous)) => Some(previous.number),
+            (None, None) => None,
+        };
+
+        // Frame numbers wrap around, so anything up to half their range ahead counts as newer
+        let ahead = newest.map(|newest| header.frame.wrapping_sub(newest) as i32);
+
+        let mut finished = Vec::new();
+        match ahead {
+            // That far back isn't a straggler, the sender started over
+            Some(ahead) if ahead < -MAX_REORDER => {
+                finished.extend(self.flush());
+                self.current = Some(self.start(&header));
+            }
+
+            // Anything older than what's being put together has missed its chance
+            Some(ahead) if ahead < 0 || (ahead == 0 && self.current.is_none()) => {
+                self.late += 1;
+                return finished;
+            }
+
+            Some(0) => {}
+
+            _ => {
+                finished.extend(self.flush());
+                self.skipped += ahead.map_or(0, |ahead| ahead - 1) as usize;
+                self.current = Some(self.start(&header));
+            }
+        }
+
+        let assembly = self.current.as_mut().unwrap();
+        let frame = &mut assembly.frame;
+        if assembly.slices.len() != header.slices as usize
+            || frame.width != header.width as usize
+            || frame.height != header.height as usize
+            || frame.bytes_per_pixel != header.bytes_per_pixel as usize
+        {
+            self.corrupted += 1;
+            return finished;
+        }
+
+        let rows = header.first_row as usize..(header.first_row + header.rows) as usize;
+        let row_len = header.row_len();
+        frame.pixels[rows.start * row_len..rows.end * row_len].copy_from_slice(&pixels);
+        assembly.slices[header.slice as usize] = true;
+        assembly.rows[rows].iter_mut().for_each(|got| *got = true);
+
+        if assembly.slices.iter().all(|&got| got) {
+            finished.extend(self.flush());
+        }
+        finished
+    }
+
+    /// Hand out the frame being put together, filling in whatever's missing. Call at the end of
+    /// a stream so the last frame isn't lost.
+    pub fn flush(&mut self) -> Option<VideoFrame> {
+        let Assembly {
+            mut frame,
+            slices,
+            rows,
+        } = self.current.take()?;
+        let row_len = frame.width * frame.bytes_per_pixel;
+
+        // Frames of a different size have nothing useful to borrow, so those rows stay black
+        let previous = self.previous.as_ref().filter(|previous| {
+            previous.width == frame.width
+                && previous.height == frame.height
+                && previous.bytes_per_pixel == frame.bytes_per_pixel
+        });
+
+        if let Some(previous) = previous {
+            for (row, _) in rows.iter().enumerate().filter(|(_, &got)| !got) {
+                let region = row * row_len..(row + 1) * row_len;
+                frame.pixels[region.clone()].copy_from_slice(&previous.pixels[region]);
+            }
+        }
+        frame.concealed = slices.iter().filter(|&&got| !got).count();
+
+        self.previous = Some(frame.clone());
+        Some(frame)
+    }
+
+    /// An empty frame to put the slices of a new frame into
+    fn start(&self, header: &SliceHeader) -> Assembly {
+        let frame = VideoFrame {
+            number: header.frame,
+            width: header.width as usize,
+            height: header.height as usize,
+            bytes_per_pixel: header.bytes_per_pixel as usize,
+            pixels: vec![0; header.height as usize * header.row_len()],
+            concealed: 0,
+        };
+        Assembly {
+            frame,
+            slices: vec![false; header.slices as usize],
+            rows: vec![false; header.height as usize],
+        }
+    }
+
+    /// Split a packet into its header and pixels, if it's intact and makes sense
+    fn parse(&self, packet: &[u8]) -> Option<(SliceHeader, Vec<u8>)> {
+        let (header, pixels) = open_frame::<SliceHeader>(packet, self.ecc)?;
+
+        // The checksum only says the sender meant it, not that it fits
+        let fits = header.slice < header.slices
+            && header.rows > 0
+            && header.first_row as usize + header.rows as usize <= header.height as usize;
+        match fits {
+            true => Some((header, pixels)),
+            false => None,
+        }
+    }
+}
+
+impl Default for VideoDepacketizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame that's different every time, so repeats are easy to spot
+    fn frame(width: usize, height: usize, number: u8) -> Vec<u8> {
+        (0..width * height * 3)
+            .map(|idx| (idx as u8).wrapping_mul(3).wrapping_add(number))
+            .collect()
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut packetizer = VideoPacketizer::new(16, 10, 3, SLICE_HEADER_LEN + 3 * 48);
+        let mut depacketizer = VideoDepacketizer::new();
+        assert_eq!(packetizer.rows_per_slice(), 3);
+
+        for number in 0..3 {
+            let sent = frame(16, 10, number);
+            let packets = packetizer.packetize(&sent);
+            assert_eq!(packets.len(), 4);
+
+            let mut received = Vec::new();
+            for packet in packets.iter().rev() {
+                received.extend(depacketizer.push(packet));
+            }
+
+            assert_eq!(received.len(), 1);
+            assert_eq!(received[0].number, number as u32);
+            assert_eq!(received[0].pixels, sent);
+            assert_eq!(received[0].concealed, 0);
+        }
+    }
+
+    #[test]
+    fn lost_slices_are_concealed_from_the_previous_frame() {
+        let mut packetizer = VideoPacketizer::new(16, 10, 3, SLICE_HEADER_LEN + 3 * 48);
+        let mut depacketizer = VideoDepacketizer::new();
+
+        let first = frame(16, 10, 0);
+        for packet in packetizer.packetize(&first) {
+            depacketizer.push(&packet);
+        }
+
+        // Lose the second slice, rows 3 to 5, then move on to the next frame
+        let second = frame(16, 10, 1);
+        let packets = packetizer.packetize(&second);
+        for packet in packets.iter().filter(|p| p != &&packets[1]) {
+            assert!(depacketizer.push(packet).is_empty());
+        }
+        let third = packetizer.packetize(&frame(16, 10, 2));
+        let shown = depacketizer.push(&third[0]);
+
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].concealed, 1);
+        let row = 16 * 3;
+        assert_eq!(shown[0].pixels[..3 * row], second[..3 * row]);
+        assert_eq!(shown[0].pixels[3 * row..6 * row], first[3 * row..6 * row]);
+        assert_eq!(shown[0].pixels[6 * row..], second[6 * row..]);
+
+        // The lost slice turning up now is too late to matter
+        assert!(depacketizer.push(&packets[1]).is_empty());
+        assert_eq!(depacketizer.late, 1);
+    }
+
+    #[test]
+    fn corrupted_packets_and_skipped_frames_are_counted() {
+        let mut packetizer = VideoPacketizer::new(8, 4, 3, 200);
+        let mut depacketizer = VideoDepacketizer::new();
+
+        let packet = packetizer.packetize(&frame(8, 4, 0)).remove(0);
+        let mut corrupted = packet.clone();
+        corrupted[SLICE_HEADER_LEN + 5] ^= 0x10;
+        assert!(depacketizer.push(&corrupted).is_empty());
+        assert_eq!(depacketizer.corrupted, 1);
+
+        // Frame 0 gets through intact, then frames 1 and 2 never make it
+        assert_eq!(depacketizer.push(&packet).len(), 1);
+        packetizer.packetize(&frame(8, 4, 1));
+        packetizer.packetize(&frame(8, 4, 2));
+
+        let sent = frame(8, 4, 3);
+        let shown = depacketizer.push(&packetizer.packetize(&sent)[0]);
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].number, 3);
+        assert_eq!(shown[0].pixels, sent);
+        assert_eq!(depacketizer.skipped, 2);
+    }
+
+    #[test]
+    fn frame_numbers_wrap_and_restarts_start_over() {
+        let mut packetizer = VideoPacketizer::new(8, 4, 3, 200);
+        let mut depacketizer = VideoDepacketizer::new();
+        packetizer.frame = u32::MAX;
+
+        // Frame 0 comes right after the last frame number
+        for _ in 0..2 {
+            let sent = frame(8, 4, 0);
+            let shown = depacketizer.push(&packetizer.packetize(&sent)[0]);
+            assert_eq!(shown.len(), 1);
+            assert_eq!(shown[0].pixels, sent);
+        }
+
+        // A sender starting over from frame 0 isn't shut out until it catches up
+        packetizer.frame = 1000;
+        depacketizer.push(&packetizer.packetize(&frame(8, 4, 1))[0]);
+        let skipped = depacketizer.skipped;
+        let mut restarted = VideoPacketizer::new(8, 4, 3, 200);
+        let sent = frame(8, 4, 2);
+        let shown = depacketizer.push(&restarted.packetize(&sent)[0]);
+
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].number, 0);
+        assert_eq!(shown[0].pixels, sent);
+        assert_eq!(depacketizer.late, 0);
+        assert_eq!(depacketizer.skipped, skipped);
+    }
+}
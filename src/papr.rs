@@ -0,0 +1,214 @@
+//! Peak to average power ratio measurement and reduction.
+//!
+//! Summing 52 subcarriers every now and then lines them all up into a peak far above the average
+//! power. Scaling so those rare peaks fit the DAC leaves most of the transmission far below full
+//! scale, so it pays to knock the peaks down before normalizing. Both methods here work on one
+//! OFDM symbol at a time, before the cyclic prefix is added back.
+//!
+//! - Clipping and filtering clips the peaks and then zeroes whatever the clipping spilled into
+//!   the null subcarriers. It's effective but puts a little noise on the data subcarriers.
+//! - Tone reservation only ever touches the null subcarriers, so the data is untouched and the
+//!   receiver needs no changes, but with only a handful of null bins it can't do as much.
+
+use num::complex::Complex64;
+
+use crate::signals::*;
+use crate::transmitter::is_null_subcarrier;
+
+/// Peak power over mean power, in dB
+pub fn papr_db(samples: &[Complex64]) -> f64 {
+    let peak = samples.iter().map(|s| s.norm_sqr()).fold(0.0, f64::max);
+    let mean = samples.power();
+    10.0 * (peak / mean).log10()
+}
+
+/// The fraction of `block_len` sample blocks with a PAPR above each threshold, for plotting the
+/// usual PAPR ccdf curve
+pub fn ccdf(samples: &[Complex64], block_len: usize, thresholds_db: &[f64]) -> Vec<f64> {
+    let paprs = samples
+        .chunks_exact(block_len)
+        .map(papr_db)
+        .collect::<Vec<_>>();
+
+    thresholds_db
+        .iter()
+        .map(|&threshold| {
+            let above = paprs.iter().filter(|&&papr| papr > threshold).count();
+            above as f64 / paprs.len() as f64
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PaprReduction {
+    /// Clip to `clip_db` above the symbol's rms, then clear the null subcarriers, repeated since
+    /// the filtering grows some of the peaks back
+    ClipAndFilter { clip_db: f64, iterations: usize },
+
+    /// Iteratively cancel whatever sits over `target_db` above the rms, using only the null
+    /// subcarriers. Needs guard bands, otherwise there are no spare subcarriers to use.
+    ToneReservation { target_db: f64, iterations: usize },
+}
+
+impl PaprReduction {
+    /// Reduce the PAPR of a single time domain symbol, in place
+    pub fn apply(&self, symbol: &mut [Complex64; 64], guard_bands: bool) {
+        match *self {
+            PaprReduction::ClipAndFilter {
+                clip_db,
+                iterations,
+            } => {
+                let limit = symbol.power().sqrt() * 10_f64.powf(clip_db / 20.0);
+                for _ in 0..iterations {
+                    for s in symbol.iter_mut() {
+                        *s -= clipping_noise(*s, limit);
+                    }
+
+                    symbol.fft();
+                    for (i, s) in symbol.iter_mut().enumerate() {
+                        if is_null_subcarrier(i, guard_bands) {
+                            *s = Complex64::default();
+                        }
+                    }
+                    symbol.ifft();
+                }
+            }
+
+            PaprReduction::ToneReservation {
+                target_db,
+                iterations,
+            } => {
+                let limit = symbol.power().sqrt() * 10_f64.powf(target_db / 20.0);
+                for _ in 0..iterations {
+                    let mut correction = [Complex64::default(); 64];
+                    for (c, s) in correction.iter_mut().zip(symbol.iter()) {
+                        *c = clipping_noise(*s, limit);
+                    }
+
+                    if correction.iter().all(|c| c.norm_sqr() == 0.0) {
+                        break;
+                    }
+
+                    // Project the peaks onto the reserved tones so the data bins never move
+                    correction.fft();
+                    for (i, c) in correction.iter_mut().enumerate() {
+                        if !is_null_subcarrier(i, guard_bands) {
+                            *c = Complex64::default();
+                        }
+                    }
+                    correction.ifft();
+
+                    for (s, c) in symbol.iter_mut().zip(correction.iter()) {
+                        *s -= c;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How far a sample pokes out past the limit, keeping its phase
+fn clipping_noise(sample: Complex64, limit: f64) -> Complex64 {
+    let magnitude = sample.norm();
+    match magnitude > limit {
+        true => sample * (1.0 - limit / magnitude),
+        false => Complex64::default(),
+    }
+}
+
+/// Apply a PAPR reduction to every cyclic prefixed block, rebuilding each prefix afterwards
+pub fn reduce_blocks(blocks: &mut [Complex64], reduction: PaprReduction, guard_bands: bool) {
+    for block in blocks.chunks_exact_mut(80) {
+        let mut symbol = [Complex64::default(); 64];
+        symbol.copy_from_slice(&block[16..]);
+
+        reduction.apply(&mut symbol, guard_bands);
+
+        block[..16].copy_from_slice(&symbol[48..]);
+        block[16..].copy_from_slice(&symbol);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Random QPSK on every used subcarrier, back in the time domain
+    fn random_symbols(count: usize) -> Vec<[Complex64; 64]> {
+        let mut rng = StdRng::seed_from_u64(9);
+        (0..count)
+            .map(|_| {
+                let mut symbol = [Complex64::default(); 64];
+                for (i, s) in symbol.iter_mut().enumerate() {
+                    if !is_null_subcarrier(i, true) {
+                        let re = if rng.gen() { 1.0 } else { -1.0 };
+                        let im = if rng.gen() { 1.0 } else { -1.0 };
+                        *s = Complex64::new(re, im);
+                    }
+                }
+                symbol.ifft();
+                symbol
+            })
+            .collect()
+    }
+
+    fn mean_papr(symbols: &[[Complex64; 64]]) -> f64 {
+        symbols.iter().map(|s| papr_db(s)).sum::<f64>() / symbols.len() as f64
+    }
+
+    #[test]
+    fn constant_envelope_has_no_papr() {
+        let tone = (0..64)
+            .map(|i| Complex64::from_polar(2.0, i as f64 * 0.3))
+            .collect::<Vec<_>>();
+        assert!(papr_db(&tone).abs() < 1e-9);
+        assert_eq!(ccdf(&tone, 16, &[-1.0, 0.5]), vec![1.0, 0.0]);
+    }
+
+    #[test]
+    fn clipping_and_filtering_lowers_papr() {
+        let original = random_symbols(200);
+        let mut clipped = original.clone();
+
+        let reduction = PaprReduction::ClipAndFilter {
+            clip_db: 4.0,
+            iterations: 4,
+        };
+        clipped.iter_mut().for_each(|s| reduction.apply(s, true));
+
+        assert!(mean_papr(&clipped) < mean_papr(&original) - 1.5);
+
+        // Nothing leaks into the null subcarriers
+        for symbol in clipped.iter_mut() {
+            symbol.fft();
+            for (i, s) in symbol.iter().enumerate() {
+                if is_null_subcarrier(i, true) {
+                    assert!(s.norm() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tone_reservation_leaves_data_alone() {
+        let original = random_symbols(200);
+        let mut reserved = original.clone();
+
+        let reduction = PaprReduction::ToneReservation {
+            target_db: 4.0,
+            iterations: 8,
+        };
+        reserved.iter_mut().for_each(|s| reduction.apply(s, true));
+
+        assert!(mean_papr(&reserved) < mean_papr(&original) - 0.5);
+
+        for (mut before, mut after) in original.into_iter().zip(reserved.into_iter()) {
+            before.fft();
+            after.fft();
+            for i in (0..64).filter(|&i| !is_null_subcarrier(i, true)) {
+                assert!((before[i] - after[i]).norm() < 1e-9);
+            }
+        }
+    }
+}
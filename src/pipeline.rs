@@ -0,0 +1,564 @@
+//! A multi-threaded receive pipeline for running against a live radio.
+//!
+//! Capture, packet detection, demodulation and error correction each get their own thread,
+//! connected by bounded queues. When a stage falls behind, the stage feeding it blocks, and that
+//! backpressure eventually reaches the capture queue. The radio can't wait, so buffers that
+//! don't fit in the capture queue are dropped and counted as overflows instead.
+//!
+//! ```ignore
+//! let (capture, pipeline) = RxPipeline::spawn(PipelineConfig::default());
+//!
+//! // on the radio thread
+//! capture.push(samples);
+//!
+//! // anywhere else
+//! for payload in pipeline.iter() { ... }
+//! ```
+
+use std::convert::TryFrom;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use num::complex::Complex64;
+
+use crate::packets::Header;
+use crate::signals::*;
+use crate::{transmitter, utils, ModulationScheme, RxReport};
+
+/// Samples kept ahead of every detected packet
+const PACKET_LEAD_IN: usize = 80;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// How many items each queue can hold before pushing back
+    pub queue_depth: usize,
+
+    /// The most samples a single transmission can span, including the preamble. Packets whose
+    /// header claims more are thrown out as corrupted.
+    pub max_packet_len: usize,
+
+    /// Correlation peak over the average correlation needed to call something a packet
+    pub detection_threshold: f64,
+
+    pub guard_bands: bool,
+    pub modulation: ModulationScheme,
+
+    /// Run the reed solomon decoder on every payload
+    pub ecc: bool,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            queue_depth: 4,
+            max_packet_len: 100_000,
+            detection_threshold: 20.0,
+            guard_bands: true,
+            modulation: ModulationScheme::Bpsk,
+            ecc: true,
+        }
+    }
+}
+
+/// Counters shared between every stage of the pipeline
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    pub buffers_captured: AtomicU64,
+    pub samples_captured: AtomicU64,
+    pub overflows: AtomicU64,
+    pub samples_dropped: AtomicU64,
+    pub packets_detected: AtomicU64,
+    pub decode_failures: AtomicU64,
+    pub fec_failures: AtomicU64,
+    pub packets_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+
+    /// Link quality of the most recently demodulated packet
+    pub last_report: Mutex<Option<RxReport>>,
+}
+
+impl PipelineStats {
+    fn bump(counter: &AtomicU64, by: u64) {
+        counter.fetch_add(by, Ordering::Relaxed);
+    }
+
+    fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// A point in time copy of the pipeline counters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatsSnapshot {
+    pub elapsed: Duration,
+    pub buffers_captured: u64,
+    pub samples_captured: u64,
+    pub overflows: u64,
+    pub samples_dropped: u64,
+    pub packets_detected: u64,
+    pub decode_failures: u64,
+    pub fec_failures: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+}
+
+impl StatsSnapshot {
+    /// Samples per second accepted into the pipeline
+    pub fn sample_rate(&self) -> f64 {
+        self.samples_captured as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Payload bytes per second coming out of the pipeline
+    pub fn goodput(&self) -> f64 {
+        self.bytes_out as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Fraction of captured samples that never made it into the pipeline
+    pub fn drop_rate(&self) -> f64 {
+        let total = self.samples_captured + self.samples_dropped;
+        match total {
+            0 => 0.0,
+            total => self.samples_dropped as f64 / total as f64,
+        }
+    }
+}
+
+/// The radio side of the pipeline. Cheap to clone and safe to hand to the capture thread.
+#[derive(Clone)]
+pub struct CaptureHandle {
+    tx: SyncSender<Vec<Complex64>>,
+    stats: Arc<PipelineStats>,
+}
+
+impl CaptureHandle {
+    /// Hand a buffer of samples to the pipeline without blocking.
+    ///
+    /// Returns false if the pipeline was too far behind and the buffer was dropped.
+    pub fn push(&self, samples: Vec<Complex64>) -> bool {
+        let len = samples.len() as u64;
+        match self.tx.try_send(samples) {
+            Ok(()) => {
+                PipelineStats::bump(&self.stats.buffers_captured, 1);
+                PipelineStats::bump(&self.stats.samples_captured, len);
+                true
+            }
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                PipelineStats::bump(&self.stats.overflows, 1);
+                PipelineStats::bump(&self.stats.samples_dropped, len);
+                false
+            }
+        }
+    }
+}
+
+/// The receiving end of the pipeline, yielding decoded payloads
+pub struct RxPipeline {
+    output: Receiver<Vec<u8>>,
+    stats: Arc<PipelineStats>,
+    started: Instant,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl RxPipeline {
+    /// Start the worker threads. The pipeline runs until every `CaptureHandle` is dropped.
+    pub fn spawn(config: PipelineConfig) -> (CaptureHandle, RxPipeline) {
+        let stats = Arc::new(PipelineStats::default());
+
+        let (capture_tx, capture_rx) = sync_channel::<Vec<Complex64>>(config.queue_depth);
+        let (packet_tx, packet_rx) = sync_channel::<Vec<Complex64>>(config.queue_depth);
+        let (payload_tx, payload_rx) = sync_channel::<Vec<u8>>(config.queue_depth);
+        let (output_tx, output_rx) = sync_channel::<Vec<u8>>(config.queue_depth);
+
+        let workers = vec![
+            spawn_worker("rx-detect", {
+                let stats = stats.clone();
+                move || detect_stage(config, capture_rx, packet_tx, &stats)
+            }),
+            spawn_worker("rx-demod", {
+                let stats = stats.clone();
+                move || demod_stage(config, packet_rx, payload_tx, &stats)
+            }),
+            spawn_worker("rx-fec", {
+                let stats = stats.clone();
+                move || fec_stage(config, payload_rx, output_tx, &stats)
+            }),
+        ];
+
+        let capture = CaptureHandle {
+            tx: capture_tx,
+            stats: stats.clone(),
+        };
+
+        let pipeline = RxPipeline {
+            output: output_rx,
+            stats,
+            started: Instant::now(),
+            workers,
+        };
+
+        (capture, pipeline)
+    }
+
+    /// Block until the next payload is decoded, or None once the pipeline has shut down
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        self.output.recv().ok()
+    }
+
+    /// Grab a decoded payload if one is ready
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        match self.output.try_recv() {
+            Ok(payload) => Some(payload),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        self.output.iter()
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        snapshot(&self.stats, self.started)
+    }
+
+    /// EVM, SNR and friends from the most recently demodulated packet
+    pub fn last_report(&self) -> Option<RxReport> {
+        self.stats.last_report.lock().unwrap().clone()
+    }
+
+    /// Wait for the workers to finish up and return the final counters.
+    ///
+    /// Payloads that haven't been received yet are thrown away.
+    pub fn join(self) -> StatsSnapshot {
+        let RxPipeline {
+            output,
+            stats,
+            started,
+            workers,
+        } = self;
+
+        // Dropping the output unblocks the last stage if it's waiting on us
+        drop(output);
+        for worker in workers {
+            let _ = worker.join();
+        }
+
+        snapshot(&stats, started)
+    }
+}
+
+fn snapshot(s: &PipelineStats, started: Instant) -> StatsSnapshot {
+    StatsSnapshot {
+        elapsed: started.elapsed(),
+        buffers_captured: PipelineStats::get(&s.buffers_captured),
+        samples_captured: PipelineStats::get(&s.samples_captured),
+        overflows: PipelineStats::get(&s.overflows),
+        samples_dropped: PipelineStats::get(&s.samples_dropped),
+        packets_detected: PipelineStats::get(&s.packets_detected),
+        decode_failures: PipelineStats::get(&s.decode_failures),
+        fec_failures: PipelineStats::get(&s.fec_failures),
+        packets_out: PipelineStats::get(&s.packets_out),
+        bytes_out: PipelineStats::get(&s.bytes_out),
+    }
+}
+
+fn spawn_worker(name: &str, f: impl FnOnce() + Send + 'static) -> JoinHandle<()> {
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .expect("failed to spawn pipeline worker")
+}
+
+/// Run a decoding step, turning a panic on a malformed packet into an error so it doesn't take
+/// the whole stage down
+fn guarded<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Decoder panicked")))
+}
+
+/// Find transmissions in the raw capture stream.
+///
+/// Captures are treated as one continuous stream, so a packet that straddles two buffers is
+/// stitched back together from the carried over tail. Every packet is cut off where its header
+/// says it ends, and whatever follows stays behind for the next search.
+fn detect_stage(
+    config: PipelineConfig,
+    input: Receiver<Vec<Complex64>>,
+    output: SyncSender<Vec<Complex64>>,
+    stats: &PipelineStats,
+) {
+    let mut carry: Vec<Complex64> = Vec::new();
+    let header_len = transmitter::transmission_len(0, config.guard_bands, config.modulation);
+
+    for buffer in input.iter() {
+        carry.extend(buffer);
+
+        while let Some(peak) = find_packet(&carry, config.detection_threshold) {
+            // Leave a little lead in so the decoder's own timing search has room to work
+            let start = peak.saturating_sub(PACKET_LEAD_IN);
+
+            // Wait for the header to show up, with the same slack after it for the timing search
+            let header_end = peak + header_len + PACKET_LEAD_IN;
+            if header_end > carry.len() {
+                carry.drain(..start);
+                break;
+            }
+
+            let len = match guarded(|| packet_len(&carry[start..header_end], config)) {
+                Ok(len) if len <= config.max_packet_len => len,
+                result => {
+                    if let Err(e) = result {
+                        log::debug!("failed to read a header: {}", e);
+                    }
+                    PipelineStats::bump(&stats.decode_failures, 1);
+
+                    // Move past this locking signal and look for the next one
+                    carry.drain(..peak + 80);
+                    continue;
+                }
+            };
+
+            if start + len > carry.len() {
+                // Wait for the rest of the packet to show up
+                carry.drain(..start);
+                break;
+            }
+
+            let rest = carry.split_off(start + len);
+            let packet = carry.split_off(start);
+            carry = rest;
+
+            PipelineStats::bump(&stats.packets_detected, 1);
+            if output.send(packet).is_err() {
+                return;
+            }
+        }
+
+        // Nothing worth keeping beyond one packet's worth of history
+        if carry.len() > config.max_packet_len {
+            carry.drain(..carry.len() - config.max_packet_len);
+        }
+    }
+}
+
+/// Find the start of the first transmission in the samples by correlating against the locking
+/// signal. The earliest correlation peak that stands out from the background wins, even when a
+/// stronger packet comes after it.
+pub fn find_packet(samples: &[Complex64], threshold: f64) -> Option<usize> {
+    if samples.len() < 80 {
+        return None;
+    }
+
+    let (_, cross) = samples.xcorr_fft(transmitter::locking_signal::<80>());
+    let zero_lag = (cross.len() - 1) / 2 + 1;
+
+    let power = cross.iter().map(|c| c.norm_sqr()).collect::<Vec<_>>();
+    let average = power.iter().sum::<f64>() / power.len() as f64;
+
+    // The correlation climbs for up to a block before it peaks, so take the top of the first
+    // climb rather than where it crosses the threshold
+    let first = (zero_lag..power.len()).find(|&idx| power[idx] > threshold * average)?;
+    let peak = (first..power.len().min(first + 2 * 80))
+        .max_by(|&a, &b| power[a].partial_cmp(&power[b]).unwrap())
+        .unwrap();
+
+    Some(peak - zero_lag)
+}
+
+/// Samples from the start of `samples` to the end of the transmission in it, going by its
+/// header. `samples` only has to reach past the header.
+fn packet_len(samples: &[Complex64], config: PipelineConfig) -> anyhow::Result<usize> {
+    let synced = crate::synchronize(samples.to_vec())?;
+    let equalized = crate::equalize(&synced.chunks, config.guard_bands);
+    let decoded = crate::demodulate(equalized.symbols, config.modulation);
+
+    let header_len = bincode::serialized_size(&Header::new(0))? as usize;
+    if decoded.len() < header_len {
+        return Err(anyhow::anyhow!("Header was cut off"));
+    }
+    let header: Header = bincode::deserialize(&decoded[..header_len])?;
+
+    // Every byte takes up at least a sample, so anything claiming more bytes than the limit has
+    // in samples is too long, and can't overflow working out how long it is
+    let data_len = usize::try_from(header.packet_length)?;
+    let len = match data_len <= config.max_packet_len {
+        true => transmitter::transmission_len(data_len, config.guard_bands, config.modulation),
+        false => usize::MAX,
+    };
+    if len > config.max_packet_len {
+        return Err(anyhow::anyhow!(
+            "Header claims {} bytes, more than {} samples can carry",
+            data_len,
+            config.max_packet_len
+        ));
+    }
+
+    Ok(synced.offset + len)
+}
+
+fn demod_stage(
+    config: PipelineConfig,
+    input: Receiver<Vec<Complex64>>,
+    output: SyncSender<Vec<u8>>,
+    stats: &PipelineStats,
+) {
+    for packet in input.iter() {
+        let decoded =
+            guarded(|| crate::decode(packet, Some(config.guard_bands), Some(config.modulation)));
+        match decoded {
+            Ok((payload, report)) => {
+                *stats.last_report.lock().unwrap() = Some(report);
+                if output.send(payload).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                log::debug!("failed to decode: {}", e);
+                PipelineStats::bump(&stats.decode_failures, 1);
+            }
+        }
+    }
+}
+
+fn fec_stage(
+    config: PipelineConfig,
+    input: Receiver<Vec<u8>>,
+    output: SyncSender<Vec<u8>>,
+    stats: &PipelineStats,
+) {
+    for payload in input.iter() {
+        let corrected = match config.ecc {
+            true => utils::decipher_transmission_bytes(&mut payload.into_iter()),
+            false => Some(payload),
+        };
+
+        match corrected {
+            Some(bytes) => {
+                PipelineStats::bump(&stats.packets_out, 1);
+                PipelineStats::bump(&stats.bytes_out, bytes.len() as u64);
+                if output.send(bytes).is_err() {
+                    return;
+                }
+            }
+            None => PipelineStats::bump(&stats.fec_failures, 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflows_are_counted() {
+        let config = PipelineConfig {
+            queue_depth: 1,
+            ..PipelineConfig::default()
+        };
+        let (capture, pipeline) = RxPipeline::spawn(config);
+
+        // Nothing detectable, but the detector can't keep up with a burst this fast
+        let pushed = (0..50)
+            .filter(|_| capture.push(vec![Complex64::default(); 100_000]))
+            .count();
+
+        drop(capture);
+        let stats = pipeline.join();
+        assert_eq!(stats.buffers_captured as usize, pushed);
+        assert_eq!(stats.overflows as usize, 50 - pushed);
+        assert_eq!(stats.packets_out, 0);
+    }
+
+    #[test]
+    fn the_earliest_packet_is_found_first() {
+        let mut stream = vec![Complex64::default(); 500];
+        let weak = crate::encode(&[1; 100], Some(true), None, None, None);
+        stream.extend(weak.iter().map(|s| s * 0.5));
+        stream.extend(vec![Complex64::default(); 300]);
+        stream.extend(crate::encode(&[2; 100], Some(true), None, None, None));
+
+        let start = find_packet(&stream, PipelineConfig::default().detection_threshold).unwrap();
+        assert!((start as i64 - 500).abs() <= 2, "{}", start);
+    }
+
+    #[test]
+    fn the_packet_limit_is_in_samples() {
+        let packet = crate::encode(&[3; 200], Some(true), None, None, None);
+        let mut samples = vec![Complex64::default(); 200];
+        samples.extend(packet.iter());
+        let config = |max_packet_len| PipelineConfig {
+            max_packet_len,
+            ..PipelineConfig::default()
+        };
+
+        let len = packet_len(&samples, config(packet.len())).unwrap();
+        assert!((len as i64 - samples.len() as i64).abs() <= 2, "{}", len);
+
+        // Far more than 200 bytes, but not enough samples to carry them
+        assert!(packet_len(&samples, config(packet.len() - 1)).is_err());
+    }
+
+    #[test]
+    fn back_to_back_packets_are_cut_at_their_headers() {
+        let short = b"short packet".to_vec();
+        let long = utils::create_transmission_text(600, false);
+
+        // The second packet follows right behind the first, with nothing to pad either out
+        let mut stream = vec![Complex64::default(); 2000];
+        stream.extend(crate::encode(&short, Some(true), None, None, None));
+        stream.extend(vec![Complex64::default(); 200]);
+        stream.extend(crate::encode(&long, Some(true), None, None, None));
+        let stream = crate::channel(stream, Some(30.0), None, None, Some(1));
+
+        let config = PipelineConfig {
+            queue_depth: 16,
+            ecc: false,
+            ..PipelineConfig::default()
+        };
+        let (capture, pipeline) = RxPipeline::spawn(config);
+        for chunk in stream.chunks(3000) {
+            assert!(capture.push(chunk.to_vec()));
+        }
+        drop(capture);
+
+        assert_eq!(pipeline.recv(), Some(short));
+        assert_eq!(pipeline.recv(), Some(long));
+        let stats = pipeline.join();
+        assert_eq!(stats.packets_detected, 2);
+        assert_eq!(stats.decode_failures, 0);
+    }
+
+    #[test]
+    fn packets_split_across_buffers() {
+        let data = utils::create_transmission_text(200, true);
+        let samples = crate::encode(&data, Some(true), None, None, None);
+        let samples = crate::channel(samples, Some(30.0), None, None, Some(0));
+
+        let config = PipelineConfig {
+            queue_depth: 16,
+            max_packet_len: samples.len() + 100,
+            ..PipelineConfig::default()
+        };
+        let (capture, pipeline) = RxPipeline::spawn(config);
+
+        // Quiet, then the packet spread over several captures, then quiet again
+        let mut stream = vec![Complex64::default(); 3000];
+        stream.extend(samples);
+        stream.extend(vec![Complex64::default(); 5000]);
+        for chunk in stream.chunks(4096) {
+            assert!(capture.push(chunk.to_vec()));
+        }
+        drop(capture);
+
+        let received = pipeline.recv().expect("packet never came out");
+        assert!(pipeline.last_report().is_some());
+        assert_eq!(
+            &received[..200],
+            &utils::create_transmission_text(200, false)[..]
+        );
+        assert_eq!(pipeline.join().packets_detected, 1);
+    }
+}
@@ -57,6 +57,46 @@ pub fn constellation(samples: &[Complex64]) {
         .display();
 }
 
+/// Plot a shifted power spectrum (like from `spectrum::welch`) in dB over normalized frequency
+pub fn psd_plot(psd: &[f64]) {
+    let db = crate::spectrum::to_db(psd);
+    let pts = crate::spectrum::frequencies(psd.len())
+        .into_iter()
+        .zip(db.iter())
+        .map(|(f, p)| (f as f32, *p as f32))
+        .collect::<Vec<_>>();
+
+    textplots::Chart::new(240, 60, -0.5, 0.5)
+        .lineplot(&Shape::Lines(&pts))
+        .display();
+}
+
+/// Print a spectrogram as shaded characters, time going down and frequency going across.
+/// Shading covers `range_db` below the strongest bin.
+pub fn spectrogram_plot(frames: &[Vec<f64>], range_db: f64) {
+    const SHADES: &[u8] = b" .:-=+*#%@";
+
+    let rows = frames
+        .iter()
+        .map(|frame| crate::spectrum::to_db(frame))
+        .collect::<Vec<_>>();
+    let max = rows
+        .iter()
+        .flatten()
+        .fold(f64::NEG_INFINITY, |l, &r| l.max(r));
+
+    for row in rows.iter() {
+        let line = row
+            .iter()
+            .map(|db| {
+                let level = ((db - max + range_db) / range_db).max(0.0).min(1.0);
+                SHADES[(level * (SHADES.len() - 1) as f64).round() as usize] as char
+            })
+            .collect::<String>();
+        println!("{}", line);
+    }
+}
+
 // Draw the channel impulse function just for funsies
 #[test]
 fn test_original() {
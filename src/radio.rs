@@ -0,0 +1,721 @@
+//! Sample transports that stand in for a radio, so the transmitter and receiver can run as
+//! separate processes on one machine.
+//!
+//! Samples go over UDP the way GNU Radio's UDP sink and source blocks send them: every datagram
+//! is complex float32 samples, interleaved I then Q, little endian, optionally behind a 64-bit
+//! sequence number. That means a GNU Radio flowgraph can sit on either end, feeding a real radio
+//! or a scope. An `ImpairedSink` runs samples through a chain of `Impairments` on the way out.
+//!
+//! A `FileSource` replays a capture as if it were coming off the radio live, so receivers can be
+//! run against the same samples over and over.
+//!
+//! ```ignore
+//! // the transmitting process
+//! let mut sink = UdpSink::connect("127.0.0.1:5005")?;
+//! sink.send(&encode(data, Some(true), None, None, None))?;
+//!
+//! // the receiving process
+//! let mut source = UdpSource::bind("127.0.0.1:5005")?;
+//! let mut buf = vec![Complex64::default(); 100_000];
+//! let len = source.receive(&mut buf)?;
+//! ```
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::ErrorKind;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use num::complex::Complex64;
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{complex_gaussian, Impairments};
+
+/// The most bytes of samples GNU Radio puts in a datagram by default, which fits in one ethernet
+/// frame
+pub const DEFAULT_PAYLOAD_SIZE: usize = 1472;
+
+/// Bytes in one fc32 sample
+const SAMPLE_SIZE: usize = 8;
+
+/// Sequence gaps bigger than this are the other end restarting, not lost datagrams
+const MAX_GAP: u64 = 1000;
+
+/// Somewhere to send samples, like the transmit side of a radio
+pub trait SampleSink {
+    fn send(&mut self, samples: &[Complex64]) -> anyhow::Result<()>;
+}
+
+/// Somewhere to receive samples from, like the receive side of a radio
+pub trait SampleSource {
+    /// Fill as much of `buf` as possible, returning how many samples were written. Fewer than
+    /// asked for means nothing else showed up before the timeout.
+    fn receive(&mut self, buf: &mut [Complex64]) -> anyhow::Result<usize>;
+}
+
+/// What comes in front of the samples in every datagram, matching GNU Radio's header types
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UdpHeader {
+    None,
+
+    /// A 64-bit little endian counter, so the receiver can tell when datagrams go missing
+    SequenceNumber,
+}
+
+impl UdpHeader {
+    fn len(&self) -> usize {
+        match self {
+            UdpHeader::None => 0,
+            UdpHeader::SequenceNumber => 8,
+        }
+    }
+}
+
+/// Keeps a stream of samples moving at a fixed sample rate
+struct Pacer {
+    rate: f64,
+
+    /// When the clock started, and how many samples have been let through since
+    start: Instant,
+    samples: u64,
+}
+
+impl Pacer {
+    fn new(rate: f64) -> Self {
+        assert!(rate > 0.0, "the sample rate has to be positive");
+        Self {
+            rate,
+            start: Instant::now(),
+            samples: 0,
+        }
+    }
+
+    fn due(&self) -> Instant {
+        self.start + Duration::from_secs_f64(self.samples as f64 / self.rate)
+    }
+
+    /// Sleep until `samples` more samples are due. The clock restarts after a pause, so idle time
+    /// isn't made up in a burst.
+    fn wait(&mut self, samples: usize) {
+        let now = Instant::now();
+        if self.due() < now {
+            self.start = now;
+            self.samples = 0;
+        }
+
+        self.samples += samples as u64;
+        let due = self.due();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+}
+
+/// Sends samples as UDP datagrams
+pub struct UdpSink {
+    socket: UdpSocket,
+    payload_size: usize,
+    header: UdpHeader,
+    sequence: u64,
+    pacer: Option<Pacer>,
+}
+
+impl UdpSink {
+    /// Send to `addr` from any local port
+    pub fn connect(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").context("Failed to open a udp socket")?;
+        socket
+            .connect(addr)
+            .context("Failed to find the address to send samples to")?;
+
+        Ok(Self {
+            socket,
+            payload_size: DEFAULT_PAYLOAD_SIZE,
+            header: UdpHeader::None,
+            sequence: 0,
+            pacer: None,
+        })
+    }
+
+    /// Bytes of samples per datagram, not counting the header. Has to match the other end.
+    pub fn payload_size(mut self, bytes: usize) -> Self {
+        assert!(
+            bytes >= SAMPLE_SIZE && bytes % SAMPLE_SIZE == 0,
+            "datagrams have to hold a whole number of samples"
+        );
+        self.payload_size = bytes;
+        self
+    }
+
+    pub fn header(mut self, header: UdpHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Send no faster than a radio running at `rate` samples per second would, like GNU Radio's
+    /// throttle block. Unpaced, a long burst overruns the receiver's socket buffer and the kernel
+    /// drops datagrams.
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.pacer = Some(Pacer::new(rate));
+        self
+    }
+}
+
+impl SampleSink for UdpSink {
+    fn send(&mut self, samples: &[Complex64]) -> anyhow::Result<()> {
+        let mut datagram = Vec::with_capacity(self.header.len() + self.payload_size);
+
+        for chunk in samples.chunks(self.payload_size / SAMPLE_SIZE) {
+            datagram.clear();
+            if self.header == UdpHeader::SequenceNumber {
+                datagram.extend_from_slice(&self.sequence.to_le_bytes());
+            }
+            for sample in chunk {
+                datagram.extend_from_slice(&(sample.re as f32).to_le_bytes());
+                datagram.extend_from_slice(&(sample.im as f32).to_le_bytes());
+            }
+
+            if let Some(pacer) = &mut self.pacer {
+                pacer.wait(chunk.len());
+            }
+            self.socket
+                .send(&datagram)
+                .context("Failed to send samples")?;
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+}
+
+/// Receives samples sent as UDP datagrams
+pub struct UdpSource {
+    socket: UdpSocket,
+    header: UdpHeader,
+
+    /// Samples from the last datagram that didn't fit in the caller's buffer
+    pending: VecDeque<Complex64>,
+    next_sequence: Option<u64>,
+    datagram: Vec<u8>,
+
+    /// Samples in the largest datagram so far. Senders only send short ones at the end of a
+    /// burst, so lost datagrams are taken to be this long.
+    largest: usize,
+
+    /// Datagrams that never arrived, going by the sequence numbers. Each was replaced with zeros
+    /// to keep the timing of everything after it.
+    pub dropped: u64,
+}
+
+impl UdpSource {
+    /// Listen on `addr`. Waits up to 100ms for samples before handing back what it has.
+    pub fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr).context("Failed to bind the udp socket")?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+
+        Ok(Self {
+            socket,
+            header: UdpHeader::None,
+            pending: VecDeque::new(),
+            next_sequence: None,
+            datagram: vec![0; 65536],
+            largest: 0,
+            dropped: 0,
+        })
+    }
+
+    pub fn header(mut self, header: UdpHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// How long `receive` waits for more samples before returning
+    pub fn timeout(self, timeout: Duration) -> anyhow::Result<Self> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        Ok(self)
+    }
+
+    /// The address samples should be sent to, useful after binding to port 0
+    pub fn local_addr(&self) -> anyhow::Result<std::net::SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Wait for the next datagram and queue up its samples. Returns false on timeout.
+    fn fill(&mut self) -> anyhow::Result<bool> {
+        let len = match self.socket.recv(&mut self.datagram) {
+            Ok(len) => len,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err).context("Failed to receive samples"),
+        };
+
+        // GNU Radio sends an empty datagram when its flowgraph stops
+        if len < self.header.len() + SAMPLE_SIZE {
+            return Ok(true);
+        }
+
+        let (header, payload) = self.datagram[..len].split_at(self.header.len());
+        self.largest = self.largest.max(payload.len() / SAMPLE_SIZE);
+
+        if self.header == UdpHeader::SequenceNumber {
+            let sequence = u64::from_le_bytes(header.try_into().unwrap());
+            let gap = self
+                .next_sequence
+                .map_or(0, |expected| sequence.wrapping_sub(expected));
+
+            if gap > 0 && gap <= MAX_GAP {
+                self.dropped += gap;
+                let missing = gap as usize * self.largest;
+                self.pending
+                    .extend(std::iter::repeat(Complex64::default()).take(missing));
+            }
+            self.next_sequence = Some(sequence.wrapping_add(1));
+        }
+
+        for sample in payload.chunks_exact(SAMPLE_SIZE) {
+            let re = f32::from_le_bytes(sample[..4].try_into().unwrap());
+            let im = f32::from_le_bytes(sample[4..].try_into().unwrap());
+            self.pending.push_back(Complex64::new(re as f64, im as f64));
+        }
+
+        Ok(true)
+    }
+}
+
+impl SampleSource for UdpSource {
+    fn receive(&mut self, buf: &mut [Complex64]) -> anyhow::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            if self.pending.is_empty() && !self.fill()? {
+                break;
+            }
+
+            while let (Some(slot), Some(sample)) = (buf.get_mut(written), self.pending.front()) {
+                *slot = *sample;
+                self.pending.pop_front();
+                written += 1;
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+/// Runs everything sent through a chain of impairments first, like a channel between the
+/// transmitter and the receiver
+pub struct ImpairedSink<S> {
+    sink: S,
+    impairments: Impairments,
+}
+
+impl<S: SampleSink> ImpairedSink<S> {
+    pub fn new(sink: S, impairments: Impairments) -> Self {
+        Self { sink, impairments }
+    }
+}
+
+impl<S: SampleSink> SampleSink for ImpairedSink<S> {
+    fn send(&mut self, samples: &[Complex64]) -> anyhow::Result<()> {
+        let impaired = self.impairments.apply(samples.to_vec());
+        self.sink.send(&impaired)
+    }
+}
+
+/// Plays back a capture from a file, either raw samples as written by `utils::sig_to_bytes` or a
+/// SigMF recording
+pub struct FileSource {
+    samples: Vec<Complex64>,
+    position: usize,
+    looping: bool,
+    pacer: Option<Pacer>,
+
+    /// Standard deviation of the noise added to every sample, and where it comes from
+    noise: Option<(f64, StdRng)>,
+}
+
+impl FileSource {
+    /// Read a whole capture into memory. Either half of a SigMF pair opens the recording, and
+    /// plays back at the sample rate in its metadata. Anything else is raw samples, which aren't
+    /// paced until a sample rate is set.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let is_sigmf = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("sigmf-meta") | Some("sigmf-data")
+        );
+
+        let source = match is_sigmf {
+            true => {
+                let (samples, rate) = read_sigmf(path)?;
+                let source = Self::from_samples(samples);
+                match rate {
+                    Some(rate) => source.sample_rate(rate),
+                    None => source,
+                }
+            }
+            false => {
+                let bytes = std::fs::read(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                Self::from_samples(unsafe { crate::utils::bytes_to_sig(bytes) })
+            }
+        };
+
+        anyhow::ensure!(
+            !source.samples.is_empty(),
+            "{} has no samples in it",
+            path.display()
+        );
+        Ok(source)
+    }
+
+    /// Play back samples that are already in memory
+    pub fn from_samples(samples: Vec<Complex64>) -> Self {
+        Self {
+            samples,
+            position: 0,
+            looping: false,
+            pacer: None,
+            noise: None,
+        }
+    }
+
+    /// Hand out samples no faster than a radio running at `rate` samples per second would
+    pub fn sample_rate(mut self, rate: f64) -> Self {
+        self.pacer = Some(Pacer::new(rate));
+        self
+    }
+
+    /// Hand out samples as fast as they're asked for, even for SigMF recordings
+    pub fn unpaced(mut self) -> Self {
+        self.pacer = None;
+        self
+    }
+
+    /// Start over from the beginning instead of running out
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    /// Add complex gaussian noise with a power of `power_db` relative to 1.0, on top of whatever
+    /// noise is already in the capture. The same seed gives the same noise every run.
+    pub fn noise_floor(mut self, power_db: f64, seed: u64) -> Self {
+        let scale = 10f64.powf(power_db / 10.0).sqrt();
+        self.noise = Some((scale, StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    /// Samples in the file
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+impl SampleSource for FileSource {
+    /// Fills the whole buffer unless the file runs out, and returns 0 once it has
+    fn receive(&mut self, buf: &mut [Complex64]) -> anyhow::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() {
+            // An empty file has nothing to loop over
+            if self.position == self.samples.len() {
+                match self.looping && !self.samples.is_empty() {
+                    true => self.position = 0,
+                    false => break,
+                }
+            }
+
+            let len = (buf.len() - written).min(self.samples.len() - self.position);
+            buf[written..written + len]
+                .copy_from_slice(&self.samples[self.position..self.position + len]);
+            self.position += len;
+            written += len;
+        }
+
+        if let Some((scale, rng)) = &mut self.noise {
+            for sample in buf[..written].iter_mut() {
+                *sample += complex_gaussian(rng) * *scale;
+            }
+        }
+
+        if let Some(pacer) = &mut self.pacer {
+            pacer.wait(written);
+        }
+
+        Ok(written)
+    }
+}
+
+/// Read a SigMF recording, returning its samples and sample rate
+fn read_sigmf(path: &Path) -> anyhow::Result<(Vec<Complex64>, Option<f64>)> {
+    let meta_path = path.with_extension("sigmf-meta");
+    let data_path = path.with_extension("sigmf-data");
+
+    let meta = std::fs::read_to_string(&meta_path)
+        .with_context(|| format!("Failed to read {}", meta_path.display()))?;
+    let meta: serde_json::Value = serde_json::from_str(&meta)
+        .with_context(|| format!("{} isn't valid json", meta_path.display()))?;
+
+    let global = &meta["global"];
+    let datatype = global["core:datatype"]
+        .as_str()
+        .context("SigMF metadata is missing core:datatype")?;
+    let rate = global["core:sample_rate"].as_f64();
+
+    let bytes = std::fs::read(&data_path)
+        .with_context(|| format!("Failed to read {}", data_path.display()))?;
+
+    // Only complex samples make sense for a receiver
+    let samples = match datatype {
+        "cf32_le" => parse_samples(&bytes, 4, |b| {
+            f32::from_le_bytes(b.try_into().unwrap()) as f64
+        }),
+        "cf32_be" => parse_samples(&bytes, 4, |b| {
+            f32::from_be_bytes(b.try_into().unwrap()) as f64
+        }),
+        "ci16_le" => parse_samples(&bytes, 2, |b| {
+            i16::from_le_bytes(b.try_into().unwrap()) as f64 / 32768.0
+        }),
+        "ci16_be" => parse_samples(&bytes, 2, |b| {
+            i16::from_be_bytes(b.try_into().unwrap()) as f64 / 32768.0
+        }),
+        "ci8" => parse_samples(&bytes, 1, |b| b[0] as i8 as f64 / 128.0),
+        other => anyhow::bail!("SigMF datatype {} isn't supported", other),
+    };
+
+    Ok((samples, rate))
+}
+
+/// Split interleaved I/Q into complex samples, `width` bytes per component
+fn parse_samples(bytes: &[u8], width: usize, component: impl Fn(&[u8]) -> f64) -> Vec<Complex64> {
+    bytes
+        .chunks_exact(2 * width)
+        .map(|sample| Complex64::new(component(&sample[..width]), component(&sample[width..])))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Awgn, NoiseLevel};
+
+    fn pair(header: UdpHeader) -> (UdpSink, UdpSource) {
+        let source = UdpSource::bind("127.0.0.1:0")
+            .unwrap()
+            .header(header)
+            .timeout(Duration::from_millis(200))
+            .unwrap();
+        let sink = UdpSink::connect(source.local_addr().unwrap())
+            .unwrap()
+            .header(header);
+        (sink, source)
+    }
+
+    fn ramp(len: usize) -> Vec<Complex64> {
+        (0..len)
+            .map(|idx| Complex64::new(idx as f64, -(idx as f64) / 2.0))
+            .collect()
+    }
+
+    #[test]
+    fn samples_arrive_as_sent() {
+        for header in [UdpHeader::None, UdpHeader::SequenceNumber].iter() {
+            let (mut sink, mut source) = pair(*header);
+            let sent = ramp(1000);
+            sink.send(&sent).unwrap();
+
+            // Reading in odd sized pieces splits datagrams across calls
+            let mut received = Vec::new();
+            let mut buf = vec![Complex64::default(); 77];
+            loop {
+                let len = source.receive(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..len]);
+                if len < buf.len() {
+                    break;
+                }
+            }
+
+            assert_eq!(received, sent, "{:?}", header);
+            assert_eq!(source.dropped, 0);
+        }
+    }
+
+    #[test]
+    fn datagrams_match_gnuradio() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = UdpSink::connect(receiver.local_addr().unwrap())
+            .unwrap()
+            .header(UdpHeader::SequenceNumber)
+            .payload_size(16);
+
+        sink.send(&[
+            Complex64::new(1.0, -2.0),
+            Complex64::new(0.5, 0.0),
+            Complex64::new(3.0, 4.0),
+        ])
+        .unwrap();
+
+        let mut datagram = [0; 64];
+        let len = receiver.recv(&mut datagram).unwrap();
+        let mut expected = 0u64.to_le_bytes().to_vec();
+        for value in [1.0f32, -2.0, 0.5, 0.0].iter() {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(&datagram[..len], &expected[..]);
+
+        // The last sample goes out on its own with the next sequence number
+        let len = receiver.recv(&mut datagram).unwrap();
+        assert_eq!(len, 16);
+        assert_eq!(datagram[..8], 1u64.to_le_bytes());
+    }
+
+    #[test]
+    fn lost_datagrams_become_zeros() {
+        let (sink, mut source) = pair(UdpHeader::SequenceNumber);
+        let sink = sink.payload_size(80);
+
+        // Skip a sequence number by sending straight from the socket
+        let datagram = |sequence: u64, value: f32, samples: usize| {
+            let mut datagram = sequence.to_le_bytes().to_vec();
+            for _ in 0..samples {
+                datagram.extend_from_slice(&value.to_le_bytes());
+                datagram.extend_from_slice(&0f32.to_le_bytes());
+            }
+            datagram
+        };
+        sink.socket.send(&datagram(0, 1.0, 10)).unwrap();
+        sink.socket.send(&datagram(2, 2.0, 10)).unwrap();
+
+        let mut buf = vec![Complex64::default(); 30];
+        assert_eq!(source.receive(&mut buf).unwrap(), 30);
+        assert_eq!(source.dropped, 1);
+        assert!(buf[..10].iter().all(|s| s.re == 1.0));
+        assert!(buf[10..20].iter().all(|s| s.re == 0.0));
+        assert!(buf[20..].iter().all(|s| s.re == 2.0));
+
+        // A short datagram after a gap doesn't shrink the ones that went missing
+        sink.socket.send(&datagram(5, 3.0, 4)).unwrap();
+        let mut buf = vec![Complex64::default(); 24];
+        assert_eq!(source.receive(&mut buf).unwrap(), 24);
+        assert_eq!(source.dropped, 3);
+        assert!(buf[..20].iter().all(|s| s.re == 0.0));
+        assert!(buf[20..].iter().all(|s| s.re == 3.0));
+    }
+
+    #[test]
+    fn sending_is_paced_at_the_sample_rate() {
+        let (sink, _source) = pair(UdpHeader::None);
+        let mut sink = sink.sample_rate(100_000.0);
+
+        let start = Instant::now();
+        sink.send(&ramp(10_000)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(99));
+    }
+
+    #[test]
+    fn packets_decode_across_the_socket() {
+        let (sink, mut source) = pair(UdpHeader::SequenceNumber);
+        let impairments = Impairments::new().then(Awgn::new(NoiseLevel::Snr(25.0), 1));
+        let mut sink = ImpairedSink::new(sink, impairments);
+
+        let data = crate::utils::create_transmission_text(300, false);
+        let samples = crate::encode(&data, Some(true), None, None, None);
+
+        // The socket holds on to the whole packet until it's read
+        let mut lead_in = vec![Complex64::default(); 200];
+        lead_in.extend(samples);
+        sink.send(&lead_in).unwrap();
+
+        let mut buf = vec![Complex64::default(); 200_000];
+        let len = source.receive(&mut buf).unwrap();
+
+        let (payload, _) = crate::decode(buf[..len].to_vec(), Some(true), None).unwrap();
+        assert_eq!(payload, data);
+    }
+
+    /// Somewhere to write a capture that won't collide with other tests
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ofdm-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn files_play_back_and_loop() {
+        let path = temp_path("capture.dat");
+        let sent = ramp(100);
+        std::fs::write(&path, crate::utils::sig_to_bytes(sent.clone())).unwrap();
+
+        let mut source = FileSource::open(&path).unwrap();
+        let mut buf = vec![Complex64::default(); 60];
+        assert_eq!(source.receive(&mut buf).unwrap(), 60);
+        assert_eq!(buf, &sent[..60]);
+        assert_eq!(source.receive(&mut buf).unwrap(), 40);
+        assert_eq!(buf[..40], sent[60..]);
+        assert_eq!(source.receive(&mut buf).unwrap(), 0);
+
+        // Looping wraps around inside a single call
+        let mut source = FileSource::open(&path).unwrap().looping(true);
+        let mut buf = vec![Complex64::default(); 250];
+        assert_eq!(source.receive(&mut buf).unwrap(), 250);
+        assert_eq!(buf[..100], sent[..]);
+        assert_eq!(buf[200..], sent[..50]);
+
+        let mut empty = FileSource::from_samples(vec![]).looping(true);
+        assert_eq!(empty.receive(&mut buf).unwrap(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sigmf_recordings_are_read_and_paced() {
+        let data_path = temp_path("capture.sigmf-data");
+        let meta_path = data_path.with_extension("sigmf-meta");
+
+        let mut bytes = Vec::new();
+        for value in [16384i16, -32768, 0, 8192].iter().cycle().take(4000) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        std::fs::write(&data_path, bytes).unwrap();
+        std::fs::write(
+            &meta_path,
+            r#"{"global": {"core:datatype": "ci16_le", "core:sample_rate": 100000, "core:version": "1.0.0"},
+               "captures": [], "annotations": []}"#,
+        )
+        .unwrap();
+
+        let mut source = FileSource::open(&meta_path).unwrap();
+        assert_eq!(source.len(), 2000);
+
+        let start = Instant::now();
+        let mut buf = vec![Complex64::default(); 2000];
+        assert_eq!(source.receive(&mut buf).unwrap(), 2000);
+        assert!(start.elapsed() >= Duration::from_millis(19));
+        assert_eq!(buf[0], Complex64::new(0.5, -1.0));
+        assert_eq!(buf[1], Complex64::new(0.0, 0.25));
+
+        std::fs::remove_file(&data_path).unwrap();
+        std::fs::remove_file(&meta_path).unwrap();
+    }
+
+    #[test]
+    fn the_noise_floor_is_repeatable() {
+        let silence = vec![Complex64::default(); 20_000];
+        let play = |seed| {
+            let mut source = FileSource::from_samples(silence.clone()).noise_floor(-20.0, seed);
+            let mut buf = vec![Complex64::default(); silence.len()];
+            source.receive(&mut buf).unwrap();
+            buf
+        };
+
+        let noise = play(3);
+        let power = noise.iter().map(|s| s.norm_sqr()).sum::<f64>() / noise.len() as f64;
+        assert!((power - 0.01).abs() < 0.001, "{}", power);
+        assert_eq!(noise, play(3));
+        assert_ne!(noise, play(4));
+    }
+}
@@ -1,5 +1,6 @@
 use num::complex::Complex64;
 
+use crate::report::RxReport;
 use crate::utils;
 use crate::{packets::Header, plots};
 use crate::{plots::stem_plot, signals::*, transmitter};
@@ -7,14 +8,74 @@ use transmitter::ModulationScheme;
 
 #[optargs::optfn]
 pub fn decode(
-    mut samples: Vec<num::complex::Complex64>,
+    samples: Vec<num::complex::Complex64>,
     guard_bands: Option<bool>,
     modulation: Option<crate::ModulationScheme>,
-) -> anyhow::Result<Vec<u8>> {
+) -> anyhow::Result<(Vec<u8>, RxReport)> {
     log::debug!("Decoding...");
 
     let guard_bands = guard_bands.unwrap_or_else(|| false);
+    let modulation = modulation.unwrap_or(ModulationScheme::Bpsk);
 
+    let synced = synchronize(samples)?;
+
+    let equalized = equalize(&synced.chunks, guard_bands);
+
+    //  Demodulate the output stream into bytes
+    let decoded = demodulate(equalized.symbols.clone(), modulation);
+
+    let payload = deframe(decoded);
+
+    // Only measure the symbols that carried the header and payload, the rest is padding
+    let header_len = bincode::serialized_size(&Header { packet_length: 0 }).unwrap() as usize;
+    let data_symbols = (header_len + payload.len()) * 8 / modulation.bits_per_symbol();
+    let report = RxReport::new(&synced, &equalized, guard_bands, modulation, |idx| {
+        idx < data_symbols
+    });
+
+    Ok((payload, report))
+}
+
+/// Decode a transmission into log likelihood ratios instead of bytes, one for every bit after
+/// the header, positive for a one
+///
+/// The header isn't protected by any code, so the caller works out how many of them to use.
+#[optargs::optfn]
+pub fn decode_soft(
+    samples: Vec<num::complex::Complex64>,
+    guard_bands: Option<bool>,
+    modulation: Option<crate::ModulationScheme>,
+) -> anyhow::Result<Vec<f64>> {
+    let guard_bands = guard_bands.unwrap_or(false);
+    let modulation = modulation.unwrap_or(ModulationScheme::Bpsk);
+
+    let synced = synchronize(samples)?;
+    let equalized = equalize(&synced.chunks, guard_bands);
+
+    // The hard decisions are enough to read the header, which says how much of the transmission
+    // is padding
+    let payload = deframe(demodulate(equalized.symbols.clone(), modulation));
+    let header_len = bincode::serialized_size(&Header { packet_length: 0 }).unwrap() as usize;
+    let data_symbols = (header_len + payload.len()) * 8 / modulation.bits_per_symbol();
+
+    let mut llrs = soft_demodulate(&equalized, guard_bands, modulation, data_symbols);
+    Ok(llrs.split_off((header_len * 8).min(llrs.len())))
+}
+
+/// A transmission that has been located in the sample stream and frequency corrected
+pub struct Synchronized {
+    /// Every 80 sample block of the transmission, starting at the locking signal
+    pub chunks: Vec<[Complex64; 80]>,
+
+    /// Where in the input the transmission started
+    pub offset: usize,
+
+    /// The frequency offset that was removed, in radians per sample
+    pub f_delta: f64,
+}
+
+/// Find the start of the transmission and remove the carrier frequency offset
+pub fn synchronize(mut samples: Vec<Complex64>) -> anyhow::Result<Synchronized> {
     // hardcode the delay from the signal
     // we should do this with xcorr but that's a bit slow, unfortunately
     let (idxmax, cross) = samples.xcorr_fft(transmitter::locking_signal::<80>());
@@ -22,6 +83,12 @@ pub fn decode(
 
     // plots::stem_plot(&cross);
 
+    // A peak in the negative lags means the locking signal was cut off at the start
+    if offset < 0 {
+        return Err(anyhow::anyhow!(
+            "Transmission starts before the input, bailing early"
+        ));
+    }
     let samples = samples.split_off(offset as usize);
 
     if samples.len() < 800 {
@@ -41,25 +108,57 @@ pub fn decode(
     utils::write_to_numpy_file(&chunks[6], "preq_correction_3a");
 
     // Apply the frequency offset
+    correct_frequency(&mut chunks, f_delta);
+
+    utils::write_to_numpy_file(&chunks[6], "post_correction_3a");
+
+    Ok(Synchronized {
+        chunks,
+        offset: offset as usize,
+        f_delta,
+    })
+}
+
+/// Remove a frequency offset from blocks that start at the beginning of the transmission
+pub fn correct_frequency(chunks: &mut [[Complex64; 80]], f_delta: f64) {
     let mut sample_id = 0;
-    for chunk in &mut chunks {
-        for sample in chunk {
+    for chunk in chunks.iter_mut() {
+        for sample in chunk.iter_mut() {
             *sample *= (Complex64::new(0.0, -1.0) * f_delta * (sample_id as f64)).exp();
             sample_id += 1;
         }
     }
+}
 
-    utils::write_to_numpy_file(&chunks[6], "post_correction_3a");
+/// Line up another receive chain with a transmission found by `synchronize`. Chains that share
+/// a clock and local oscillator see the same timing and frequency offset, so one search covers
+/// all of them.
+pub fn align(
+    mut samples: Vec<Complex64>,
+    synced: &Synchronized,
+) -> anyhow::Result<Vec<[Complex64; 80]>> {
+    if samples.len() < synced.offset + 800 {
+        return Err(anyhow::anyhow!("Input not long enough, bailing early"));
+    }
+
+    let mut chunks = split_into_chunks(samples.split_off(synced.offset));
+    correct_frequency(&mut chunks, synced.f_delta);
+    Ok(chunks)
+}
 
-    assert_eq!(sample_id, chunks.len() * 80);
+/// The data subcarriers of every block after channel and phase correction
+pub struct Equalized {
+    pub symbols: Vec<Complex64>,
+    pub h_k: [Complex64; 64],
+}
 
+/// Estimate the channel from the training blocks and undo it on every data block
+pub fn equalize(chunks: &[[Complex64; 80]], guard_bands: bool) -> Equalized {
     let h_k = estimate_channel(&chunks[5..10]);
 
     utils::write_to_numpy_file(&h_k, "hk_estimate_3a");
     // stem_plot(&h_k);
 
-    dbg!(&f_delta);
-
     let mut out_stream = Vec::new();
     for chunk in &chunks[10..] {
         let mut unprefixed = unprefix_block(chunk);
@@ -79,12 +178,23 @@ pub fn decode(
 
     // plots::constellation(&out_stream[..230 * 8]);
 
-    //  Demodulate the output stream into bytes
-    let mut decoded = demodulate(out_stream, modulation.unwrap_or(ModulationScheme::Bpsk));
+    Equalized {
+        symbols: out_stream,
+        h_k,
+    }
+}
 
+/// Parse the header off the demodulated bytes and trim the payload to its length
+pub fn deframe(mut decoded: Vec<u8>) -> Vec<u8> {
     // Parse off the header
     let header_len =
         bincode::serialized_size(&crate::packets::Header { packet_length: 0 }).unwrap();
+
+    // A capture cut off right after the training blocks has no header to read
+    if decoded.len() < header_len as usize {
+        return Vec::new();
+    }
+
     let header_bytes = decoded.drain(0..header_len as usize).collect::<Vec<_>>();
     let header: Header = bincode::deserialize(&header_bytes).unwrap();
 
@@ -92,7 +202,7 @@ pub fn decode(
     // In the future, we'll want to do this earlier to not waste computation
     decoded.truncate(header.packet_length as usize);
 
-    Ok(decoded)
+    decoded
 }
 
 /// Remove the cyclic prefix and then write into the buffer
@@ -111,19 +221,21 @@ pub fn decode_block(
 ) {
     let mut input_iter = std::array::IntoIter::new(input).enumerate();
 
-    let pilot_count: f64 = 4.0;
-
-    let mut phase_offset: f64 = 0.0;
+    // Averaging the pilots as unit phasors rather than angles keeps a phase near ±pi from
+    // wrapping around to zero
+    let mut pilot_sum = Complex64::default();
     let mut samples_counted = 0;
 
     while let Some((i, next)) = input_iter.next() {
         match i {
             // dc offset, sidebands, just skip
-            i if guard_bands && (i >= 59 || i <= 5 || i == 32) => {}
+            i if transmitter::is_null_subcarrier(i, guard_bands) => {}
 
             // pilot tones
-            i if guard_bands && (i == 6 || i == 25 || i == 39 || i == 58) => {
-                phase_offset = phase_offset + angle(input[i] / Complex64::new(1.0, 0.0));
+            i if transmitter::is_pilot_subcarrier(i, guard_bands) => {
+                if input[i].norm() > 0.0 {
+                    pilot_sum += input[i] / input[i].norm();
+                }
                 // phase_offset = phase_offset + angle(input[i] / hk[i]);
             }
 
@@ -134,7 +246,7 @@ pub fn decode_block(
         }
     }
 
-    phase_offset /= pilot_count as f64;
+    let phase_offset = angle(pilot_sum);
 
     // go back to all the samples we pushed in and correct them retroactively.
     output
@@ -189,6 +301,52 @@ pub fn demodulate(stream: Vec<Complex64>, scheme: ModulationScheme) -> Vec<u8> {
     out
 }
 
+/// Log likelihood ratios for every bit of the equalized symbols, positive for a one
+///
+/// The noise is measured on every subcarrier from how far its symbols land from the nearest
+/// constellation point. That picks up fades, residual phase and anything else the training
+/// blocks can't see, so a bad transmission doesn't claim to be sure of its bits. Only the first
+/// `data_symbols` are measured, the padding after them is nowhere near the constellation.
+pub fn soft_demodulate(
+    equalized: &Equalized,
+    guard_bands: bool,
+    scheme: ModulationScheme,
+    data_symbols: usize,
+) -> Vec<f64> {
+    let subcarriers = transmitter::data_subcarriers(guard_bands).len();
+    let nearest = |symbol: &Complex64| match scheme {
+        ModulationScheme::Bpsk => Complex64::new(symbol.re.signum(), 0.0),
+        _ => Complex64::new(symbol.re.signum(), symbol.im.signum()),
+    };
+
+    let mut noise = vec![0.0; subcarriers];
+    let mut counts = vec![0; subcarriers];
+    for (idx, symbol) in equalized.symbols.iter().take(data_symbols).enumerate() {
+        noise[idx % subcarriers] += (symbol - nearest(symbol)).norm_sqr();
+        counts[idx % subcarriers] += 1;
+    }
+    for (n, &count) in noise.iter_mut().zip(counts.iter()) {
+        *n = (*n / count.max(1) as f64).max(f64::MIN_POSITIVE);
+    }
+
+    let mut out = Vec::with_capacity(equalized.symbols.len() * scheme.bits_per_symbol());
+    for (idx, symbol) in equalized.symbols.iter().enumerate() {
+        // Half the noise lands on each of the real and imaginary parts
+        let scale = 4.0 / noise[idx % subcarriers];
+
+        match scheme {
+            ModulationScheme::Bpsk => out.push(scale * symbol.re),
+            ModulationScheme::Qpsk => {
+                out.push(scale * symbol.re);
+                out.push(scale * symbol.im);
+            }
+            ModulationScheme::Qam => {}
+        }
+    }
+
+    out
+}
+
 pub fn split_into_chunks(samples: Vec<Complex64>) -> Vec<[Complex64; 80]> {
     let mut samples = samples.into_boxed_slice();
 
@@ -248,6 +406,7 @@ fn angle(Complex64 { re, im }: Complex64) -> f64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::f64::consts::PI;
 
     #[test]
     fn angle_is_ok() {
@@ -282,6 +441,56 @@ mod tests {
         dbg!(angle(a / b));
     }
 
+    #[test]
+    fn pilots_near_pi_do_not_flip_the_block() {
+        // A block turned all the way around, with the pilots landing either side of ±pi. Their
+        // angles average out to zero, which would leave every symbol upside down.
+        let rotation = |phase: f64| (Complex64::new(0.0, 1.0) * phase).exp();
+        let mut block = [Complex64::default(); 64];
+        for (i, sample) in block.iter_mut().enumerate() {
+            *sample = match i {
+                i if transmitter::is_null_subcarrier(i, true) => Complex64::default(),
+                6 | 25 => rotation(PI - 0.05),
+                39 | 58 => rotation(-PI + 0.05),
+                _ => rotation(PI),
+            };
+        }
+
+        let mut output = Vec::new();
+        decode_block(block, &[Complex64::new(1.0, 0.0); 64], true, &mut output);
+
+        assert_eq!(output.len(), 48);
+        for symbol in output {
+            assert!(
+                (symbol - Complex64::new(1.0, 0.0)).norm() < 1e-9,
+                "{}",
+                symbol
+            );
+        }
+    }
+
+    #[test]
+    fn padding_is_left_out_of_the_noise() {
+        // Two blocks of slightly noisy symbols, then a long run of silence
+        let mut symbols = (0..96)
+            .map(|idx| Complex64::new(if idx % 2 == 0 { 1.1 } else { -0.9 }, 0.0))
+            .collect::<Vec<_>>();
+        symbols.resize(96 * 6, Complex64::default());
+        let equalized = Equalized {
+            symbols,
+            h_k: [Complex64::new(1.0, 0.0); 64],
+        };
+
+        // The symbols are 0.1 off, so the noise is 0.01 and the ratios are around 400
+        let llrs = soft_demodulate(&equalized, true, ModulationScheme::Bpsk, 96);
+        assert!(
+            llrs[..96].iter().all(|llr| llr.abs() > 300.0),
+            "{:?}",
+            &llrs[..4]
+        );
+        assert!(llrs[0] > 0.0 && llrs[1] < 0.0);
+    }
+
     #[test]
     fn lags() {
         let samples = [1, 2, 3, 4, 5, 6].to_signal();
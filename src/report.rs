@@ -0,0 +1,223 @@
+//! Link quality measurements taken from every decoded packet.
+//!
+//! Unlike `utils::Analysis`, nothing here needs to know what was sent. EVM and MER compare the
+//! equalized constellation against the nearest ideal points, and the SNR comes from how much the
+//! channel estimate wanders between the five identical training blocks.
+
+use std::fmt;
+
+use num::complex::Complex64;
+
+use crate::receiver::{unprefix_block, Equalized, Synchronized};
+use crate::signals::*;
+use crate::transmitter::{self, ModulationScheme};
+
+#[derive(Debug, Clone)]
+pub struct RxReport {
+    /// RMS error vector magnitude over every data symbol, as a fraction of the rms constellation
+    /// amplitude
+    pub evm_rms: f64,
+
+    /// RMS EVM of each subcarrier on its own. Zero for subcarriers that carry no data.
+    pub evm_per_subcarrier: [f64; 64],
+
+    /// Modulation error ratio in dB: constellation power over error vector power
+    pub mer_db: f64,
+
+    /// Average SNR over the used subcarriers, estimated from the training blocks
+    pub snr_db: f64,
+
+    /// SNR of every subcarrier, estimated from the training blocks
+    pub snr_per_subcarrier_db: [f64; 64],
+
+    /// Carrier frequency offset that was removed, in radians per sample
+    pub cfo: f64,
+
+    /// Where in the input samples the transmission started
+    pub timing_offset: usize,
+
+    /// |h_k| of the channel estimate
+    pub channel_magnitude: [f64; 64],
+}
+
+impl RxReport {
+    /// Measure a decoded packet. Only the equalized symbols that `carried` says held data are
+    /// used, since padding sits wherever a frame runs out of data.
+    pub fn new(
+        synced: &Synchronized,
+        equalized: &Equalized,
+        guard_bands: bool,
+        modulation: ModulationScheme,
+        carried: impl Fn(usize) -> bool,
+    ) -> Self {
+        let subcarriers = transmitter::data_subcarriers(guard_bands);
+
+        let mut error_power = [0.0; 64];
+        let mut reference_power = [0.0; 64];
+        let mut counts = [0usize; 64];
+
+        let symbols = equalized.symbols.iter().enumerate();
+        for (idx, symbol) in symbols.filter(|(idx, _)| carried(*idx)) {
+            let ideal = nearest_point(*symbol, modulation);
+            let k = subcarriers[idx % subcarriers.len()];
+            error_power[k] += (symbol - ideal).norm_sqr();
+            reference_power[k] += ideal.norm_sqr();
+            counts[k] += 1;
+        }
+
+        let mut evm_per_subcarrier = [0.0; 64];
+        for k in subcarriers.iter().copied().filter(|&k| counts[k] > 0) {
+            evm_per_subcarrier[k] = (error_power[k] / reference_power[k]).sqrt();
+        }
+
+        let total_error = error_power.iter().sum::<f64>();
+        let total_reference = reference_power.iter().sum::<f64>();
+
+        let (snr_db, snr_per_subcarrier_db) =
+            training_snr(&synced.chunks[5..10], &equalized.h_k, guard_bands);
+
+        Self {
+            evm_rms: (total_error / total_reference).sqrt(),
+            evm_per_subcarrier,
+            mer_db: to_db(total_reference / total_error),
+            snr_db,
+            snr_per_subcarrier_db,
+            cfo: synced.f_delta,
+            timing_offset: synced.offset,
+            channel_magnitude: {
+                let mut magnitude = [0.0; 64];
+                for (m, h) in magnitude.iter_mut().zip(equalized.h_k.iter()) {
+                    *m = h.norm();
+                }
+                magnitude
+            },
+        }
+    }
+
+    pub fn evm_percent(&self) -> f64 {
+        self.evm_rms * 100.0
+    }
+
+    pub fn evm_db(&self) -> f64 {
+        20.0 * self.evm_rms.log10()
+    }
+
+    /// The carrier frequency offset in hz, given the rate the samples were taken at
+    pub fn cfo_hz(&self, sample_rate: f64) -> f64 {
+        self.cfo * sample_rate / (2.0 * std::f64::consts::PI)
+    }
+}
+
+impl fmt::Display for RxReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EVM {:.2}% ({:.1}dB), MER {:.1}dB, SNR {:.1}dB, CFO {:.5} rad/sample, offset {}",
+            self.evm_percent(),
+            self.evm_db(),
+            self.mer_db,
+            self.snr_db,
+            self.cfo,
+            self.timing_offset
+        )
+    }
+}
+
+/// The ideal constellation point closest to the sample
+fn nearest_point(sample: Complex64, modulation: ModulationScheme) -> Complex64 {
+    let sign = |v: f64| if v >= 0.0 { 1.0 } else { -1.0 };
+
+    // 16 qam sits on a grid of -3, -1, 1, 3
+    let qam_level = |v: f64| (((v + 3.0) / 2.0).round().max(0.0).min(3.0)) * 2.0 - 3.0;
+
+    match modulation {
+        ModulationScheme::Bpsk => Complex64::new(sign(sample.re), 0.0),
+        ModulationScheme::Qpsk => Complex64::new(sign(sample.re), sign(sample.im)),
+        ModulationScheme::Qam => Complex64::new(qam_level(sample.re), qam_level(sample.im)),
+    }
+}
+
+/// Each training block gives its own estimate of the channel. They all see the same channel, so
+/// how far they spread around their mean is down to noise.
+fn training_snr(
+    training_blocks: &[[Complex64; 80]],
+    h_k: &[Complex64; 64],
+    guard_bands: bool,
+) -> (f64, [f64; 64]) {
+    let training = transmitter::training_signals::<80>();
+
+    let mut noise = [0.0; 64];
+    for block in training_blocks.iter() {
+        let mut estimate = unprefix_block(block);
+        estimate.div_by_other(&training);
+        for (n, (e, h)) in noise.iter_mut().zip(estimate.iter().zip(h_k.iter())) {
+            *n += (e - h).norm_sqr();
+        }
+    }
+
+    // The mean soaks up one block's worth of the noise
+    let degrees_of_freedom = (training_blocks.len() - 1) as f64;
+    noise.iter_mut().for_each(|n| *n /= degrees_of_freedom);
+
+    let mut per_subcarrier = [0.0; 64];
+    for (snr, (h, n)) in per_subcarrier.iter_mut().zip(h_k.iter().zip(noise.iter())) {
+        *snr = to_db(h.norm_sqr() / n);
+    }
+
+    let used = (0..64).filter(|&k| !transmitter::is_null_subcarrier(k, guard_bands));
+    let (signal, noise) = used.fold((0.0, 0.0), |(s, n), k| {
+        (s + h_k[k].norm_sqr(), n + noise[k])
+    });
+
+    (to_db(signal / noise), per_subcarrier)
+}
+
+fn to_db(ratio: f64) -> f64 {
+    10.0 * ratio.max(f64::MIN_POSITIVE).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback(snr: f64) -> RxReport {
+        let data = crate::utils::create_transmission_text(500, false);
+        let samples = crate::encode(&data, Some(true), None, None, None);
+        let received = crate::channel(samples, Some(snr), None, None, Some(1));
+
+        let (_, report) = crate::decode(received, Some(true), None).unwrap();
+        report
+    }
+
+    #[test]
+    fn report_tracks_the_noise() {
+        let clean = loopback(40.0);
+        let noisy = loopback(20.0);
+
+        assert!(clean.evm_rms < noisy.evm_rms, "{}\n{}", clean, noisy);
+        assert!(clean.mer_db > noisy.mer_db + 5.0, "{}\n{}", clean, noisy);
+        assert!(clean.snr_db > noisy.snr_db + 5.0, "{}\n{}", clean, noisy);
+        assert!((clean.mer_db + clean.evm_db()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn nearest_points() {
+        let point = Complex64::new(0.7, -2.4);
+        assert_eq!(
+            nearest_point(point, ModulationScheme::Bpsk),
+            Complex64::new(1.0, 0.0)
+        );
+        assert_eq!(
+            nearest_point(point, ModulationScheme::Qpsk),
+            Complex64::new(1.0, -1.0)
+        );
+        assert_eq!(
+            nearest_point(point, ModulationScheme::Qam),
+            Complex64::new(1.0, -3.0)
+        );
+        assert_eq!(
+            nearest_point(Complex64::new(9.0, -0.1), ModulationScheme::Qam),
+            Complex64::new(3.0, -1.0)
+        );
+    }
+}
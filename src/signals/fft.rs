@@ -0,0 +1,133 @@
+//! Reusable fft plans and scratch space.
+//!
+//! Building an `FftPlanner` and planning a transform is far more expensive than running a 64
+//! point fft, so every thread keeps one `FftContext` around and the signal traits borrow it.
+//! Anything that wants to manage its own context (like a worker thread that wants to be explicit
+//! about it) can make one with `FftContext::new` and call it directly.
+
+use std::cell::RefCell;
+
+use num::complex::Complex64;
+use rustfft::FftPlanner;
+
+thread_local! {
+    static CONTEXT: RefCell<FftContext> = RefCell::new(FftContext::new());
+}
+
+pub struct FftContext {
+    // The planner caches every plan it has made, so asking again for the same size is cheap
+    planner: FftPlanner<f64>,
+    scratch: Vec<Complex64>,
+    work: Vec<Complex64>,
+}
+
+impl Default for FftContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FftContext {
+    pub fn new() -> Self {
+        Self {
+            planner: FftPlanner::new(),
+            scratch: Vec::new(),
+            work: Vec::new(),
+        }
+    }
+
+    /// Run a closure with this thread's shared context
+    pub fn with_thread_local<R>(f: impl FnOnce(&mut FftContext) -> R) -> R {
+        CONTEXT.with(|ctx| f(&mut ctx.borrow_mut()))
+    }
+
+    /// Forward ffts of size `len` over the buffer, in place
+    pub fn fft_len(&mut self, buffer: &mut [Complex64], len: usize) {
+        let fft = self.planner.plan_fft_forward(len);
+        self.scratch
+            .resize(fft.get_inplace_scratch_len(), Complex64::default());
+        fft.process_with_scratch(buffer, &mut self.scratch);
+    }
+
+    /// Inverse ffts of size `len` over the buffer, in place, normalized by 1/len like Matlab
+    pub fn ifft_len(&mut self, buffer: &mut [Complex64], len: usize) {
+        let fft = self.planner.plan_fft_inverse(len);
+        self.scratch
+            .resize(fft.get_inplace_scratch_len(), Complex64::default());
+        fft.process_with_scratch(buffer, &mut self.scratch);
+
+        let scale = 1.0 / len as f64;
+        for val in buffer.iter_mut() {
+            *val *= scale;
+        }
+    }
+
+    /// Multiply the spectrum of `buffer` by the spectrum of `other` zero padded to the same
+    /// length, optionally conjugating `other`'s spectrum first, and return to the time domain.
+    ///
+    /// This is the core of both fft convolution and correlation. `other` is copied into a work
+    /// buffer owned by the context, so nothing besides `buffer` gets allocated.
+    pub fn spectral_product(
+        &mut self,
+        buffer: &mut [Complex64],
+        other: &[Complex64],
+        conjugate_other: bool,
+    ) {
+        let len = buffer.len();
+
+        let mut work = std::mem::take(&mut self.work);
+        work.clear();
+        work.extend_from_slice(other);
+        work.resize(len, Complex64::default());
+
+        self.fft_len(buffer, len);
+        self.fft_len(&mut work, len);
+
+        for (l, r) in buffer.iter_mut().zip(work.iter()) {
+            *l *= match conjugate_other {
+                true => r.conj(),
+                false => *r,
+            };
+        }
+
+        self.ifft_len(buffer, len);
+        self.work = work;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signals::*;
+
+    #[test]
+    fn context_matches_a_fresh_planner() {
+        let mut expected = (0..64)
+            .map(|i: i32| (i % 5, i % 3))
+            .collect::<Vec<_>>()
+            .to_signal();
+        let mut actual = expected.clone();
+
+        rustfft::FftPlanner::new()
+            .plan_fft_forward(64)
+            .process(&mut expected);
+        FftContext::new().fft_len(&mut actual, 64);
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = (0..80)
+            .map(|i: i32| (i % 7, -(i % 4)))
+            .collect::<Vec<_>>()
+            .to_signal();
+
+        let mut sig = original.clone();
+        sig.fft().ifft();
+
+        for (l, r) in sig.iter().zip(original.iter()) {
+            assert!((l - r).norm() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Stateful FIR filtering for streams of samples.
+//!
+//! `SignalRef::convolve` needs the whole signal up front. `FirFilter` instead takes samples a
+//! chunk at a time and remembers the tail of the previous chunk, so chunks fed through one after
+//! another come out exactly like one long convolution. Short kernels are run directly, long
+//! kernels use overlap-save with ffts from the thread's `FftContext`.
+
+use num::complex::Complex64;
+
+use super::FftContext;
+
+/// Kernels at or under this length are cheaper to run directly than through an fft
+const DIRECT_MAX_TAPS: usize = 32;
+
+pub struct FirFilter {
+    taps: Vec<Complex64>,
+
+    // The last taps.len() - 1 inputs
+    history: Vec<Complex64>,
+
+    // Only used by overlap-save
+    fft_len: usize,
+    taps_fft: Vec<Complex64>,
+    segment: Vec<Complex64>,
+}
+
+impl FirFilter {
+    pub fn new(taps: impl AsRef<[Complex64]>) -> Self {
+        let taps = taps.as_ref().to_vec();
+        assert!(!taps.is_empty(), "a filter needs at least one tap");
+
+        let (fft_len, taps_fft) = match taps.len() > DIRECT_MAX_TAPS {
+            true => {
+                // Make each fft do a good chunk of work per tap of overlap
+                let fft_len = (4 * taps.len()).next_power_of_two();
+                let mut taps_fft = taps.clone();
+                taps_fft.resize(fft_len, Complex64::default());
+                FftContext::with_thread_local(|ctx| ctx.fft_len(&mut taps_fft, fft_len));
+                (fft_len, taps_fft)
+            }
+            false => (0, Vec::new()),
+        };
+
+        Self {
+            history: vec![Complex64::default(); taps.len() - 1],
+            taps,
+            fft_len,
+            taps_fft,
+            segment: Vec::new(),
+        }
+    }
+
+    /// Build a filter from real valued taps
+    pub fn from_real(taps: &[f64]) -> Self {
+        Self::new(
+            taps.iter()
+                .map(|&t| Complex64::new(t, 0.0))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    pub fn taps(&self) -> &[Complex64] {
+        &self.taps
+    }
+
+    /// Forget everything that has been seen so far
+    pub fn reset(&mut self) {
+        self.history.fill(Complex64::default());
+    }
+
+    /// Filter the next chunk of the stream. The output is always the same length as the input.
+    pub fn process(&mut self, input: &[Complex64]) -> Vec<Complex64> {
+        let overlap = self.history.len();
+
+        let mut buffer = Vec::with_capacity(overlap + input.len());
+        buffer.extend_from_slice(&self.history);
+        buffer.extend_from_slice(input);
+
+        let output = match self.fft_len {
+            0 => self.direct(&buffer, input.len()),
+            _ => self.overlap_save(&buffer, input.len()),
+        };
+
+        self.history
+            .copy_from_slice(&buffer[buffer.len() - overlap..]);
+
+        output
+    }
+
+    fn direct(&self, buffer: &[Complex64], len: usize) -> Vec<Complex64> {
+        let overlap = self.taps.len() - 1;
+        (0..len)
+            .map(|n| {
+                self.taps
+                    .iter()
+                    .enumerate()
+                    .map(|(k, h)| h * buffer[overlap + n - k])
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn overlap_save(&mut self, buffer: &[Complex64], len: usize) -> Vec<Complex64> {
+        let overlap = self.taps.len() - 1;
+        let step = self.fft_len - overlap;
+
+        let mut output = Vec::with_capacity(len);
+        let mut start = 0;
+        while output.len() < len {
+            let end = (start + self.fft_len).min(buffer.len());
+
+            self.segment.clear();
+            self.segment.extend_from_slice(&buffer[start..end]);
+            self.segment.resize(self.fft_len, Complex64::default());
+
+            let (fft_len, taps_fft, segment) = (self.fft_len, &self.taps_fft, &mut self.segment);
+            FftContext::with_thread_local(|ctx| {
+                ctx.fft_len(segment, fft_len);
+                for (s, h) in segment.iter_mut().zip(taps_fft.iter()) {
+                    *s *= h;
+                }
+                ctx.ifft_len(segment, fft_len);
+            });
+
+            // The first `overlap` outputs are corrupted by circular wraparound
+            let valid = step.min(len - output.len());
+            output.extend_from_slice(&self.segment[overlap..overlap + valid]);
+            start += step;
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signals::*;
+
+    fn ramp(len: usize) -> Vec<Complex64> {
+        (0..len)
+            .map(|i| Complex64::new((i % 13) as f64 - 6.0, (i % 5) as f64))
+            .collect()
+    }
+
+    fn check_streaming(num_taps: usize) {
+        let taps = ramp(num_taps)
+            .into_iter()
+            .map(|t| t * 0.1)
+            .collect::<Vec<_>>();
+        let input = ramp(1000);
+        let expected = input.convolve(&taps);
+
+        let mut filter = FirFilter::new(&taps);
+        let mut output = Vec::new();
+        for chunk in [1, 7, 100, 333, 559].iter().scan(0, |start, &len| {
+            let chunk = &input[*start..*start + len];
+            *start += len;
+            Some(chunk)
+        }) {
+            output.extend(filter.process(chunk));
+        }
+
+        assert_eq!(output.len(), input.len());
+        for (l, r) in output.iter().zip(expected.iter()) {
+            assert!((l - r).norm() < 1e-9, "{} != {}", l, r);
+        }
+    }
+
+    #[test]
+    fn short_kernels_stream() {
+        check_streaming(1);
+        check_streaming(5);
+    }
+
+    #[test]
+    fn long_kernels_stream() {
+        check_streaming(33);
+        check_streaming(129);
+    }
+}
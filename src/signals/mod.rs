@@ -6,6 +6,15 @@ use num::complex::Complex64;
 mod impls;
 pub use impls::*;
 
+mod fft;
+pub use fft::*;
+
+mod fir;
+pub use fir::*;
+
+mod resample;
+pub use resample::*;
+
 // Blanket implement our signal traits for anything that can be casted as a Slice of Complex
 // This enables both primitives and custom wrapper types
 impl<T: AsRef<[Complex64]>> SignalRef for T {}
@@ -39,21 +48,15 @@ pub trait SignalMut: AsMut<[Complex64]> + AsRef<[Complex64]> {
 
     // FFT but with a custom len parameter
     fn fft_len(&mut self, len: usize) -> &mut Self {
-        rustfft::FftPlanner::new()
-            .plan_fft_forward(len)
-            .process(self.as_mut());
+        FftContext::with_thread_local(|ctx| ctx.fft_len(self.as_mut(), len));
         self
     }
 
     /// ifft but with a custom len
     fn ifft_len(&mut self, len: usize) -> &mut Self {
-        rustfft::FftPlanner::new()
-            .plan_fft_inverse(len)
-            .process(self.as_mut());
-
         // Matlab expects a normalization of the ifft
         // perhaps we shouldn't?
-        self.normalize_by(1.0 / len as f64);
+        FftContext::with_thread_local(|ctx| ctx.ifft_len(self.as_mut(), len));
         self
     }
 
@@ -64,15 +67,7 @@ pub trait SignalMut: AsMut<[Complex64]> + AsRef<[Complex64]> {
         let len = signal.len();
         let mid = (len as f64 + 1.0) / 2 as f64;
 
-        let mut samples = vec![Complex64::default(); len];
-        samples.copy_from_slice(signal);
-        let (l, r) = samples.split_at_mut(mid.floor() as usize);
-
-        r.iter()
-            .chain(l.iter())
-            .zip(signal.iter_mut())
-            .for_each(|(n, o)| *o = *n);
-
+        signal.rotate_left(mid.floor() as usize);
         self
     }
 
@@ -83,14 +78,7 @@ pub trait SignalMut: AsMut<[Complex64]> + AsRef<[Complex64]> {
         let len = signal.len();
         let mid = (len as f64) / 2 as f64;
 
-        let mut samples = vec![Complex64::default(); len];
-        samples.copy_from_slice(signal);
-        let (l, r) = samples.split_at_mut(mid.floor() as usize);
-
-        r.iter()
-            .chain(l.iter())
-            .zip(signal.iter_mut())
-            .for_each(|(n, o)| *o = *n);
+        signal.rotate_left(mid.floor() as usize);
         self
     }
 
@@ -184,21 +172,19 @@ pub trait SignalRef: AsRef<[Complex64]> {
     // }
 
     fn xcorr_fft(&self, other: impl AsRef<[Complex64]>) -> (usize, SignalVec) {
-        let mut a = self.as_ref().to_vec();
-        let mut b = other.as_ref().to_vec();
-
-        let a_len = a.len();
-        let b_len = b.len();
+        let a_len = self.as_ref().len();
 
         // http://matlab.izmiran.ru/help/toolbox/signal/xcorr.html
         let pad_to = 2 * a_len - 1;
-        a.extend(vec![Complex64::default(); pad_to - a_len].into_iter());
-        b.extend(vec![Complex64::default(); pad_to - b_len].into_iter());
+        let mut a = Vec::with_capacity(pad_to);
+        a.extend_from_slice(self.as_ref());
+        a.resize(pad_to, Complex64::default());
 
         // https://stackoverflow.com/questions/7396814/cross-correlation-in-matlab-without-using-the-inbuilt-function
         // The xcorr of two values is the product of the ffts where one is conjugated
         // It's a bit dense :x
-        a.fft().mul_by_other(b.fft().conj()).ifft().fft_shift();
+        FftContext::with_thread_local(|ctx| ctx.spectral_product(&mut a, other.as_ref(), true));
+        a.fft_shift();
 
         let out = a;
 
@@ -217,21 +203,18 @@ pub trait SignalRef: AsRef<[Complex64]> {
     }
 
     fn convolve(&self, kernel: impl AsRef<[Complex64]>) -> SignalVec {
-        let mut a = self.as_ref().to_vec();
-        let mut b = kernel.as_ref().to_vec();
-
-        let a_len = a.len();
-        let b_len = b.len();
+        let a_len = self.as_ref().len();
+        let b_len = kernel.as_ref().len();
 
         // http://matlab.izmiran.ru/help/toolbox/signal/xcorr.html
         let pad_to = a_len + b_len - 1;
-        a.extend(vec![Complex64::default(); pad_to - a_len].into_iter());
-        b.extend(vec![Complex64::default(); pad_to - b_len].into_iter());
+        let mut a = Vec::with_capacity(pad_to);
+        a.extend_from_slice(self.as_ref());
+        a.resize(pad_to, Complex64::default());
 
         //https://www.mathworks.com/matlabcentral/answers/38066-difference-between-conv-ifft-fft-when-doing-convolution
         // ifft(fft(a, 14) .* fft(b, 14))
-
-        a.fft().mul_by_other(b.fft()).ifft();
+        FftContext::with_thread_local(|ctx| ctx.spectral_product(&mut a, kernel.as_ref(), false));
 
         a
     }
@@ -248,6 +231,33 @@ pub trait SignalRef: AsRef<[Complex64]> {
             / (signal.len() as f64)
     }
 
+    /// Average power of the signal, ie the mean of |x|^2
+    fn power(&self) -> f64 {
+        let signal = self.as_ref();
+        signal.iter().map(|f| f.norm_sqr()).sum::<f64>() / signal.len() as f64
+    }
+
+    /// RMS level relative to full scale, where a full scale complex tone (|x| = 1) is 0 dBFS
+    fn rms_dbfs(&self) -> f64 {
+        10.0 * self.power().log10()
+    }
+
+    /// Level of the largest sample relative to full scale
+    fn peak_dbfs(&self) -> f64 {
+        let peak = self.as_ref().iter().map(|f| f.norm()).fold(0.0, f64::max);
+        20.0 * peak.log10()
+    }
+
+    /// Welch power spectral density with hann windows overlapping by half, fft shifted
+    fn psd(&self, fft_len: usize) -> Vec<f64> {
+        crate::spectrum::welch(
+            self.as_ref(),
+            fft_len,
+            fft_len / 2,
+            crate::filters::Window::Hann,
+        )
+    }
+
     fn mean(&self) -> Complex64 {
         let signal = self.as_ref();
 
@@ -509,4 +519,20 @@ mod tests {
 
         // af_print!("Output", output);
     }
+
+    #[test]
+    fn dbfs_levels() {
+        let full_scale = (0..100)
+            .map(|i| Complex64::from_polar(1.0, i as f64))
+            .collect::<Vec<_>>();
+        assert!(full_scale.rms_dbfs().abs() < 1e-9);
+        assert!(full_scale.peak_dbfs().abs() < 1e-9);
+
+        let mut half = full_scale.clone();
+        half.iter_mut().for_each(|f| *f *= 0.5);
+        half[0] = Complex64::new(1.0, 0.0);
+        assert!((half.peak_dbfs() - 0.0).abs() < 1e-9);
+        let expected = 10.0 * ((99.0 * 0.25 + 1.0) / 100.0_f64).log10();
+        assert!((half.rms_dbfs() - expected).abs() < 1e-9);
+    }
 }
@@ -0,0 +1,321 @@
+//! Sample rate conversion for streams of samples.
+//!
+//! `RationalResampler` changes the rate by an exact ratio L/M with a polyphase filter, which is
+//! what we want for moving recordings between our usual rates (2 MHz down to 1 MHz is 1/2).
+//! `FarrowInterpolator` handles the arbitrary and slowly varying ratios that come up when
+//! correcting a sampling clock offset, where L/M would need absurdly large integers.
+//!
+//! Like `FirFilter`, both keep the tail of the previous chunk so a stream can be fed through
+//! a chunk at a time and come out exactly like one long signal.
+
+use num::complex::Complex64;
+use num::integer::gcd;
+
+use crate::filters::{kaiser_beta, kaiser_num_taps, lowpass, Window};
+
+/// Stopband attenuation of the default anti-aliasing filter
+const ATTENUATION_DB: f64 = 60.0;
+
+pub struct RationalResampler {
+    interpolation: usize,
+    decimation: usize,
+
+    // phases[p][k] = prototype[p + k * interpolation], scaled by the interpolation factor
+    phases: Vec<Vec<f64>>,
+    num_taps: usize,
+
+    // The last taps_per_phase - 1 inputs
+    history: Vec<Complex64>,
+
+    // Which phase and which input (relative to the start of history) the next output uses
+    phase: usize,
+    base: usize,
+}
+
+impl RationalResampler {
+    /// Resample by `interpolation / decimation` with a default Kaiser lowpass that keeps the
+    /// lower 80% of the narrower of the two bands
+    pub fn new(interpolation: usize, decimation: usize) -> Self {
+        let (interpolation, decimation) = reduce(interpolation, decimation);
+        let widest = interpolation.max(decimation) as f64;
+
+        let num_taps = kaiser_num_taps(ATTENUATION_DB, 0.2 / widest);
+        let taps = lowpass(
+            num_taps,
+            0.5 / widest,
+            Window::Kaiser {
+                beta: kaiser_beta(ATTENUATION_DB),
+            },
+        );
+
+        Self::with_taps(interpolation, decimation, &taps)
+    }
+
+    /// Resample between two sample rates, such as `from_rates(2e6, 1e6)`
+    pub fn from_rates(from: f64, to: f64) -> Self {
+        assert!(
+            from.fract() == 0.0 && to.fract() == 0.0,
+            "sample rates must be whole numbers of hz"
+        );
+        Self::new(to as usize, from as usize)
+    }
+
+    /// Resample with a custom prototype filter, designed at `interpolation` times the input rate
+    /// with unity gain at dc
+    pub fn with_taps(interpolation: usize, decimation: usize, taps: &[f64]) -> Self {
+        assert!(
+            interpolation > 0 && decimation > 0,
+            "resampling ratio must be positive"
+        );
+        assert!(!taps.is_empty(), "a filter needs at least one tap");
+
+        let taps_per_phase = (taps.len() + interpolation - 1) / interpolation;
+        let phases = (0..interpolation)
+            .map(|p| {
+                (0..taps_per_phase)
+                    .map(|k| taps.get(p + k * interpolation).copied().unwrap_or(0.0))
+                    .map(|t| t * interpolation as f64)
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            interpolation,
+            decimation,
+            phases,
+            num_taps: taps.len(),
+            history: vec![Complex64::default(); taps_per_phase - 1],
+            phase: 0,
+            base: taps_per_phase - 1,
+        }
+    }
+
+    pub fn ratio(&self) -> (usize, usize) {
+        (self.interpolation, self.decimation)
+    }
+
+    /// How many output samples late the filter makes everything
+    pub fn delay(&self) -> f64 {
+        (self.num_taps - 1) as f64 / 2.0 / self.decimation as f64
+    }
+
+    /// Forget everything that has been seen so far
+    pub fn reset(&mut self) {
+        self.history.fill(Complex64::default());
+        self.phase = 0;
+        self.base = self.history.len();
+    }
+
+    /// Resample the next chunk of the stream. Over a long stream the output is
+    /// `interpolation / decimation` times as long as the input.
+    pub fn process(&mut self, input: &[Complex64]) -> Vec<Complex64> {
+        let overlap = self.history.len();
+
+        let mut buffer = std::mem::take(&mut self.history);
+        buffer.extend_from_slice(input);
+
+        let mut output = Vec::with_capacity(input.len() * self.interpolation / self.decimation + 1);
+        while self.base < buffer.len() {
+            let branch = &self.phases[self.phase];
+            output.push(
+                branch
+                    .iter()
+                    .enumerate()
+                    .map(|(k, h)| buffer[self.base - k] * h)
+                    .sum(),
+            );
+
+            self.phase += self.decimation;
+            self.base += self.phase / self.interpolation;
+            self.phase %= self.interpolation;
+        }
+
+        // Keep the samples the next output still needs
+        let consumed = buffer.len() - overlap;
+        self.history = buffer.split_off(consumed);
+        self.base -= consumed;
+
+        output
+    }
+}
+
+fn reduce(interpolation: usize, decimation: usize) -> (usize, usize) {
+    let divisor = gcd(interpolation, decimation).max(1);
+    (interpolation / divisor, decimation / divisor)
+}
+
+/// Cubic Lagrange interpolation in the Farrow structure
+///
+/// The four neighbouring samples are combined into polynomial coefficients once, and the
+/// fractional position only shows up when evaluating the polynomial. That makes moving the
+/// interpolation point (changing the ratio or the delay) free.
+pub struct FarrowInterpolator {
+    step: f64,
+
+    // Position of the next output sample relative to the start of `history`
+    position: f64,
+    history: Vec<Complex64>,
+}
+
+impl FarrowInterpolator {
+    /// Take one output sample for every `step` input samples. A step over 1 lowers the rate.
+    pub fn new(step: f64) -> Self {
+        assert!(step > 0.0, "the interpolator has to move forwards");
+        Self {
+            step,
+
+            // Start one sample in so there's a sample on either side to interpolate with
+            position: 1.0,
+            history: vec![Complex64::default()],
+        }
+    }
+
+    /// Delay a stream by a fixed fraction of a sample
+    pub fn fractional_delay(delay: f64) -> Self {
+        assert!((0.0..1.0).contains(&delay), "delay must be within a sample");
+        // An extra sample of history keeps a sample behind the very first output
+        Self {
+            step: 1.0,
+            position: 2.0 - delay,
+            history: vec![Complex64::default(); 2],
+        }
+    }
+
+    pub fn step(&self) -> f64 {
+        self.step
+    }
+
+    /// Change the ratio mid-stream, for tracking a drifting clock
+    pub fn set_step(&mut self, step: f64) {
+        assert!(step > 0.0, "the interpolator has to move forwards");
+        self.step = step;
+    }
+
+    /// Nudge the next output by a fraction of an input sample
+    pub fn advance(&mut self, samples: f64) {
+        self.position = (self.position + samples).max(1.0);
+    }
+
+    /// Interpolate the next chunk of the stream
+    pub fn process(&mut self, input: &[Complex64]) -> Vec<Complex64> {
+        let mut buffer = std::mem::take(&mut self.history);
+        buffer.extend_from_slice(input);
+
+        let mut output = Vec::with_capacity((input.len() as f64 / self.step) as usize + 1);
+        while self.position + 2.0 < buffer.len() as f64 {
+            let base = self.position.floor() as usize;
+            let mu = self.position - base as f64;
+            output.push(farrow_cubic(&buffer[base - 1..base + 3], mu));
+            self.position += self.step;
+        }
+
+        // Keep the samples the next output still needs
+        let keep_from = (self.position.floor() as usize - 1).min(buffer.len());
+        self.history = buffer.split_off(keep_from);
+        self.position -= keep_from as f64;
+
+        output
+    }
+}
+
+/// Third order Lagrange interpolation between `points[1]` and `points[2]`
+fn farrow_cubic(points: &[Complex64], mu: f64) -> Complex64 {
+    let [ym1, y0, y1, y2] = [points[0], points[1], points[2], points[3]];
+
+    let c0 = y0;
+    let c1 = -ym1 / 3.0 - y0 / 2.0 + y1 - y2 / 6.0;
+    let c2 = (ym1 + y1) / 2.0 - y0;
+    let c3 = (y0 - y1) / 2.0 + (y2 - ym1) / 6.0;
+
+    ((c3 * mu + c2) * mu + c1) * mu + c0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn tone(len: usize, freq: f64) -> Vec<Complex64> {
+        (0..len)
+            .map(|i| Complex64::from_polar(1.0, 2.0 * PI * freq * i as f64))
+            .collect()
+    }
+
+    #[test]
+    fn resampler_streams() {
+        let input = tone(2000, 0.013);
+
+        let mut whole = RationalResampler::new(3, 2);
+        let expected = whole.process(&input);
+
+        let mut chunked = RationalResampler::new(3, 2);
+        let mut output = Vec::new();
+        for chunk in input.chunks(333) {
+            output.extend(chunked.process(chunk));
+        }
+
+        assert_eq!(output.len(), 3000);
+        assert_eq!(expected, output);
+    }
+
+    #[test]
+    fn halving_the_rate_keeps_the_tone() {
+        // 100 kHz at 2 MHz should come out as 100 kHz at 1 MHz
+        let mut resampler = RationalResampler::from_rates(2e6, 1e6);
+        assert_eq!(resampler.ratio(), (1, 2));
+
+        let output = resampler.process(&tone(4000, 0.05));
+        assert_eq!(output.len(), 2000);
+
+        // Past the filter's start up, every sample should be the tone at the new rate
+        let expected = tone(2000, 0.1);
+        let start = 200;
+        let rotation = output[start] / expected[start];
+        assert!((rotation.norm() - 1.0).abs() < 1e-2);
+        for (l, r) in output[start..].iter().zip(expected[start..].iter()) {
+            assert!(
+                (l - r * rotation).norm() < 1e-2,
+                "{} != {}",
+                l,
+                r * rotation
+            );
+        }
+    }
+
+    #[test]
+    fn resampler_rejects_aliases() {
+        // 0.4 at the input rate would alias to -0.2 after halving the rate
+        let output = RationalResampler::new(1, 2).process(&tone(4000, 0.4));
+        let power = output[200..].iter().map(|s| s.norm_sqr()).sum::<f64>() / 1800.0;
+        assert!(10.0 * power.log10() < -55.0);
+    }
+
+    #[test]
+    fn farrow_delays_a_tone() {
+        let freq = 0.02;
+        let input = tone(200, freq);
+        let output = FarrowInterpolator::fractional_delay(0.3).process(&input);
+
+        // Output n sits at input n - 0.3
+        for n in 10..150 {
+            let expected = Complex64::from_polar(1.0, 2.0 * PI * freq * (n as f64 - 0.3));
+            assert!((output[n] - expected).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn farrow_matches_lagrange() {
+        let points = [
+            Complex64::new(0.3, -1.0),
+            Complex64::new(1.2, 0.5),
+            Complex64::new(-0.7, 2.0),
+            Complex64::new(0.1, 0.1),
+        ];
+
+        // Lagrange interpolation goes through every point
+        for (i, point) in points.iter().enumerate() {
+            let mu = i as f64 - 1.0;
+            assert!((farrow_cubic(&points, mu) - point).norm() < 1e-12);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Spectrum measurements for checking what the transmitter puts on the air, and for making sense
+//! of live captures.
+//!
+//! Power spectra here are fft shifted and indexed by normalized frequency, so bin 0 is -0.5 and
+//! the middle bin is dc. Multiply by the sample rate to get hz. Every estimate is scaled so its
+//! bins sum to the mean power of the samples that went in.
+
+use num::complex::Complex64;
+
+use crate::filters::Window;
+use crate::signals::*;
+
+/// Welch's power spectral density estimate: the mean of the windowed periodograms of segments
+/// `fft_len` long, each starting `fft_len - overlap` samples after the last. Trailing samples
+/// that don't fill a segment are ignored.
+pub fn welch(samples: &[Complex64], fft_len: usize, overlap: usize, window: Window) -> Vec<f64> {
+    assert!(
+        overlap < fft_len,
+        "segments have to overlap by less than their length"
+    );
+    let frames = stft(samples, fft_len, fft_len - overlap, window);
+    assert!(
+        !frames.is_empty(),
+        "need at least one full segment of samples"
+    );
+
+    let mut psd = vec![0.0; fft_len];
+    for frame in frames.iter() {
+        for (p, s) in psd.iter_mut().zip(frame.iter()) {
+            *p += s.norm_sqr();
+        }
+    }
+
+    let scale = fft_len as f64 * window_power(window, fft_len) * frames.len() as f64;
+    psd.iter_mut().for_each(|p| *p /= scale);
+    psd
+}
+
+/// Averaged periodogram: Welch's method over back to back hann windowed segments
+pub fn periodogram(samples: &[Complex64], fft_len: usize) -> Vec<f64> {
+    welch(samples, fft_len, 0, Window::Hann)
+}
+
+/// Short time fourier transform: the shifted spectrum of a windowed segment every `hop` samples
+pub fn stft(
+    samples: &[Complex64],
+    fft_len: usize,
+    hop: usize,
+    window: Window,
+) -> Vec<Vec<Complex64>> {
+    assert!(hop > 0, "segments have to move forwards");
+
+    let window = window.coefficients(fft_len);
+    let num_frames = match samples.len() >= fft_len {
+        true => (samples.len() - fft_len) / hop + 1,
+        false => 0,
+    };
+
+    (0..num_frames)
+        .map(|frame| {
+            let start = frame * hop;
+            let mut segment = samples[start..start + fft_len]
+                .iter()
+                .zip(window.iter())
+                .map(|(s, w)| s * w)
+                .collect::<Vec<_>>();
+            segment.fft().fft_shift();
+            segment
+        })
+        .collect()
+}
+
+/// Power spectrum over time, one row per `hop` samples, each scaled like `welch`
+pub fn spectrogram(
+    samples: &[Complex64],
+    fft_len: usize,
+    hop: usize,
+    window: Window,
+) -> Vec<Vec<f64>> {
+    let scale = fft_len as f64 * window_power(window, fft_len);
+    stft(samples, fft_len, hop, window)
+        .into_iter()
+        .map(|frame| frame.iter().map(|s| s.norm_sqr() / scale).collect())
+        .collect()
+}
+
+/// Sum of squares of the window, which is how much it scales the power of white noise
+fn window_power(window: Window, len: usize) -> f64 {
+    window.coefficients(len).iter().map(|w| w * w).sum()
+}
+
+/// The normalized frequency of every bin of a shifted spectrum
+pub fn frequencies(fft_len: usize) -> Vec<f64> {
+    (0..fft_len)
+        .map(|k| (k as f64 - (fft_len / 2) as f64) / fft_len as f64)
+        .collect()
+}
+
+/// Total power in the bins between two normalized frequencies, inclusive
+pub fn band_power(psd: &[f64], low: f64, high: f64) -> f64 {
+    frequencies(psd.len())
+        .into_iter()
+        .zip(psd.iter())
+        .filter(|(f, _)| *f >= low && *f <= high)
+        .map(|(_, p)| p)
+        .sum()
+}
+
+/// Adjacent channel leakage ratio in dB: how far the power in `adjacent` is below the power in
+/// `channel`. Both are (low, high) pairs of normalized frequencies. Bigger is better.
+pub fn aclr(psd: &[f64], channel: (f64, f64), adjacent: (f64, f64)) -> f64 {
+    let wanted = band_power(psd, channel.0, channel.1);
+    let leaked = band_power(psd, adjacent.0, adjacent.1);
+    10.0 * (wanted / leaked.max(f64::MIN_POSITIVE)).log10()
+}
+
+/// The (low, high) normalized frequencies holding `fraction` of the total power, leaving equal
+/// amounts outside on either side. The occupied bandwidth is `high - low`; 0.99 is the usual
+/// fraction for regulatory measurements.
+pub fn occupied_band(psd: &[f64], fraction: f64) -> (f64, f64) {
+    assert!(
+        fraction > 0.0 && fraction <= 1.0,
+        "fraction must be in (0, 1]"
+    );
+
+    let total: f64 = psd.iter().sum();
+    let tail = total * (1.0 - fraction) / 2.0;
+    let freqs = frequencies(psd.len());
+    let bin_width = 1.0 / psd.len() as f64;
+
+    let low = band_edge(psd, 0..psd.len(), tail);
+    let high = band_edge(psd, (0..psd.len()).rev(), tail);
+
+    (freqs[low] - bin_width / 2.0, freqs[high] + bin_width / 2.0)
+}
+
+/// Walk in from one edge until the skipped power would pass the allowed tail
+fn band_edge(psd: &[f64], mut bins: impl Iterator<Item = usize>, tail: f64) -> usize {
+    let mut skipped = 0.0;
+    bins.find(|&k| {
+        skipped += psd[k];
+        skipped > tail
+    })
+    .unwrap_or(0)
+}
+
+/// Convert linear power to dB, flooring at -300 so empty bins stay plottable
+pub fn to_db(psd: &[f64]) -> Vec<f64> {
+    psd.iter().map(|p| 10.0 * p.max(1e-30).log10()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn tone(len: usize, freq: f64) -> Vec<Complex64> {
+        (0..len)
+            .map(|i| Complex64::from_polar(1.0, 2.0 * PI * freq * i as f64))
+            .collect()
+    }
+
+    #[test]
+    fn tone_lands_in_its_bin() {
+        let psd = periodogram(&tone(1024, 0.125), 256);
+        let peak = (0..psd.len())
+            .max_by(|&l, &r| psd[l].partial_cmp(&psd[r]).unwrap())
+            .unwrap();
+        assert_eq!(frequencies(256)[peak], 0.125);
+
+        // The whole spectrum holds the tone's unit power
+        let total = band_power(&psd, -0.5, 0.5);
+        assert!((total - 1.0).abs() < 1e-9, "{}", total);
+    }
+
+    #[test]
+    fn aclr_of_a_clean_tone() {
+        let psd = periodogram(&tone(4096, 0.25), 512);
+        assert!(aclr(&psd, (0.2, 0.3), (-0.3, -0.2)) > 100.0);
+        assert!(aclr(&psd, (0.2, 0.3), (0.2, 0.3)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn welch_keeps_the_power() {
+        let samples = tone(5000, -0.2)
+            .into_iter()
+            .map(|s| s * 0.5)
+            .collect::<Vec<_>>();
+
+        for window in [Window::Hann, Window::Blackman, Window::Rectangular].iter() {
+            let psd = welch(&samples, 128, 64, *window);
+            let total: f64 = psd.iter().sum();
+            assert!((total - 0.25).abs() < 1e-9, "{:?} {}", window, total);
+        }
+    }
+
+    #[test]
+    fn spectrogram_follows_a_hop() {
+        // A tone that jumps from -0.25 to 0.25 halfway through
+        let mut samples = tone(2048, -0.25);
+        samples.extend(tone(2048, 0.25));
+
+        let frames = spectrogram(&samples, 64, 64, Window::Hann);
+        assert_eq!(frames.len(), 64);
+
+        let peak = |frame: &Vec<f64>| {
+            let k = (0..frame.len())
+                .max_by(|&l, &r| frame[l].partial_cmp(&frame[r]).unwrap())
+                .unwrap();
+            frequencies(64)[k]
+        };
+        assert_eq!(peak(&frames[10]), -0.25);
+        assert_eq!(peak(&frames[50]), 0.25);
+    }
+
+    #[test]
+    fn occupied_band_of_flat_noise() {
+        // Flat from -0.25 to 0.25, nothing anywhere else
+        let psd = frequencies(256)
+            .into_iter()
+            .map(|f| if f.abs() < 0.25 { 1.0 } else { 0.0 })
+            .collect::<Vec<_>>();
+
+        let (low, high) = occupied_band(&psd, 1.0);
+        assert!((low + 0.25).abs() <= 1.0 / 256.0);
+        assert!((high - 0.25).abs() <= 1.0 / 256.0);
+
+        let (low, high) = occupied_band(&psd, 0.9);
+        assert!((high - low - 0.45).abs() < 2.0 / 256.0);
+    }
+}
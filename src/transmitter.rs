@@ -1,5 +1,7 @@
 use std::convert::TryInto;
+use std::f64::consts::PI;
 
+use crate::papr::{reduce_blocks, PaprReduction};
 use crate::utils::GetBitAt;
 use crate::{packets::Header, signals::*};
 use num::complex::Complex64;
@@ -12,26 +14,14 @@ pub fn encode(
     data: &[u8],
     guard_bands: Option<bool>,
     modulation: Option<crate::ModulationScheme>,
+    window: Option<usize>,
+    papr: Option<PaprReduction>,
 ) -> Vec<Complex64> {
     let guard_bands = guard_bands.unwrap_or(false);
     let modulation = modulation.unwrap_or(ModulationScheme::Bpsk);
+    let window = window.unwrap_or(0);
 
-    let mut out_stream = Vec::new();
-
-    // Add the locking block
-    for _ in 0..1 {
-        out_stream.extend(locking_signal::<80>().iter());
-    }
-
-    // Add the preamble for frequency correction
-    for _ in 0..4 {
-        out_stream.extend(preamble::<80>().iter())
-    }
-
-    // Add the training signals for channel estimation
-    for _ in 0..5 {
-        out_stream.extend(prefix_block::<64, 16>(&mut training_signals::<64>()).iter());
-    }
+    let mut out_stream = frame_start();
 
     // Add a header for the receiver to know how long the transmission is
     let header = Header::new(data.len() as u128);
@@ -53,10 +43,54 @@ pub fn encode(
             .pipe(|b| out_stream.extend(b.iter()));
     }
 
+    // Training blocks use every subcarrier and have to stay exactly as the receiver expects
+    if let Some(papr) = papr {
+        reduce_blocks(&mut out_stream[DATA_START..], papr, guard_bands);
+    }
+
+    // Everything from the training blocks on is made of cyclic prefixed blocks
+    window_blocks::<64, 16>(&mut out_stream[TRAINING_START..], window);
+
     normalize(&mut out_stream);
     out_stream
 }
 
+/// Where the training blocks start in every frame, after the locking block and the preamble
+pub const TRAINING_START: usize = 5 * 80;
+
+/// Where the header and data start in every frame
+pub const DATA_START: usize = TRAINING_START + 5 * 80;
+
+/// Samples in the frame `encode` makes for `data_len` bytes
+pub fn transmission_len(data_len: usize, guard_bands: bool, modulation: ModulationScheme) -> usize {
+    let header_len = bincode::serialized_size(&Header::new(0)).unwrap() as usize;
+    let symbols = (header_len + data_len) * 8 / modulation.bits_per_symbol();
+    let per_block = data_subcarriers(guard_bands).len();
+    DATA_START + (symbols + per_block - 1) / per_block * 80
+}
+
+/// The locking block, preamble and training blocks that every frame starts with
+pub fn frame_start() -> Vec<Complex64> {
+    let mut out_stream = Vec::with_capacity(DATA_START);
+
+    // Add the locking block
+    for _ in 0..1 {
+        out_stream.extend(locking_signal::<80>().iter());
+    }
+
+    // Add the preamble for frequency correction
+    for _ in 0..4 {
+        out_stream.extend(preamble::<80>().iter())
+    }
+
+    // Add the training signals for channel estimation
+    for _ in 0..5 {
+        out_stream.extend(prefix_block::<64, 16>(&mut training_signals::<64>()).iter());
+    }
+
+    out_stream
+}
+
 pub fn locking_signal<const LEN: usize>() -> [Complex64; LEN] {
     let mut out = [Complex64::default(); LEN];
 
@@ -95,6 +129,7 @@ pub fn training_signals<const LEN: usize>() -> [Complex64; LEN] {
     out
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ModulationScheme {
     Bpsk,
     Qpsk,
@@ -103,6 +138,17 @@ pub enum ModulationScheme {
     Qam,
 }
 
+impl ModulationScheme {
+    /// How many bits are carried by each constellation point
+    pub fn bits_per_symbol(&self) -> usize {
+        match self {
+            ModulationScheme::Bpsk => 1,
+            ModulationScheme::Qpsk => 2,
+            ModulationScheme::Qam => 4,
+        }
+    }
+}
+
 // This modulates a bit stream into a Vec of complex values.
 // This method currently uses BPSK modulation.
 pub fn modulate(stream: &[u8], scheme: &ModulationScheme) -> Vec<Complex64> {
@@ -150,12 +196,10 @@ pub fn encode_block(
     for i in 0..64 {
         out[i] = match i {
             // dc offset, sidebands, just skip
-            i if guard_bands && (i >= 59 || i <= 5 || i == 32) => Complex64::new(0.0, 0.0),
+            i if is_null_subcarrier(i, guard_bands) => Complex64::new(0.0, 0.0),
 
             // pilot tones
-            i if guard_bands && (i == 6 || i == 25 || i == 39 || i == 58) => {
-                Complex64::new(1.0, 0.0)
-            }
+            i if is_pilot_subcarrier(i, guard_bands) => Complex64::new(1.0, 0.0),
 
             _ => stream.next().unwrap_or_else(|| Complex64::new(0.0, 0.0)),
         }
@@ -164,6 +208,23 @@ pub fn encode_block(
     out
 }
 
+/// Subcarriers left empty by the guard bands: dc offset and the sidebands
+pub fn is_null_subcarrier(i: usize, guard_bands: bool) -> bool {
+    guard_bands && (i >= 59 || i <= 5 || i == 32)
+}
+
+/// Subcarriers carrying the known pilot tones used to track phase
+pub fn is_pilot_subcarrier(i: usize, guard_bands: bool) -> bool {
+    guard_bands && (i == 6 || i == 25 || i == 39 || i == 58)
+}
+
+/// The subcarriers that carry data, in the order symbols are loaded into them
+pub fn data_subcarriers(guard_bands: bool) -> Vec<usize> {
+    (0..64)
+        .filter(|&i| !is_null_subcarrier(i, guard_bands) && !is_pilot_subcarrier(i, guard_bands))
+        .collect()
+}
+
 /// Encode the data with an FFT and then add a cyclic prefix
 pub fn prefix_block<const LEN: usize, const PREFIX: usize>(
     fftdata: &mut [Complex64; LEN],
@@ -180,12 +241,55 @@ pub fn prefix_block<const LEN: usize, const PREFIX: usize>(
     out
 }
 
+/// Taper the edges of consecutive cyclic prefixed blocks with a raised cosine roll-off
+///
+/// Each block is extended by `overlap` samples of its own cyclic continuation, which ramps down
+/// over the start of the next block's prefix while that ramps up. The hard jumps between blocks
+/// are what spread the spectrum, so smoothing them pulls the sidelobes in a long way. The
+/// receiver throws the prefix away, so the cost is only `overlap` samples less tolerance to
+/// delay spread. The last block's roll-off is dropped so the transmission keeps its length.
+pub fn window_blocks<const LEN: usize, const PREFIX: usize>(
+    blocks: &mut [Complex64],
+    overlap: usize,
+) {
+    assert!(
+        overlap < PREFIX,
+        "the window has to leave some of the cyclic prefix"
+    );
+    if overlap == 0 {
+        return;
+    }
+
+    let ramp = (0..overlap)
+        .map(|n| 0.5 * (1.0 - (PI * (n as f64 + 0.5) / overlap as f64).cos()))
+        .collect::<Vec<_>>();
+
+    let mut tail = vec![Complex64::default(); overlap];
+    for block in blocks.chunks_exact_mut(LEN + PREFIX) {
+        // Past the end of the block the symbol starts over, right after the prefix
+        let next_tail = (0..overlap)
+            .map(|n| block[PREFIX + n] * (1.0 - ramp[n]))
+            .collect::<Vec<_>>();
+
+        for n in 0..overlap {
+            block[n] = block[n] * ramp[n] + tail[n];
+        }
+
+        tail = next_tail;
+    }
+}
+
+/// Scale so the largest of the real and imaginary parts, positive or negative, is exactly 1
 pub fn normalize(data: &mut Vec<Complex64>) -> &mut Vec<Complex64> {
-    let mut max: f64 = 0.0;
-    for f in data.iter() {
-        max = f64::max(f.re, max);
-        max = f64::max(f.im, max);
+    let max = data
+        .iter()
+        .map(|f| f64::max(f.re.abs(), f.im.abs()))
+        .fold(0.0, f64::max);
+
+    if max == 0.0 {
+        return data;
     }
+
     for f in data.iter_mut() {
         f.re = f.re / max;
         f.im = f.im / max;
@@ -199,6 +303,28 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn transmission_len_matches_encode() {
+        let modulations = [ModulationScheme::Bpsk, ModulationScheme::Qpsk];
+        for &len in [0, 1, 5, 200, 1000].iter() {
+            for &modulation in modulations.iter() {
+                for &guard_bands in [false, true].iter() {
+                    let samples = encode(
+                        &vec![0; len],
+                        Some(guard_bands),
+                        Some(modulation),
+                        None,
+                        None,
+                    );
+                    assert_eq!(
+                        samples.len(),
+                        transmission_len(len, guard_bands, modulation)
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     fn cyclic_prefix_works() {
         let mut i = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10].to_signal();
@@ -223,4 +349,52 @@ mod tests {
         dbg!(out.reals());
         dbg!(out.fft_shift().reals());
     }
+
+    #[test]
+    fn windowing_cuts_leakage() {
+        let data = (0..2000).map(|i| (i * 37 % 251) as u8).collect::<Vec<_>>();
+
+        // Leakage into the empty bins around dc, relative to the data bins
+        let aclr = |window| {
+            let samples = encode(
+                &data,
+                Some(true),
+                Some(ModulationScheme::Qpsk),
+                Some(window),
+                None,
+            );
+
+            // Only look at the data blocks, the preamble is white noise on purpose
+            let psd = crate::spectrum::periodogram(&samples[800..], 1024);
+            crate::spectrum::aclr(&psd, (0.1, 0.4), (-0.02, 0.02))
+        };
+
+        let hard = aclr(0);
+        let windowed = aclr(12);
+        assert!(windowed > hard + 10.0, "{}dB vs {}dB", windowed, hard);
+    }
+
+    #[test]
+    fn normalize_sees_negative_peaks() {
+        let mut data = vec![Complex64::new(0.5, -4.0), Complex64::new(2.0, 1.0)];
+        normalize(&mut data);
+        assert_eq!(data[0], Complex64::new(0.125, -1.0));
+        assert_eq!(data[1], Complex64::new(0.5, 0.25));
+    }
+
+    #[test]
+    fn papr_reduction_in_encode() {
+        let data = (0..2000).map(|i| (i * 37 % 251) as u8).collect::<Vec<_>>();
+        let papr = |reduction| {
+            let samples = encode(&data, Some(true), None, None, reduction);
+            crate::papr::papr_db(&samples[800..])
+        };
+
+        let plain = papr(None);
+        let reduced = papr(Some(PaprReduction::ClipAndFilter {
+            clip_db: 4.0,
+            iterations: 4,
+        }));
+        assert!(reduced < plain - 1.0, "{}dB vs {}dB", reduced, plain);
+    }
 }
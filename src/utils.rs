@@ -179,11 +179,13 @@ pub fn decipher_transmission_bytes(byte_iter: &mut impl Iterator<Item = u8>) ->
     Some(outstream)
 }
 
+/// Turn a stream of palette indices back into 0RGB pixels. A palette packet at the front of the
+/// stream gives the colors, otherwise they're the fixed `Palette::colormap()`.
 pub fn decipher_transmision_colorspace(
     bytes_iter: &mut impl ExactSizeIterator<Item = u8>,
     ecc: bool,
 ) -> Option<Vec<u32>> {
-    use crate::packets::colors;
+    use crate::packets::palette::Palette;
 
     let data = if ecc {
         log::debug!("ECC enabled, {}", bytes_iter.len());
@@ -192,16 +194,15 @@ pub fn decipher_transmision_colorspace(
         bytes_iter.collect()
     };
 
-    let out = data
-        .iter()
-        .map(|byte| {
-            let color = colors::COLORMAP.get(*byte);
-            let colors::CustomRgb { r, g, b } = color.rgb;
+    let (palette, pixels) = match Palette::from_packet(&data) {
+        Some(palette) => {
+            let len = palette.to_packet().len();
+            (palette, &data[len..])
+        }
+        None => (Palette::colormap(), &data[..]),
+    };
 
-            ((r as u32) << 16) | ((g as u32) << 8) | b as u32
-        })
-        .collect();
-    Some(out)
+    Some(pixels.iter().map(|&idx| palette.rgb(idx)).collect())
 }
 
 pub fn debug_data(left: &[u8], right: &[u8]) {
@@ -263,6 +264,14 @@ pub fn write_to_numpy_file(data: &[Complex64], filename: &'static str) -> anyhow
     Ok(())
 }
 
+/// Write out real valued data like a power spectrum. Spectrograms go in a frame at a time, so
+/// reshape to (frames, fft_len) on the python side.
+pub fn write_reals_to_numpy_file(data: &[f64], filename: &'static str) -> anyhow::Result<()> {
+    npy::to_file(format!("data/simulated/{}.npy", filename), data.to_vec())?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::IntoSignal;
@@ -365,4 +374,22 @@ mod tests {
         let text = decipher_transmission_text(1024, ecced, true);
         dbg!(text);
     }
+
+    #[test]
+    fn colorspace_uses_a_palette_sent_in_front() {
+        use crate::packets::palette::Palette;
+
+        let colormap = decipher_transmision_colorspace(&mut vec![0, 9].into_iter(), false);
+        let colormap_palette = Palette::colormap();
+        assert_eq!(
+            colormap,
+            Some(vec![colormap_palette.rgb(0), colormap_palette.rgb(9)])
+        );
+
+        let palette = Palette::new(7, vec![[255, 0, 0], [0, 0, 255]]);
+        let mut stream = palette.to_packet();
+        stream.extend_from_slice(&[1, 0]);
+        let pixels = decipher_transmision_colorspace(&mut stream.into_iter(), false);
+        assert_eq!(pixels, Some(vec![0x0000ff, 0xff0000]));
+    }
 }